@@ -0,0 +1,397 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use fuzzysearch_common::net::is_public;
+use reqwest::{header, Url};
+
+/// The largest image that will be downloaded from a URL.
+pub const MAX_IMAGE_SIZE: usize = 10_000_000;
+
+/// The most redirects that will be followed before giving up.
+const MAX_REDIRECTS: usize = 5;
+
+/// How long to wait to connect to a host.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long each request, including downloading the body, may take.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(15);
+
+#[derive(Debug)]
+pub enum FetchError {
+    /// The URL could not be parsed or used a scheme other than HTTP or HTTPS.
+    InvalidUrl,
+    /// The host resolved to an address that should not be accessed, such as
+    /// a loopback or private network address.
+    ForbiddenAddress,
+    /// The host could not be resolved.
+    Resolve(std::io::Error),
+    /// Resolving the host, connecting, or downloading took too long.
+    Timeout,
+    TooManyRedirects,
+    /// The response was not successful.
+    Status(reqwest::StatusCode),
+    TooLarge,
+    /// The response did not contain an image.
+    NotImage,
+    Reqwest(reqwest::Error),
+}
+
+impl From<reqwest::Error> for FetchError {
+    fn from(err: reqwest::Error) -> Self {
+        if err.is_timeout() {
+            Self::Timeout
+        } else {
+            Self::Reqwest(err)
+        }
+    }
+}
+
+/// Download an image from a user provided URL.
+///
+/// Only HTTP and HTTPS URLs are allowed, and every address the host resolves
+/// to must be publicly routable. Redirects are followed manually so each
+/// location is checked the same way. Requests are made to the address that
+/// was checked, so the host can't resolve to a different address when
+/// connecting.
+#[tracing::instrument]
+pub async fn fetch_image(url: &str) -> Result<Vec<u8>, FetchError> {
+    fetch_image_from(url, |addr| is_public(addr.ip()), REQUEST_TIMEOUT).await
+}
+
+/// Download an image, only connecting to addresses that are `allowed`.
+async fn fetch_image_from<F>(
+    url: &str,
+    allowed: F,
+    timeout: Duration,
+) -> Result<Vec<u8>, FetchError>
+where
+    F: Fn(SocketAddr) -> bool,
+{
+    let mut url = Url::parse(url).map_err(|_err| FetchError::InvalidUrl)?;
+
+    for _ in 0..=MAX_REDIRECTS {
+        let client = guarded_client(&url, &allowed, timeout).await?;
+
+        let mut resp = client
+            .get(url.clone())
+            .header(header::ACCEPT, "image/*")
+            .send()
+            .await?;
+
+        if resp.status().is_redirection() {
+            let location = resp
+                .headers()
+                .get(header::LOCATION)
+                .and_then(|location| location.to_str().ok())
+                .ok_or(FetchError::InvalidUrl)?;
+
+            url = url.join(location).map_err(|_err| FetchError::InvalidUrl)?;
+            tracing::debug!(%url, "following redirect");
+
+            continue;
+        }
+
+        if !resp.status().is_success() {
+            return Err(FetchError::Status(resp.status()));
+        }
+
+        if !allowed_content_type(resp.headers().get(header::CONTENT_TYPE)) {
+            return Err(FetchError::NotImage);
+        }
+
+        if resp.content_length().unwrap_or(0) > MAX_IMAGE_SIZE as u64 {
+            return Err(FetchError::TooLarge);
+        }
+
+        let mut buf = Vec::with_capacity(resp.content_length().unwrap_or(0) as usize);
+
+        while let Some(chunk) = resp.chunk().await? {
+            if buf.len() + chunk.len() > MAX_IMAGE_SIZE {
+                return Err(FetchError::TooLarge);
+            }
+
+            buf.extend_from_slice(&chunk);
+        }
+
+        // Servers often send the wrong content type, so the contents must
+        // also look like a known image format.
+        if image::guess_format(&buf).is_err() {
+            return Err(FetchError::NotImage);
+        }
+
+        return Ok(buf);
+    }
+
+    Err(FetchError::TooManyRedirects)
+}
+
+/// Build a client that may only connect to an allowed address of the URL's
+/// host and does not follow redirects.
+async fn guarded_client<F>(
+    url: &Url,
+    allowed: F,
+    timeout: Duration,
+) -> Result<reqwest::Client, FetchError>
+where
+    F: Fn(SocketAddr) -> bool,
+{
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(FetchError::InvalidUrl);
+    }
+
+    // IPv6 addresses are bracketed in URLs, but can't be resolved that way.
+    let host = url
+        .host_str()
+        .ok_or(FetchError::InvalidUrl)?
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+    let port = url.port_or_known_default().ok_or(FetchError::InvalidUrl)?;
+
+    let addrs: Vec<SocketAddr> =
+        tokio::time::timeout(CONNECT_TIMEOUT, tokio::net::lookup_host((host, port)))
+            .await
+            .map_err(|_elapsed| FetchError::Timeout)?
+            .map_err(FetchError::Resolve)?
+            .collect();
+
+    if addrs.is_empty() || !addrs.iter().all(|addr| allowed(*addr)) {
+        return Err(FetchError::ForbiddenAddress);
+    }
+
+    let builder = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .connect_timeout(CONNECT_TIMEOUT)
+        .timeout(timeout)
+        .no_proxy();
+
+    // IP addresses are connected to directly, domains must be pinned to the
+    // address that was checked.
+    let builder = match url.domain() {
+        Some(domain) => builder.resolve(domain, addrs[0]),
+        None => builder,
+    };
+
+    Ok(builder.build()?)
+}
+
+/// If a response's content type could be an image. Responses without a
+/// content type or with a generic binary type are allowed and checked after
+/// being downloaded.
+fn allowed_content_type(content_type: Option<&header::HeaderValue>) -> bool {
+    let content_type = match content_type.and_then(|value| value.to_str().ok()) {
+        Some(content_type) => content_type.to_ascii_lowercase(),
+        None => return true,
+    };
+
+    content_type.starts_with("image/") || content_type.starts_with("application/octet-stream")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::{fetch_image, fetch_image_from, FetchError, MAX_IMAGE_SIZE, MAX_REDIRECTS};
+
+    /// The start of a PNG file, which is enough to be recognized as an image.
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+
+    const TIMEOUT: Duration = Duration::from_millis(500);
+
+    /// Start a server on a loopback address that responds to each request
+    /// with the response for its path.
+    async fn serve(respond: fn(&str, SocketAddr) -> Vec<u8>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+
+                tokio::spawn(async move {
+                    let mut req = Vec::new();
+                    let mut buf = [0; 1024];
+                    while !req.windows(4).any(|window| window == b"\r\n\r\n") {
+                        match stream.read(&mut buf).await {
+                            Ok(0) | Err(_) => return,
+                            Ok(n) => req.extend_from_slice(&buf[..n]),
+                        }
+                    }
+
+                    let req = String::from_utf8_lossy(&req);
+                    let path = req.split(' ').nth(1).unwrap_or("/");
+
+                    if path == "/slow" {
+                        tokio::time::sleep(TIMEOUT * 4).await;
+                    }
+
+                    let _ = stream.write_all(&respond(path, addr)).await;
+                });
+            }
+        });
+
+        addr
+    }
+
+    fn response(status: &str, headers: &[(&str, String)], body: &[u8]) -> Vec<u8> {
+        let mut resp = format!("HTTP/1.1 {}\r\nconnection: close\r\n", status);
+        for (name, value) in headers {
+            resp.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        resp.push_str("\r\n");
+
+        let mut resp = resp.into_bytes();
+        resp.extend_from_slice(body);
+        resp
+    }
+
+    fn respond(path: &str, addr: SocketAddr) -> Vec<u8> {
+        let image = |content_type: &str, body: &[u8]| {
+            response(
+                "200 OK",
+                &[
+                    ("content-type", content_type.to_string()),
+                    ("content-length", body.len().to_string()),
+                ],
+                body,
+            )
+        };
+        let redirect = |location: String| response("302 Found", &[("location", location)], b"");
+
+        match path {
+            "/image" | "/slow" => image("image/png", PNG),
+            "/octet-stream" => image("application/octet-stream", PNG),
+            "/html" => image("text/html", b"<!doctype html>"),
+            "/not-image" => image("image/png", b"not an image"),
+            "/declared-large" => response(
+                "200 OK",
+                &[
+                    ("content-type", "image/png".to_string()),
+                    ("content-length", (MAX_IMAGE_SIZE + 1).to_string()),
+                ],
+                PNG,
+            ),
+            "/undeclared-large" => {
+                let mut body = PNG.to_vec();
+                body.resize(MAX_IMAGE_SIZE + 1, 0);
+                response(
+                    "200 OK",
+                    &[("content-type", "image/png".to_string())],
+                    &body,
+                )
+            }
+            "/missing" => response("404 Not Found", &[("content-length", "0".to_string())], b""),
+            "/redirect-loopback" => redirect(format!("http://127.0.0.1:{}/image", addr.port() + 1)),
+            path => match path
+                .strip_prefix("/redirect/")
+                .and_then(|n| n.parse::<usize>().ok())
+            {
+                Some(0) => redirect("/image".to_string()),
+                Some(n) => redirect(format!("/redirect/{}", n - 1)),
+                None => response("404 Not Found", &[("content-length", "0".to_string())], b""),
+            },
+        }
+    }
+
+    /// Fetch a path from the server, only allowing connections to it.
+    async fn fetch(addr: SocketAddr, path: &str) -> Result<Vec<u8>, FetchError> {
+        let url = format!("http://{}{}", addr, path);
+
+        fetch_image_from(&url, |allowed| allowed == addr, TIMEOUT).await
+    }
+
+    #[tokio::test]
+    async fn fetches_images() {
+        let addr = serve(respond).await;
+
+        assert_eq!(fetch(addr, "/image").await.unwrap(), PNG);
+        assert_eq!(fetch(addr, "/octet-stream").await.unwrap(), PNG);
+    }
+
+    #[tokio::test]
+    async fn limits_redirects() {
+        let addr = serve(respond).await;
+
+        let path = format!("/redirect/{}", MAX_REDIRECTS - 1);
+        assert_eq!(fetch(addr, &path).await.unwrap(), PNG);
+
+        let path = format!("/redirect/{}", MAX_REDIRECTS);
+        assert!(matches!(
+            fetch(addr, &path).await,
+            Err(FetchError::TooManyRedirects)
+        ));
+    }
+
+    #[tokio::test]
+    async fn rejects_private_addresses() {
+        let addr = serve(respond).await;
+
+        for url in [
+            format!("http://{}/image", addr),
+            format!("http://localhost:{}/image", addr.port()),
+            format!("http://[::1]:{}/image", addr.port()),
+            "http://10.0.0.1/image".to_string(),
+            "http://169.254.169.254/latest/meta-data".to_string(),
+            "http://[::ffff:127.0.0.1]/image".to_string(),
+        ] {
+            assert!(
+                matches!(fetch_image(&url).await, Err(FetchError::ForbiddenAddress)),
+                "{} was not rejected",
+                url
+            );
+        }
+
+        // Only the server is allowed, so redirecting to another loopback
+        // address must be checked again.
+        assert!(matches!(
+            fetch(addr, "/redirect-loopback").await,
+            Err(FetchError::ForbiddenAddress)
+        ));
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_urls() {
+        for url in ["not a url", "file:///etc/passwd", "ftp://example.com/image"] {
+            assert!(matches!(
+                fetch_image(url).await,
+                Err(FetchError::InvalidUrl)
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn limits_size() {
+        let addr = serve(respond).await;
+
+        for path in ["/declared-large", "/undeclared-large"] {
+            assert!(matches!(fetch(addr, path).await, Err(FetchError::TooLarge)));
+        }
+    }
+
+    #[tokio::test]
+    async fn times_out() {
+        let addr = serve(respond).await;
+
+        assert!(matches!(
+            fetch(addr, "/slow").await,
+            Err(FetchError::Timeout)
+        ));
+    }
+
+    #[tokio::test]
+    async fn rejects_other_responses() {
+        let addr = serve(respond).await;
+
+        for path in ["/html", "/not-image"] {
+            assert!(matches!(fetch(addr, path).await, Err(FetchError::NotImage)));
+        }
+
+        assert!(matches!(
+            fetch(addr, "/missing").await,
+            Err(FetchError::Status(reqwest::StatusCode::NOT_FOUND))
+        ));
+    }
+}
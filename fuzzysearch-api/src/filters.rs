@@ -1,112 +1,918 @@
-use crate::{handlers, Pool};
+use crate::{handlers, limiter::Limiter, subscriptions::HashAddedSender, utils::Mailer, Pool};
 use crate::{types::*, Endpoints};
+use fuzzysearch_common::faktory::FaktoryClient;
 use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
 use tracing_futures::Instrument;
-use warp::{Filter, Rejection, Reply};
+use utoipa::openapi::path::{Parameter, ParameterIn};
+use warp::{filters::BoxedFilter, http::Method, path::FullPath, Filter, Rejection, Reply};
+
+/// A route served by the API, which filters match requests against and the
+/// OpenAPI document is checked against.
+pub struct Route {
+    pub method: Method,
+    /// The path, with parameters named in braces.
+    pub path: &'static str,
+    /// The query parameters accepted by the route.
+    pub params: &'static [fn() -> Vec<Parameter>],
+}
+
+impl Route {
+    fn segments(path: &str) -> impl Iterator<Item = &str> {
+        path.split('/').filter(|segment| !segment.is_empty())
+    }
+
+    /// The name of the path parameter in a segment of the route's path.
+    fn param_name(segment: &str) -> Option<&str> {
+        segment.strip_prefix('{')?.strip_suffix('}')
+    }
+
+    /// If a request path matches this route's path, where any segment may be
+    /// given for a parameter.
+    fn matches(&self, path: &str) -> bool {
+        let mut expected = Self::segments(self.path);
+        let mut segments = Self::segments(path);
+
+        loop {
+            match (expected.next(), segments.next()) {
+                (Some(expected), Some(segment)) => {
+                    if Self::param_name(expected).is_none() && expected != segment {
+                        return false;
+                    }
+                }
+                (None, None) => return true,
+                _ => return false,
+            }
+        }
+    }
+}
+
+/// Every route served by `search` and `accounts`, other than the OpenAPI
+/// document itself, which tests check are served and documented.
+#[cfg(test)]
+pub static ROUTES: &[&Route] = &[
+    &SEARCH_IMAGE,
+    &SEARCH_IMAGE_BATCH,
+    &SEARCH_VIDEO,
+    &SEARCH_HASHES,
+    &SEARCH_FILE,
+    &SEARCH_SHA256,
+    &SEARCH_SHA256_FILE,
+    &CHECK_HANDLE,
+    &SEARCH_IMAGE_BY_URL,
+    &SEARCH_DUPLICATES,
+    &ARTIST_SUBMISSIONS,
+    &RELATED_ARTISTS,
+    &SUBSCRIBE_HASHES,
+    &API_KEY_USAGE,
+    &REGISTER_ACCOUNT,
+    &VERIFY_ACCOUNT,
+    &GET_ACCOUNT,
+    &LIST_API_KEYS,
+    &CREATE_API_KEY,
+    &RENAME_API_KEY,
+    &ROTATE_API_KEY,
+    &REVOKE_API_KEY,
+    &UPDATE_API_KEY_LIMITS,
+    &LIST_HASH_WATCHES,
+    &WATCH_HASH,
+    &UNWATCH_HASH,
+    &LIST_WEBHOOKS,
+    &CREATE_WEBHOOK,
+    &UPDATE_WEBHOOK_FILTERS,
+    &ROTATE_WEBHOOK_SECRET,
+    &ENABLE_WEBHOOK,
+    &LIST_WEBHOOK_DELIVERIES,
+    &REDELIVER_WEBHOOK,
+    &DELETE_WEBHOOK,
+];
+
+pub static SEARCH_IMAGE: Route = Route {
+    method: Method::POST,
+    path: "/image",
+    params: &[
+        params::<ImageSearchOpts>,
+        params::<SearchFilterOpts>,
+        params::<SearchPageOpts>,
+    ],
+};
+pub static SEARCH_IMAGE_BATCH: Route = Route {
+    method: Method::POST,
+    path: "/image/batch",
+    params: &[params::<ImageSearchOpts>, params::<SearchFilterOpts>],
+};
+pub static SEARCH_VIDEO: Route = Route {
+    method: Method::POST,
+    path: "/video",
+    params: &[params::<ImageSearchOpts>, params::<SearchFilterOpts>],
+};
+pub static SEARCH_HASHES: Route = Route {
+    method: Method::GET,
+    path: "/hashes",
+    params: &[
+        params::<HashSearchOpts>,
+        params::<SearchFilterOpts>,
+        params::<SearchPageOpts>,
+    ],
+};
+pub static SEARCH_FILE: Route = Route {
+    method: Method::GET,
+    path: "/file",
+    params: &[params::<FileSearchOpts>],
+};
+pub static SEARCH_SHA256: Route = Route {
+    method: Method::GET,
+    path: "/sha256",
+    params: &[params::<Sha256SearchOpts>],
+};
+pub static SEARCH_SHA256_FILE: Route = Route {
+    method: Method::POST,
+    path: "/sha256",
+    params: &[],
+};
+pub static CHECK_HANDLE: Route = Route {
+    method: Method::GET,
+    path: "/handle",
+    params: &[params::<HandleOpts>],
+};
+pub static SEARCH_IMAGE_BY_URL: Route = Route {
+    method: Method::GET,
+    path: "/url",
+    params: &[
+        params::<UrlSearchOpts>,
+        params::<SearchFilterOpts>,
+        params::<SearchPageOpts>,
+    ],
+};
+pub static SEARCH_DUPLICATES: Route = Route {
+    method: Method::GET,
+    path: "/duplicates",
+    params: &[params::<DuplicateOpts>],
+};
+pub static ARTIST_SUBMISSIONS: Route = Route {
+    method: Method::GET,
+    path: "/artist",
+    params: &[params::<ArtistOpts>, params::<IdPageOpts>],
+};
+pub static RELATED_ARTISTS: Route = Route {
+    method: Method::GET,
+    path: "/artist/related",
+    params: &[params::<ArtistOpts>, params::<HashKindOpts>],
+};
+pub static SUBSCRIBE_HASHES: Route = Route {
+    method: Method::GET,
+    path: "/subscribe",
+    params: &[params::<SubscribeOpts>],
+};
+pub static API_KEY_USAGE: Route = Route {
+    method: Method::GET,
+    path: "/usage",
+    params: &[params::<UsageOpts>],
+};
+pub static REGISTER_ACCOUNT: Route = Route {
+    method: Method::POST,
+    path: "/account",
+    params: &[],
+};
+pub static VERIFY_ACCOUNT: Route = Route {
+    method: Method::POST,
+    path: "/account/verify",
+    params: &[],
+};
+pub static GET_ACCOUNT: Route = Route {
+    method: Method::GET,
+    path: "/account",
+    params: &[],
+};
+pub static LIST_API_KEYS: Route = Route {
+    method: Method::GET,
+    path: "/account/keys",
+    params: &[],
+};
+pub static CREATE_API_KEY: Route = Route {
+    method: Method::POST,
+    path: "/account/keys",
+    params: &[],
+};
+pub static RENAME_API_KEY: Route = Route {
+    method: Method::POST,
+    path: "/account/keys/{key_id}/name",
+    params: &[],
+};
+pub static ROTATE_API_KEY: Route = Route {
+    method: Method::POST,
+    path: "/account/keys/{key_id}/rotate",
+    params: &[],
+};
+pub static REVOKE_API_KEY: Route = Route {
+    method: Method::POST,
+    path: "/account/keys/{key_id}/revoke",
+    params: &[],
+};
+pub static UPDATE_API_KEY_LIMITS: Route = Route {
+    method: Method::POST,
+    path: "/admin/keys/{key_id}/limits",
+    params: &[],
+};
+pub static LIST_HASH_WATCHES: Route = Route {
+    method: Method::GET,
+    path: "/account/watches",
+    params: &[],
+};
+pub static WATCH_HASH: Route = Route {
+    method: Method::POST,
+    path: "/account/watches",
+    params: &[],
+};
+pub static UNWATCH_HASH: Route = Route {
+    method: Method::DELETE,
+    path: "/account/watches/{watch_id}",
+    params: &[],
+};
+pub static LIST_WEBHOOKS: Route = Route {
+    method: Method::GET,
+    path: "/account/webhooks",
+    params: &[],
+};
+pub static CREATE_WEBHOOK: Route = Route {
+    method: Method::POST,
+    path: "/account/webhooks",
+    params: &[],
+};
+pub static UPDATE_WEBHOOK_FILTERS: Route = Route {
+    method: Method::POST,
+    path: "/account/webhooks/{webhook_id}/filters",
+    params: &[],
+};
+pub static ROTATE_WEBHOOK_SECRET: Route = Route {
+    method: Method::POST,
+    path: "/account/webhooks/{webhook_id}/secret",
+    params: &[],
+};
+pub static ENABLE_WEBHOOK: Route = Route {
+    method: Method::POST,
+    path: "/account/webhooks/{webhook_id}/enable",
+    params: &[],
+};
+pub static LIST_WEBHOOK_DELIVERIES: Route = Route {
+    method: Method::GET,
+    path: "/account/webhooks/{webhook_id}/deliveries",
+    params: &[params::<IdPageOpts>],
+};
+pub static REDELIVER_WEBHOOK: Route = Route {
+    method: Method::POST,
+    path: "/account/webhooks/{webhook_id}/deliveries/{delivery_id}/redeliver",
+    params: &[],
+};
+pub static DELETE_WEBHOOK: Route = Route {
+    method: Method::DELETE,
+    path: "/account/webhooks/{webhook_id}",
+    params: &[],
+};
 
 pub fn search(
     db: Pool,
+    limiter: Limiter,
     bkapi: bkapi_client::BKApiClient,
     endpoints: Endpoints,
+    hash_added: HashAddedSender,
 ) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
-    search_image(db.clone(), bkapi.clone(), endpoints)
-        .or(search_hashes(db.clone(), bkapi.clone()))
-        .or(search_file(db.clone()))
-        .or(check_handle(db.clone()))
-        .or(search_image_by_url(db, bkapi))
+    search_image_batch(
+        db.clone(),
+        limiter.clone(),
+        bkapi.clone(),
+        endpoints.clone(),
+    )
+    .or(search_image(
+        db.clone(),
+        limiter.clone(),
+        bkapi.clone(),
+        endpoints.clone(),
+    ))
+    .or(search_video(
+        db.clone(),
+        limiter.clone(),
+        bkapi.clone(),
+        endpoints.clone(),
+    ))
+    .or(search_hashes(db.clone(), limiter.clone(), bkapi.clone()))
+    .or(search_file(db.clone(), limiter.clone()))
+    .or(search_sha256(db.clone(), limiter.clone()))
+    .or(search_sha256_file(db.clone(), limiter.clone()))
+    .or(check_handle(db.clone()))
+    .or(search_image_by_url(
+        db.clone(),
+        limiter.clone(),
+        bkapi.clone(),
+        endpoints,
+    ))
+    .or(search_duplicates(
+        db.clone(),
+        limiter.clone(),
+        bkapi.clone(),
+    ))
+    .or(artist_submissions(db.clone(), limiter.clone()))
+    .or(related_artists(db.clone(), limiter.clone(), bkapi))
+    .or(subscribe_hashes(limiter.clone(), hash_added))
+    .or(api_key_usage(db, limiter))
+    .or(openapi())
 }
 
-pub fn search_file(db: Pool) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
-    warp::path("file")
+pub fn search_file(
+    db: Pool,
+    limiter: Limiter,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    route(&SEARCH_FILE)
         .and(warp::header::headers_cloned())
-        .and(warp::get())
-        .and(warp::query::<FileSearchOpts>())
+        .and(query::<FileSearchOpts>())
         .and(with_pool(db))
+        .and(with_limiter(limiter))
         .and(with_api_key())
-        .and_then(|headers, opts, db, api_key| {
+        .and_then(|headers, opts, db, limiter, api_key| {
             use tracing_opentelemetry::OpenTelemetrySpanExt;
 
             let span = tracing::info_span!("search_file", ?opts);
             span.set_parent(with_telem(headers));
-            span.in_scope(|| handlers::search_file(opts, db, api_key).in_current_span())
+            span.in_scope(|| handlers::search_file(opts, db, limiter, api_key).in_current_span())
+        })
+}
+
+pub fn search_sha256(
+    db: Pool,
+    limiter: Limiter,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    route(&SEARCH_SHA256)
+        .and(warp::header::headers_cloned())
+        .and(query::<Sha256SearchOpts>())
+        .and(with_pool(db))
+        .and(with_limiter(limiter))
+        .and(with_api_key())
+        .and_then(|headers, opts, db, limiter, api_key| {
+            use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+            let span = tracing::info_span!("search_sha256", ?opts);
+            span.set_parent(with_telem(headers));
+            span.in_scope(|| handlers::search_sha256(opts, db, limiter, api_key).in_current_span())
+        })
+}
+
+pub fn search_sha256_file(
+    db: Pool,
+    limiter: Limiter,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    route(&SEARCH_SHA256_FILE)
+        .and(warp::header::headers_cloned())
+        .and(warp::multipart::form().max_length(1024 * 1024 * 50))
+        .and(with_pool(db))
+        .and(with_limiter(limiter))
+        .and(with_api_key())
+        .and_then(|headers, form, db, limiter, api_key| {
+            use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+            let span = tracing::info_span!("search_sha256_file");
+            span.set_parent(with_telem(headers));
+            span.in_scope(|| {
+                handlers::search_sha256_file(form, db, limiter, api_key).in_current_span()
+            })
         })
 }
 
 pub fn search_image(
     db: Pool,
+    limiter: Limiter,
     bkapi: bkapi_client::BKApiClient,
     endpoints: Endpoints,
 ) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
-    warp::path("image")
+    route(&SEARCH_IMAGE)
         .and(warp::header::headers_cloned())
-        .and(warp::post())
         .and(warp::multipart::form().max_length(1024 * 1024 * 10))
-        .and(warp::query::<ImageSearchOpts>())
+        .and(query::<ImageSearchOpts>())
+        .and(query::<SearchFilterOpts>())
+        .and(query::<SearchPageOpts>())
         .and(with_pool(db))
+        .and(with_limiter(limiter))
         .and(with_bkapi(bkapi))
         .and(with_api_key())
         .and(with_endpoints(endpoints))
-        .and_then(|headers, form, opts, pool, bkapi, api_key, endpoints| {
-            use tracing_opentelemetry::OpenTelemetrySpanExt;
+        .and_then(
+            |headers, form, opts, filter, page, pool, limiter, bkapi, api_key, endpoints| {
+                use tracing_opentelemetry::OpenTelemetrySpanExt;
 
-            let span = tracing::info_span!("search_image", ?opts);
-            span.set_parent(with_telem(headers));
-            span.in_scope(|| {
-                handlers::search_image(form, opts, pool, bkapi, api_key, endpoints)
+                let span = tracing::info_span!("search_image", ?opts, ?filter, ?page);
+                span.set_parent(with_telem(headers));
+                span.in_scope(|| {
+                    handlers::search_image(
+                        form, opts, filter, page, pool, limiter, bkapi, api_key, endpoints,
+                    )
                     .in_current_span()
-            })
-        })
+                })
+            },
+        )
+}
+
+pub fn search_image_batch(
+    db: Pool,
+    limiter: Limiter,
+    bkapi: bkapi_client::BKApiClient,
+    endpoints: Endpoints,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    route(&SEARCH_IMAGE_BATCH)
+        .and(warp::header::headers_cloned())
+        .and(warp::multipart::form().max_length(1024 * 1024 * 50))
+        .and(query::<ImageSearchOpts>())
+        .and(query::<SearchFilterOpts>())
+        .and(with_pool(db))
+        .and(with_limiter(limiter))
+        .and(with_bkapi(bkapi))
+        .and(with_api_key())
+        .and(with_endpoints(endpoints))
+        .and_then(
+            |headers, form, opts, filter, pool, limiter, bkapi, api_key, endpoints| {
+                use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+                let span = tracing::info_span!("search_image_batch", ?opts, ?filter);
+                span.set_parent(with_telem(headers));
+                span.in_scope(|| {
+                    handlers::search_image_batch(
+                        form, opts, filter, pool, limiter, bkapi, api_key, endpoints,
+                    )
+                    .in_current_span()
+                })
+            },
+        )
+}
+
+pub fn search_video(
+    db: Pool,
+    limiter: Limiter,
+    bkapi: bkapi_client::BKApiClient,
+    endpoints: Endpoints,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    route(&SEARCH_VIDEO)
+        .and(warp::header::headers_cloned())
+        .and(warp::multipart::form().max_length(1024 * 1024 * 50))
+        .and(query::<ImageSearchOpts>())
+        .and(query::<SearchFilterOpts>())
+        .and(with_pool(db))
+        .and(with_limiter(limiter))
+        .and(with_bkapi(bkapi))
+        .and(with_api_key())
+        .and(with_endpoints(endpoints))
+        .and_then(
+            |headers, form, opts, filter, pool, limiter, bkapi, api_key, endpoints| {
+                use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+                let span = tracing::info_span!("search_video", ?opts, ?filter);
+                span.set_parent(with_telem(headers));
+                span.in_scope(|| {
+                    handlers::search_video(
+                        form, opts, filter, pool, limiter, bkapi, api_key, endpoints,
+                    )
+                    .in_current_span()
+                })
+            },
+        )
 }
 
 pub fn search_image_by_url(
     db: Pool,
+    limiter: Limiter,
     bkapi: bkapi_client::BKApiClient,
+    endpoints: Endpoints,
 ) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
-    warp::path("url")
-        .and(warp::get())
-        .and(warp::query::<UrlSearchOpts>())
+    route(&SEARCH_IMAGE_BY_URL)
+        .and(query::<UrlSearchOpts>())
+        .and(query::<SearchFilterOpts>())
+        .and(query::<SearchPageOpts>())
         .and(with_pool(db))
+        .and(with_limiter(limiter))
         .and(with_bkapi(bkapi))
         .and(with_api_key())
+        .and(with_endpoints(endpoints))
         .and_then(handlers::search_image_by_url)
 }
 
 pub fn search_hashes(
     db: Pool,
+    limiter: Limiter,
     bkapi: bkapi_client::BKApiClient,
 ) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
-    warp::path("hashes")
+    route(&SEARCH_HASHES)
         .and(warp::header::headers_cloned())
-        .and(warp::get())
-        .and(warp::query::<HashSearchOpts>())
+        .and(query::<HashSearchOpts>())
+        .and(query::<SearchFilterOpts>())
+        .and(query::<SearchPageOpts>())
         .and(with_pool(db))
+        .and(with_limiter(limiter))
         .and(with_bkapi(bkapi))
         .and(with_api_key())
-        .and_then(|headers, opts, db, bkapi, api_key| {
+        .and_then(|headers, opts, filter, page, db, limiter, bkapi, api_key| {
             use tracing_opentelemetry::OpenTelemetrySpanExt;
 
-            let span = tracing::info_span!("search_hashes", ?opts);
+            let span = tracing::info_span!("search_hashes", ?opts, ?filter, ?page);
             span.set_parent(with_telem(headers));
-            span.in_scope(|| handlers::search_hashes(opts, db, bkapi, api_key).in_current_span())
+            span.in_scope(|| {
+                handlers::search_hashes(opts, filter, page, db, limiter, bkapi, api_key)
+                    .in_current_span()
+            })
         })
 }
 
+pub fn search_duplicates(
+    db: Pool,
+    limiter: Limiter,
+    bkapi: bkapi_client::BKApiClient,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    route(&SEARCH_DUPLICATES)
+        .and(query::<DuplicateOpts>())
+        .and(with_pool(db))
+        .and(with_limiter(limiter))
+        .and(with_bkapi(bkapi))
+        .and(with_api_key())
+        .and_then(handlers::search_duplicates)
+}
+
+pub fn subscribe_hashes(
+    limiter: Limiter,
+    hash_added: HashAddedSender,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    route(&SUBSCRIBE_HASHES)
+        .and(query::<SubscribeOpts>())
+        .and(warp::any().map(move || hash_added.clone()))
+        .and(with_limiter(limiter))
+        .and(with_api_key())
+        .and_then(handlers::subscribe_hashes)
+}
+
+pub fn artist_submissions(
+    db: Pool,
+    limiter: Limiter,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    route(&ARTIST_SUBMISSIONS)
+        .and(query::<ArtistOpts>())
+        .and(query::<IdPageOpts>())
+        .and(with_pool(db))
+        .and(with_limiter(limiter))
+        .and(with_api_key())
+        .and_then(handlers::artist_submissions)
+}
+
+pub fn related_artists(
+    db: Pool,
+    limiter: Limiter,
+    bkapi: bkapi_client::BKApiClient,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    route(&RELATED_ARTISTS)
+        .and(query::<ArtistOpts>())
+        .and(query::<HashKindOpts>())
+        .and(with_pool(db))
+        .and(with_limiter(limiter))
+        .and(with_bkapi(bkapi))
+        .and(with_api_key())
+        .and_then(handlers::related_artists)
+}
+
+pub fn api_key_usage(
+    db: Pool,
+    limiter: Limiter,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    route(&API_KEY_USAGE)
+        .and(query::<UsageOpts>())
+        .and(with_pool(db))
+        .and(with_limiter(limiter))
+        .and(with_api_key())
+        .and_then(handlers::api_key_usage)
+}
+
 pub fn check_handle(db: Pool) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
-    warp::path("handle")
-        .and(warp::get())
-        .and(warp::query::<HandleOpts>())
+    route(&CHECK_HANDLE)
+        .and(query::<HandleOpts>())
         .and(with_pool(db))
         .and_then(handlers::check_handle)
 }
 
+/// Account management, where webhooks can only be redelivered if there is a
+/// Faktory client.
+pub fn accounts(
+    db: Pool,
+    mailer: Option<Mailer>,
+    faktory: Option<FaktoryClient>,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    register_account(db.clone(), mailer)
+        .or(verify_account(db.clone()))
+        .or(get_account(db.clone()))
+        .or(list_api_keys(db.clone()))
+        .or(create_api_key(db.clone()))
+        .or(rename_api_key(db.clone()))
+        .or(rotate_api_key(db.clone()))
+        .or(revoke_api_key(db.clone()))
+        .or(update_api_key_limits(db.clone()))
+        .or(list_hash_watches(db.clone()))
+        .or(watch_hash(db.clone()))
+        .or(unwatch_hash(db.clone()))
+        .or(list_webhooks(db.clone()))
+        .or(create_webhook(db.clone()))
+        .or(update_webhook_filters(db.clone()))
+        .or(rotate_webhook_secret(db.clone()))
+        .or(enable_webhook(db.clone()))
+        .or(list_webhook_deliveries(db.clone()))
+        .or(redeliver_webhook(db.clone(), faktory))
+        .or(delete_webhook(db))
+}
+
+pub fn register_account(
+    db: Pool,
+    mailer: Option<Mailer>,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    route(&REGISTER_ACCOUNT)
+        .and(json_body())
+        .and(with_client_addr())
+        .and(with_pool(db))
+        .and(warp::any().map(move || mailer.clone()))
+        .and_then(handlers::register_account)
+}
+
+pub fn verify_account(db: Pool) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    route(&VERIFY_ACCOUNT)
+        .and(json_body())
+        .and(with_pool(db))
+        .and_then(handlers::verify_account)
+}
+
+pub fn get_account(db: Pool) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    route(&GET_ACCOUNT)
+        .and(with_credentials())
+        .and(with_pool(db))
+        .and_then(handlers::get_account)
+}
+
+pub fn list_api_keys(db: Pool) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    route(&LIST_API_KEYS)
+        .and(with_credentials())
+        .and(with_pool(db))
+        .and_then(handlers::list_api_keys)
+}
+
+pub fn create_api_key(db: Pool) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    route(&CREATE_API_KEY)
+        .and(with_credentials())
+        .and(json_body())
+        .and(with_pool(db))
+        .and_then(handlers::create_api_key)
+}
+
+pub fn rename_api_key(db: Pool) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    route(&RENAME_API_KEY)
+        .and(path_param::<i32>(&RENAME_API_KEY, "key_id"))
+        .and(with_credentials())
+        .and(json_body())
+        .and(with_pool(db))
+        .and_then(handlers::rename_api_key)
+}
+
+pub fn rotate_api_key(db: Pool) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    route(&ROTATE_API_KEY)
+        .and(path_param::<i32>(&ROTATE_API_KEY, "key_id"))
+        .and(with_credentials())
+        .and(with_pool(db))
+        .and_then(handlers::rotate_api_key)
+}
+
+pub fn revoke_api_key(db: Pool) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    route(&REVOKE_API_KEY)
+        .and(path_param::<i32>(&REVOKE_API_KEY, "key_id"))
+        .and(with_credentials())
+        .and(with_pool(db))
+        .and_then(handlers::revoke_api_key)
+}
+
+pub fn update_api_key_limits(
+    db: Pool,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    route(&UPDATE_API_KEY_LIMITS)
+        .and(path_param::<i32>(&UPDATE_API_KEY_LIMITS, "key_id"))
+        .and(with_credentials())
+        .and(json_body())
+        .and(with_pool(db))
+        .and_then(handlers::update_api_key_limits)
+}
+
+pub fn list_hash_watches(db: Pool) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    route(&LIST_HASH_WATCHES)
+        .and(with_credentials())
+        .and(with_pool(db))
+        .and_then(handlers::list_hash_watches)
+}
+
+pub fn watch_hash(db: Pool) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    route(&WATCH_HASH)
+        .and(with_credentials())
+        .and(json_body())
+        .and(with_pool(db))
+        .and_then(handlers::watch_hash)
+}
+
+pub fn unwatch_hash(db: Pool) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    route(&UNWATCH_HASH)
+        .and(path_param::<i32>(&UNWATCH_HASH, "watch_id"))
+        .and(with_credentials())
+        .and(with_pool(db))
+        .and_then(handlers::unwatch_hash)
+}
+
+pub fn list_webhooks(db: Pool) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    route(&LIST_WEBHOOKS)
+        .and(with_credentials())
+        .and(with_pool(db))
+        .and_then(handlers::list_webhooks)
+}
+
+pub fn create_webhook(db: Pool) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    route(&CREATE_WEBHOOK)
+        .and(with_credentials())
+        .and(json_body())
+        .and(with_pool(db))
+        .and_then(handlers::create_webhook)
+}
+
+pub fn update_webhook_filters(
+    db: Pool,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    route(&UPDATE_WEBHOOK_FILTERS)
+        .and(path_param::<i32>(&UPDATE_WEBHOOK_FILTERS, "webhook_id"))
+        .and(with_credentials())
+        .and(json_body())
+        .and(with_pool(db))
+        .and_then(handlers::update_webhook_filters)
+}
+
+pub fn rotate_webhook_secret(
+    db: Pool,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    route(&ROTATE_WEBHOOK_SECRET)
+        .and(path_param::<i32>(&ROTATE_WEBHOOK_SECRET, "webhook_id"))
+        .and(with_credentials())
+        .and(with_pool(db))
+        .and_then(handlers::rotate_webhook_secret)
+}
+
+pub fn enable_webhook(db: Pool) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    route(&ENABLE_WEBHOOK)
+        .and(path_param::<i32>(&ENABLE_WEBHOOK, "webhook_id"))
+        .and(with_credentials())
+        .and(with_pool(db))
+        .and_then(handlers::enable_webhook)
+}
+
+pub fn list_webhook_deliveries(
+    db: Pool,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    route(&LIST_WEBHOOK_DELIVERIES)
+        .and(path_param::<i32>(&LIST_WEBHOOK_DELIVERIES, "webhook_id"))
+        .and(query::<IdPageOpts>())
+        .and(with_credentials())
+        .and(with_pool(db))
+        .and_then(handlers::list_webhook_deliveries)
+}
+
+pub fn redeliver_webhook(
+    db: Pool,
+    faktory: Option<FaktoryClient>,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    route(&REDELIVER_WEBHOOK)
+        .and(path_param::<i32>(&REDELIVER_WEBHOOK, "webhook_id"))
+        .and(path_param::<i64>(&REDELIVER_WEBHOOK, "delivery_id"))
+        .and(with_credentials())
+        .and(with_pool(db))
+        .and(warp::any().map(move || faktory.clone()))
+        .and_then(handlers::redeliver_webhook)
+}
+
+pub fn delete_webhook(db: Pool) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    route(&DELETE_WEBHOOK)
+        .and(path_param::<i32>(&DELETE_WEBHOOK, "webhook_id"))
+        .and(with_credentials())
+        .and(with_pool(db))
+        .and_then(handlers::delete_webhook)
+}
+
+pub fn openapi() -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    use utoipa::OpenApi;
+
+    let doc = crate::openapi::ApiDoc::openapi();
+
+    warp::path!("openapi.json")
+        .and(warp::get())
+        .map(move || warp::reply::json(&doc))
+}
+
+/// The query parameters described by a type.
+fn params<T: utoipa::IntoParams>() -> Vec<Parameter> {
+    T::into_params(|| Some(ParameterIn::Query))
+}
+
+/// Match requests for a route's method and path.
+fn route(route: &'static Route) -> BoxedFilter<()> {
+    let method = match route.method {
+        Method::GET => warp::get().boxed(),
+        Method::POST => warp::post().boxed(),
+        Method::DELETE => warp::delete().boxed(),
+        ref method => unreachable!("no routes use {}", method),
+    };
+
+    warp::path::full()
+        .and_then(move |path: FullPath| {
+            let matches = route.matches(path.as_str());
+            async move {
+                if matches {
+                    Ok(())
+                } else {
+                    Err(warp::reject::not_found())
+                }
+            }
+        })
+        .untuple_one()
+        .and(method)
+        .boxed()
+}
+
+/// A path parameter of a route.
+fn path_param<T>(
+    route: &'static Route,
+    name: &'static str,
+) -> impl Filter<Extract = (T,), Error = Rejection> + Clone
+where
+    T: std::str::FromStr + Send + 'static,
+{
+    let index = Route::segments(route.path)
+        .position(|segment| Route::param_name(segment) == Some(name))
+        .unwrap_or_else(|| panic!("{} has no {} parameter", route.path, name));
+
+    warp::path::full().and_then(move |path: FullPath| {
+        let param = Route::segments(path.as_str())
+            .nth(index)
+            .and_then(|segment| segment.parse().ok());
+        async move { param.ok_or_else(warp::reject::not_found) }
+    })
+}
+
+/// Query parameters, which must be described by the type so every query
+/// accepted by a filter can be included in the OpenAPI document.
+fn query<T>() -> impl Filter<Extract = (T,), Error = Rejection> + Clone
+where
+    T: serde::de::DeserializeOwned + utoipa::IntoParams + Send + 'static,
+{
+    warp::query::<T>()
+}
+
 fn with_api_key() -> impl Filter<Extract = (String,), Error = Rejection> + Clone {
     warp::header::<String>("x-api-key")
 }
 
+/// The HTTP basic authorization credentials used to authenticate an account.
+fn with_credentials() -> impl Filter<Extract = (handlers::Credentials,), Error = Rejection> + Clone
+{
+    warp::header::optional::<String>("authorization")
+        .and(with_client_addr())
+        .map(|authorization, client| handlers::Credentials {
+            authorization,
+            client,
+        })
+}
+
+/// The IP address of the client. Requests from private addresses are assumed
+/// to come from a proxy, which adds the client's address to the end of the
+/// X-Forwarded-For header.
+fn with_client_addr() -> impl Filter<Extract = (Option<IpAddr>,), Error = Rejection> + Clone {
+    warp::addr::remote()
+        .and(warp::header::optional::<String>("x-forwarded-for"))
+        .map(
+            |remote: Option<SocketAddr>, forwarded_for: Option<String>| match remote {
+                Some(remote) if fuzzysearch_common::net::is_public(remote.ip()) => {
+                    Some(remote.ip())
+                }
+                remote => forwarded_for
+                    .as_deref()
+                    .and_then(|forwarded_for| forwarded_for.rsplit(',').next())
+                    .and_then(|addr| addr.trim().parse().ok())
+                    .or_else(|| remote.map(|remote| remote.ip())),
+            },
+        )
+}
+
+fn json_body<T: serde::de::DeserializeOwned + Send>(
+) -> impl Filter<Extract = (T,), Error = Rejection> + Clone {
+    warp::body::content_length_limit(1024 * 16).and(warp::body::json())
+}
+
 fn with_pool(db: Pool) -> impl Filter<Extract = (Pool,), Error = Infallible> + Clone {
     warp::any().map(move || db.clone())
 }
 
+fn with_limiter(limiter: Limiter) -> impl Filter<Extract = (Limiter,), Error = Infallible> + Clone {
+    warp::any().map(move || limiter.clone())
+}
+
 fn with_bkapi(
     bkapi: bkapi_client::BKApiClient,
 ) -> impl Filter<Extract = (bkapi_client::BKApiClient,), Error = Infallible> + Clone {
@@ -128,3 +934,45 @@ fn with_telem(headers: warp::http::HeaderMap) -> opentelemetry::Context {
 
     remote_context
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn routes_match_paths() {
+        for (route, path, matches) in [
+            (&SEARCH_IMAGE, "/image", true),
+            (&SEARCH_IMAGE, "/image/", true),
+            (&SEARCH_IMAGE, "/image/batch", false),
+            (&SEARCH_IMAGE_BATCH, "/image/batch", true),
+            (&SEARCH_IMAGE_BATCH, "/image", false),
+            (&RENAME_API_KEY, "/account/keys/12/name", true),
+            (&RENAME_API_KEY, "/account/keys/name", false),
+            (&RENAME_API_KEY, "/account/keys/12/rotate", false),
+            (&DELETE_WEBHOOK, "/account/webhooks/3", true),
+            (&DELETE_WEBHOOK, "/account/webhooks/3/enable", false),
+        ] {
+            assert_eq!(route.matches(path), matches, "{} {}", route.path, path);
+        }
+    }
+
+    #[tokio::test]
+    async fn path_params_are_parsed() {
+        let filter = path_param::<i32>(&REDELIVER_WEBHOOK, "webhook_id")
+            .and(path_param::<i64>(&REDELIVER_WEBHOOK, "delivery_id"));
+
+        let params = warp::test::request()
+            .path("/account/webhooks/3/deliveries/12345678901/redeliver")
+            .filter(&filter)
+            .await
+            .unwrap();
+        assert_eq!(params, (3, 12_345_678_901));
+
+        assert!(warp::test::request()
+            .path("/account/webhooks/three/deliveries/1/redeliver")
+            .filter(&filter)
+            .await
+            .is_err());
+    }
+}
@@ -3,18 +3,20 @@ use futures::TryStreamExt;
 use hyper::StatusCode;
 use lazy_static::lazy_static;
 use prometheus::{register_histogram, register_int_counter, Histogram, IntCounter};
-use std::convert::TryInto;
-use tracing::{span, warn};
-use tracing_futures::Instrument;
+use tracing::warn;
 use warp::{Rejection, Reply};
 
-use crate::models::image_query;
+use crate::limiter::Limiter;
+use crate::models::{image_query, sha256_query};
 use crate::types::*;
 use crate::Endpoints;
 use crate::{early_return, rate_limit, Pool};
 use fuzzysearch_common::{
     trace::InjectContext,
-    types::{SearchResult, SiteInfo},
+    types::{
+        FrameHash, HashKind, ImageHashError, ImageHashErrorCode, ImageHashInfo, SearchResult, Site,
+        SiteInfo, HASH_INPUT_MEDIA_TYPE,
+    },
 };
 
 lazy_static! {
@@ -47,13 +49,40 @@ enum Error {
     Warp(warp::Error),
     InvalidData,
     InvalidImage,
+    UnsupportedImage,
+    ImageTooLarge,
+    TruncatedImage,
+    Download,
+    DownloadTimeout,
     ApiKey,
-    RateLimit,
+    RateLimit(RateLimitStatus),
+    Unauthorized,
+    Forbidden,
+    Unverified,
+    NotFound,
+    TooManyApiKeys,
+    TooManyHashWatches,
+    TooManyWebhooks,
+    TooManySubscriptions,
+    WebhookDisabled,
+    Unavailable,
+    Internal,
 }
 
-impl warp::Reply for Error {
-    fn into_response(self) -> warp::reply::Response {
-        let msg = match self {
+impl Error {
+    /// If the error was caused by the contents of a provided image.
+    fn is_image_error(&self) -> bool {
+        matches!(
+            self,
+            Error::InvalidImage
+                | Error::UnsupportedImage
+                | Error::ImageTooLarge
+                | Error::TruncatedImage
+        )
+    }
+
+    fn message(&self) -> ErrorMessage {
+        match self {
             Error::Postgres(_) | Error::Reqwest(_) | Error::Warp(_) => ErrorMessage {
                 code: 500,
                 message: "Internal server error".to_string(),
@@ -66,22 +95,104 @@ impl warp::Reply for Error {
                 code: 400,
                 message: "Invalid image provided".to_string(),
             },
+            Error::UnsupportedImage => ErrorMessage {
+                code: 415,
+                message: "Unsupported image format".to_string(),
+            },
+            Error::ImageTooLarge => ErrorMessage {
+                code: 413,
+                message: "Image too large".to_string(),
+            },
+            Error::TruncatedImage => ErrorMessage {
+                code: 400,
+                message: "Image data was truncated".to_string(),
+            },
+            Error::Download => ErrorMessage {
+                code: 502,
+                message: "Could not download image".to_string(),
+            },
+            Error::DownloadTimeout => ErrorMessage {
+                code: 504,
+                message: "Timed out downloading image".to_string(),
+            },
             Error::ApiKey => ErrorMessage {
                 code: 401,
                 message: "Invalid API key".to_string(),
             },
-            Error::RateLimit => ErrorMessage {
+            Error::RateLimit(_) => ErrorMessage {
                 code: 429,
                 message: "Too many requests".to_string(),
             },
+            Error::Unauthorized => ErrorMessage {
+                code: 401,
+                message: "Invalid account credentials".to_string(),
+            },
+            Error::Forbidden => ErrorMessage {
+                code: 403,
+                message: "Not permitted".to_string(),
+            },
+            Error::Unverified => ErrorMessage {
+                code: 403,
+                message: "Email address has not been verified".to_string(),
+            },
+            Error::NotFound => ErrorMessage {
+                code: 404,
+                message: "Not found".to_string(),
+            },
+            Error::TooManyApiKeys => ErrorMessage {
+                code: 409,
+                message: "Maximum number of API keys reached".to_string(),
+            },
+            Error::TooManyHashWatches => ErrorMessage {
+                code: 409,
+                message: "Maximum number of watched hashes reached".to_string(),
+            },
+            Error::TooManyWebhooks => ErrorMessage {
+                code: 409,
+                message: "Maximum number of webhooks reached".to_string(),
+            },
+            Error::TooManySubscriptions => ErrorMessage {
+                code: 429,
+                message: "Maximum number of open subscriptions reached".to_string(),
+            },
+            Error::WebhookDisabled => ErrorMessage {
+                code: 409,
+                message: "Webhook is disabled".to_string(),
+            },
+            Error::Unavailable => ErrorMessage {
+                code: 503,
+                message: "Service unavailable".to_string(),
+            },
+            Error::Internal => ErrorMessage {
+                code: 500,
+                message: "Internal server error".to_string(),
+            },
+        }
+    }
+}
+
+impl warp::Reply for Error {
+    fn into_response(self) -> warp::reply::Response {
+        let needs_credentials = matches!(self, Error::Unauthorized);
+        let rate_limit = match self {
+            Error::RateLimit(status) => Some(status),
+            _ => None,
         };
 
+        let msg = self.message();
+
         let body = hyper::body::Body::from(serde_json::to_string(&msg).unwrap());
 
-        warp::http::Response::builder()
-            .status(msg.code)
-            .body(body)
-            .unwrap()
+        let mut builder = warp::http::Response::builder().status(msg.code);
+        if needs_credentials {
+            builder = builder.header("www-authenticate", "Basic realm=\"fuzzysearch\"");
+        }
+
+        if let Some(status) = rate_limit {
+            builder = rate_limit_headers(builder, &[status], true);
+        }
+
+        builder.body(body).unwrap()
     }
 }
 
@@ -103,10 +214,60 @@ impl From<warp::Error> for Error {
     }
 }
 
+impl From<crate::models::ImageQueryError> for Error {
+    fn from(err: crate::models::ImageQueryError) -> Self {
+        match err {
+            crate::models::ImageQueryError::Database(err) => Self::Postgres(err),
+            crate::models::ImageQueryError::Bkapi(err) => {
+                tracing::error!("could not search bkapi: {}", err);
+                Self::Internal
+            }
+        }
+    }
+}
+
+impl From<crate::fetch::FetchError> for Error {
+    fn from(err: crate::fetch::FetchError) -> Self {
+        use crate::fetch::FetchError;
+
+        match err {
+            FetchError::InvalidUrl | FetchError::ForbiddenAddress => Error::InvalidData,
+            FetchError::TooLarge => Error::ImageTooLarge,
+            FetchError::TooManyRedirects | FetchError::NotImage => Error::InvalidImage,
+            FetchError::Timeout => Error::DownloadTimeout,
+            FetchError::Resolve(_) | FetchError::Status(_) | FetchError::Reqwest(_) => {
+                Error::Download
+            }
+        }
+    }
+}
+
+/// The maximum number of images that may be included in a batch search.
+const MAX_BATCH_IMAGES: usize = 50;
+
+/// The number of batch images to send to the hash input service at once.
+const BATCH_HASH_CONCURRENCY: usize = 4;
+
+/// The maximum number of SHA-256 digests that may be looked up at once.
+const MAX_SHA256_DIGESTS: usize = 50;
+
+/// Read the entire contents of a multipart part into memory.
+async fn read_part(part: warp::multipart::Part) -> bytes::BytesMut {
+    part.stream()
+        .fold(bytes::BytesMut::new(), |mut buf, chunk| {
+            use bytes::BufMut;
+
+            buf.put(chunk.unwrap());
+            async move { buf }
+        })
+        .await
+}
+
 #[tracing::instrument(skip(endpoints, form))]
 async fn hash_input(
     endpoints: &Endpoints,
     mut form: warp::multipart::FormData,
+    kind: HashKind,
 ) -> Result<i64, Error> {
     let mut image_part = None;
 
@@ -120,16 +281,15 @@ async fn hash_input(
     let image_part = image_part.ok_or(Error::InvalidImage)?;
 
     tracing::debug!("found image part, reading data");
-    let bytes = image_part
-        .stream()
-        .fold(bytes::BytesMut::new(), |mut buf, chunk| {
-            use bytes::BufMut;
+    let bytes = read_part(image_part).await;
 
-            buf.put(chunk.unwrap());
-            async move { buf }
-        })
-        .await;
-    let part = reqwest::multipart::Part::bytes(bytes.to_vec());
+    hash_bytes(endpoints, bytes.to_vec(), kind).await
+}
+
+/// Send image data to the hash input service and parse the resulting hash.
+#[tracing::instrument(skip(endpoints, bytes), fields(len = bytes.len()))]
+async fn hash_bytes(endpoints: &Endpoints, bytes: Vec<u8>, kind: HashKind) -> Result<i64, Error> {
+    let part = reqwest::multipart::Part::bytes(bytes);
 
     let form = reqwest::multipart::Form::new().part("image", part);
 
@@ -137,6 +297,8 @@ async fn hash_input(
     let client = reqwest::Client::new();
     let resp = client
         .post(&endpoints.hash_input)
+        .query(&[("kind", kind.as_str())])
+        .header(reqwest::header::ACCEPT, HASH_INPUT_MEDIA_TYPE)
         .inject_context()
         .multipart(form)
         .send()
@@ -144,71 +306,251 @@ async fn hash_input(
 
     tracing::debug!("got response");
     if resp.status() != StatusCode::OK {
-        return Err(Error::InvalidImage);
+        return Err(hash_input_error(resp).await);
+    }
+
+    let info: ImageHashInfo = resp.json().await.map_err(|_err| Error::InvalidImage)?;
+
+    Ok(info.hash)
+}
+
+/// Convert an unsuccessful response from the hash input service into the
+/// matching error.
+async fn hash_input_error(resp: reqwest::Response) -> Error {
+    let err: ImageHashError = match resp.json().await {
+        Ok(err) => err,
+        Err(_err) => return Error::InvalidImage,
+    };
+
+    tracing::debug!(code = ?err.code, "hash input service could not hash image: {}", err.message);
+
+    match err.code {
+        ImageHashErrorCode::UnsupportedFormat => Error::UnsupportedImage,
+        ImageHashErrorCode::TooLarge => Error::ImageTooLarge,
+        ImageHashErrorCode::Truncated => Error::TruncatedImage,
+        ImageHashErrorCode::DecodeFailed
+        | ImageHashErrorCode::MissingField
+        | ImageHashErrorCode::Internal => Error::InvalidImage,
+    }
+}
+
+/// Send animation data to the hash input service, returning the hash of each
+/// keyframe.
+#[tracing::instrument(skip(endpoints, form))]
+async fn hash_video(
+    endpoints: &Endpoints,
+    mut form: warp::multipart::FormData,
+    kind: HashKind,
+) -> Result<Vec<FrameHash>, Error> {
+    let mut video_part = None;
+
+    while let Ok(Some(part)) = form.try_next().await {
+        if part.name() == "video" {
+            video_part = Some(part);
+        }
+    }
+
+    let video_part = video_part.ok_or(Error::InvalidImage)?;
+    let bytes = read_part(video_part).await;
+
+    let _timer = VIDEO_HASH_DURATION.start_timer();
+
+    let part = reqwest::multipart::Part::bytes(bytes.to_vec());
+    let form = reqwest::multipart::Form::new().part("video", part);
+
+    let endpoint = endpoints
+        .hash_input_video
+        .as_ref()
+        .ok_or(Error::Unavailable)?;
+
+    tracing::debug!("sending video to hash input service");
+    let client = reqwest::Client::new();
+    let resp = client
+        .post(endpoint)
+        .query(&[("kind", kind.as_str())])
+        .header(reqwest::header::ACCEPT, HASH_INPUT_MEDIA_TYPE)
+        .inject_context()
+        .multipart(form)
+        .send()
+        .await?;
+
+    if resp.status() != StatusCode::OK {
+        return Err(hash_input_error(resp).await);
+    }
+
+    let frames: Vec<FrameHash> = resp.json().await.map_err(|_err| Error::InvalidImage)?;
+
+    Ok(frames)
+}
+
+/// Read every part named `image` from a form, keeping the provided filename
+/// for each.
+#[tracing::instrument(skip(form))]
+async fn batch_image_parts(
+    mut form: warp::multipart::FormData,
+) -> Result<Vec<(Option<String>, bytes::BytesMut)>, Error> {
+    let mut parts = Vec::new();
+
+    while let Some(part) = form.try_next().await? {
+        if part.name() != "image" {
+            continue;
+        }
+
+        if parts.len() >= MAX_BATCH_IMAGES {
+            return Err(Error::InvalidData);
+        }
+
+        let filename = part.filename().map(ToString::to_string);
+        let bytes = read_part(part).await;
+
+        parts.push((filename, bytes));
     }
 
-    let hash: i64 = resp
-        .text()
-        .await?
-        .parse()
-        .map_err(|_err| Error::InvalidImage)?;
+    tracing::debug!("found {} image parts", parts.len());
 
-    Ok(hash)
+    Ok(parts)
 }
 
+/// Group the results of a batch search back to the image each hash was from,
+/// in the order the images were provided.
+///
+/// Like the single image search, close matches are only included when there
+/// were no exact matches for an image unless they were forced.
+fn group_batch_results<I>(
+    filenames: I,
+    hashes: Vec<Result<i64, Error>>,
+    results: &[SearchResult],
+    force: bool,
+) -> Vec<BatchImageSimilarity>
+where
+    I: IntoIterator<Item = Option<String>>,
+{
+    filenames
+        .into_iter()
+        .zip(hashes)
+        .enumerate()
+        .map(|(index, (filename, hash))| {
+            let hash = match hash {
+                Ok(hash) => hash,
+                Err(err) => {
+                    return BatchImageSimilarity {
+                        index,
+                        filename,
+                        hash: None,
+                        matches: Vec::new(),
+                        error: Some(err.message()),
+                    }
+                }
+            };
+
+            let mut matches: Vec<SearchResult> = results
+                .iter()
+                .filter(|result| result.searched_hash == Some(hash))
+                .cloned()
+                .collect();
+
+            if !force && matches.iter().any(|result| result.distance == Some(0)) {
+                matches.retain(|result| result.distance == Some(0));
+            }
+
+            matches.sort_by_key(|result| result.distance.unwrap_or(u64::MAX));
+
+            BatchImageSimilarity {
+                index,
+                filename,
+                hash: Some(hash),
+                matches,
+                error: None,
+            }
+        })
+        .collect()
+}
+
+#[utoipa::path(
+    post,
+    path = "/image",
+    tag = "search",
+    params(ImageSearchOpts, SearchFilterOpts, SearchPageOpts),
+    request_body(content = ImageForm, content_type = "multipart/form-data"),
+    responses(
+        (
+            status = 200,
+            description = "Submissions similar to the image",
+            body = ImageSimilarity,
+            headers(
+                ("x-has-more" = bool, description = "If there are more results"),
+                ("x-next-cursor" = String, description = "The cursor for the next page"),
+            ),
+        ),
+        (status = 400, description = "Invalid image", body = ErrorMessage),
+        (status = 401, description = "Invalid API key", body = ErrorMessage),
+        (status = 429, description = "Rate limit exceeded", body = ErrorMessage),
+    ),
+    security(("api_key" = [])),
+)]
+#[allow(clippy::too_many_arguments)]
 pub async fn search_image(
     form: warp::multipart::FormData,
     opts: ImageSearchOpts,
+    filter: SearchFilterOpts,
+    page: SearchPageOpts,
     db: Pool,
+    limiter: Limiter,
     bkapi: bkapi_client::BKApiClient,
     api_key: String,
     endpoints: Endpoints,
 ) -> Result<Box<dyn Reply>, Rejection> {
-    let image_remaining = rate_limit!(&api_key, &db, image_limit, "image");
-    let hash_remaining = rate_limit!(&api_key, &db, hash_limit, "hash");
-
-    let num = early_return!(hash_input(&endpoints, form).await);
+    let image_remaining = rate_limit!(&api_key, &limiter, image_limit, "image");
+    let hash_remaining = rate_limit!(&api_key, &limiter, hash_limit, "hash");
+
+    let kind = opts.kind.unwrap_or_default();
+    let num = early_return!(hash_input(&endpoints, form, kind).await);
+
+    let search = |distance| {
+        image_query(
+            db.clone(),
+            bkapi.clone(),
+            vec![num],
+            distance,
+            kind,
+            &filter,
+            Some(&page),
+        )
+    };
 
     let mut items = {
         if opts.search_type == Some(ImageSearchType::Force) {
-            image_query(db.clone(), bkapi.clone(), vec![num], 10)
-                .await
-                .unwrap()
+            early_return!(search(10).await)
+        } else if let Some(ref cursor) = page.cursor {
+            // Later pages must keep searching at the same distance as the
+            // first page, which only had inexact matches if it fell back.
+            let distance = if cursor.distance > 0 { 10 } else { 0 };
+
+            early_return!(search(distance).await)
         } else {
-            let results = image_query(db.clone(), bkapi.clone(), vec![num], 0)
-                .await
-                .unwrap();
+            let results = early_return!(search(0).await);
             if results.is_empty() && opts.search_type != Some(ImageSearchType::Exact) {
-                image_query(db.clone(), bkapi.clone(), vec![num], 10)
-                    .await
-                    .unwrap()
+                early_return!(search(10).await)
             } else {
                 results
             }
         }
     };
 
-    items.sort_by(|a, b| {
-        a.distance
-            .unwrap_or(u64::max_value())
-            .partial_cmp(&b.distance.unwrap_or(u64::max_value()))
-            .unwrap()
-    });
+    let next_cursor = page.next_cursor(&mut items);
 
     let similarity = ImageSimilarity {
         hash: num,
         matches: items,
     };
 
-    let resp = warp::http::Response::builder()
+    let builder = rate_limit_headers(
+        warp::http::Response::builder(),
+        &[image_remaining, hash_remaining],
+        false,
+    );
+    let resp = page_headers(builder, next_cursor)
         .header("x-image-hash", num.to_string())
-        .header("x-rate-limit-total-image", image_remaining.1.to_string())
-        .header(
-            "x-rate-limit-remaining-image",
-            image_remaining.0.to_string(),
-        )
-        .header("x-rate-limit-total-hash", hash_remaining.1.to_string())
-        .header("x-rate-limit-remaining-hash", hash_remaining.0.to_string())
         .header("content-type", "application/json")
         .body(serde_json::to_string(&similarity).unwrap())
         .unwrap();
@@ -216,9 +558,296 @@ pub async fn search_image(
     Ok(Box::new(resp))
 }
 
+/// Add rate limit headers for the groups used by a request.
+///
+/// Each API key group gets the legacy `x-rate-limit-total-*` and
+/// `x-rate-limit-remaining-*` headers, and the standard `RateLimit-*` headers
+/// describe the most limited group. When the request was refused, that
+/// group's reset is also given as `Retry-After`.
+fn rate_limit_headers(
+    mut builder: warp::http::response::Builder,
+    statuses: &[RateLimitStatus],
+    limited: bool,
+) -> warp::http::response::Builder {
+    for status in statuses {
+        if let Some(group) = status.group {
+            builder = builder
+                .header(
+                    format!("x-rate-limit-total-{}", group).as_str(),
+                    status.limit.to_string(),
+                )
+                .header(
+                    format!("x-rate-limit-remaining-{}", group).as_str(),
+                    status.remaining.to_string(),
+                );
+        }
+    }
+
+    let status = match statuses.iter().min_by_key(|status| status.remaining) {
+        Some(status) => status,
+        None => return builder,
+    };
+
+    builder = builder
+        .header("ratelimit-limit", status.limit.to_string())
+        .header("ratelimit-remaining", status.remaining.to_string())
+        .header("ratelimit-reset", status.reset.to_string());
+
+    if limited {
+        builder = builder.header("retry-after", status.reset.to_string());
+    }
+
+    builder
+}
+
+/// Add headers describing if there is another page of results, and how to
+/// request it.
+fn page_headers<C: std::fmt::Display>(
+    builder: warp::http::response::Builder,
+    next_cursor: Option<C>,
+) -> warp::http::response::Builder {
+    let builder = builder.header("x-has-more", next_cursor.is_some().to_string());
+
+    match next_cursor {
+        Some(cursor) => builder.header("x-next-cursor", cursor.to_string()),
+        None => builder,
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/image/batch",
+    tag = "search",
+    params(ImageSearchOpts, SearchFilterOpts),
+    request_body(content = BatchImageForm, content_type = "multipart/form-data"),
+    responses(
+        (status = 200, description = "Submissions similar to each image", body = [BatchImageSimilarity]),
+        (status = 400, description = "Invalid image", body = ErrorMessage),
+        (status = 401, description = "Invalid API key", body = ErrorMessage),
+        (status = 429, description = "Rate limit exceeded", body = ErrorMessage),
+    ),
+    security(("api_key" = [])),
+)]
+#[allow(clippy::too_many_arguments)]
+pub async fn search_image_batch(
+    form: warp::multipart::FormData,
+    opts: ImageSearchOpts,
+    filter: SearchFilterOpts,
+    db: Pool,
+    limiter: Limiter,
+    bkapi: bkapi_client::BKApiClient,
+    api_key: String,
+    endpoints: Endpoints,
+) -> Result<Box<dyn Reply>, Rejection> {
+    let parts = early_return!(batch_image_parts(form).await);
+    if parts.is_empty() {
+        return Ok(Box::new(Error::InvalidImage));
+    }
+
+    let count = parts.len() as i16;
+    let image_remaining = rate_limit!(&api_key, &limiter, image_limit, "image", count);
+    let hash_remaining = rate_limit!(&api_key, &limiter, hash_limit, "hash", count);
+
+    let kind = opts.kind.unwrap_or_default();
+
+    let images: Vec<Vec<u8>> = parts
+        .iter()
+        .map(|(_filename, bytes)| bytes.to_vec())
+        .collect();
+    let hashes: Vec<Result<i64, Error>> = futures::stream::iter(images)
+        .map(|bytes| {
+            let endpoints = endpoints.clone();
+            async move { hash_bytes(&endpoints, bytes, kind).await }
+        })
+        .buffered(BATCH_HASH_CONCURRENCY)
+        .collect()
+        .await;
+
+    // Images that could not be hashed get their own error, but anything else
+    // means none of the images can be searched.
+    let hashes: Result<Vec<Result<i64, Error>>, Error> = hashes
+        .into_iter()
+        .map(|hash| match hash {
+            Err(err) if !err.is_image_error() => Err(err),
+            hash => Ok(hash),
+        })
+        .collect();
+    let hashes = early_return!(hashes);
+
+    let mut unique_hashes: Vec<i64> = hashes
+        .iter()
+        .filter_map(|hash| hash.as_ref().ok())
+        .copied()
+        .collect();
+    unique_hashes.sort_unstable();
+    unique_hashes.dedup();
+
+    let distance = if opts.search_type == Some(ImageSearchType::Exact) {
+        0
+    } else {
+        10
+    };
+
+    let results = if unique_hashes.is_empty() {
+        Vec::new()
+    } else {
+        early_return!(
+            image_query(
+                db.clone(),
+                bkapi.clone(),
+                unique_hashes,
+                distance,
+                kind,
+                &filter,
+                None
+            )
+            .await
+        )
+    };
+
+    let filenames = parts.into_iter().map(|(filename, _bytes)| filename);
+    let similarities = group_batch_results(
+        filenames,
+        hashes,
+        &results,
+        opts.search_type == Some(ImageSearchType::Force),
+    );
+
+    let builder = rate_limit_headers(
+        warp::http::Response::builder(),
+        &[image_remaining, hash_remaining],
+        false,
+    );
+    let resp = builder
+        .header("content-type", "application/json")
+        .body(serde_json::to_string(&similarities).unwrap())
+        .unwrap();
+
+    Ok(Box::new(resp))
+}
+
+#[utoipa::path(
+    post,
+    path = "/video",
+    tag = "search",
+    params(ImageSearchOpts, SearchFilterOpts),
+    request_body(content = VideoForm, content_type = "multipart/form-data"),
+    responses(
+        (status = 200, description = "Submissions similar to each keyframe", body = [FrameSimilarity]),
+        (status = 400, description = "Invalid animation", body = ErrorMessage),
+        (status = 401, description = "Invalid API key", body = ErrorMessage),
+        (status = 429, description = "Rate limit exceeded", body = ErrorMessage),
+        (status = 503, description = "Animation search is not configured", body = ErrorMessage),
+    ),
+    security(("api_key" = [])),
+)]
+#[allow(clippy::too_many_arguments)]
+pub async fn search_video(
+    form: warp::multipart::FormData,
+    opts: ImageSearchOpts,
+    filter: SearchFilterOpts,
+    db: Pool,
+    limiter: Limiter,
+    bkapi: bkapi_client::BKApiClient,
+    api_key: String,
+    endpoints: Endpoints,
+) -> Result<Box<dyn Reply>, Rejection> {
+    if endpoints.hash_input_video.is_none() {
+        return Ok(Box::new(Error::Unavailable));
+    }
+
+    // The number of keyframes isn't known until the animation is hashed, so
+    // only make sure the key isn't already limited before hashing.
+    rate_limit!(&api_key, &limiter, image_limit, "image", 0);
+
+    let kind = opts.kind.unwrap_or_default();
+    let frames = early_return!(hash_video(&endpoints, form, kind).await);
+
+    let mut hashes: Vec<i64> = frames.iter().map(|frame| frame.hash).collect();
+    hashes.sort_unstable();
+    hashes.dedup();
+
+    // Like a batch search, each distinct keyframe is charged as an image.
+    let count = hashes.len() as i16;
+    let image_remaining = rate_limit!(&api_key, &limiter, image_limit, "image", count);
+    let hash_remaining = rate_limit!(&api_key, &limiter, hash_limit, "hash", count);
+
+    let distance = if opts.search_type == Some(ImageSearchType::Exact) {
+        0
+    } else {
+        10
+    };
+
+    let results = early_return!(
+        image_query(
+            db.clone(),
+            bkapi.clone(),
+            hashes,
+            distance,
+            kind,
+            &filter,
+            None
+        )
+        .await
+    );
+
+    let similarities: Vec<FrameSimilarity> = frames
+        .into_iter()
+        .map(|frame| {
+            let matches = results
+                .iter()
+                .filter(|result| result.searched_hash == Some(frame.hash))
+                .cloned()
+                .collect();
+
+            FrameSimilarity {
+                frame: frame.frame,
+                hash: frame.hash,
+                matches,
+            }
+        })
+        .collect();
+
+    let builder = rate_limit_headers(
+        warp::http::Response::builder(),
+        &[image_remaining, hash_remaining],
+        false,
+    );
+    let resp = builder
+        .header("content-type", "application/json")
+        .body(serde_json::to_string(&similarities).unwrap())
+        .unwrap();
+
+    Ok(Box::new(resp))
+}
+
+#[utoipa::path(
+    get,
+    path = "/hashes",
+    tag = "search",
+    params(HashSearchOpts, SearchFilterOpts, SearchPageOpts),
+    responses(
+        (
+            status = 200,
+            description = "Submissions similar to the hashes",
+            body = [SearchResult],
+            headers(
+                ("x-has-more" = bool, description = "If there are more results"),
+                ("x-next-cursor" = String, description = "The cursor for the next page"),
+            ),
+        ),
+        (status = 400, description = "Invalid hashes", body = ErrorMessage),
+        (status = 401, description = "Invalid API key", body = ErrorMessage),
+        (status = 429, description = "Rate limit exceeded", body = ErrorMessage),
+    ),
+    security(("api_key" = [])),
+)]
 pub async fn search_hashes(
     opts: HashSearchOpts,
+    filter: SearchFilterOpts,
+    page: SearchPageOpts,
     db: Pool,
+    limiter: Limiter,
     bkapi: bkapi_client::BKApiClient,
     api_key: String,
 ) -> Result<Box<dyn Reply>, Rejection> {
@@ -235,17 +864,137 @@ pub async fn search_hashes(
         return Ok(Box::new(Error::InvalidData));
     }
 
-    let image_remaining = rate_limit!(&api_key, &db, image_limit, "image", hashes.len() as i16);
+    let image_remaining = rate_limit!(
+        &api_key,
+        &limiter,
+        image_limit,
+        "image",
+        hashes.len() as i16
+    );
+
+    let mut results = early_return!(
+        image_query(
+            pool,
+            bkapi,
+            hashes.clone(),
+            opts.distance.unwrap_or(10),
+            opts.kind.unwrap_or_default(),
+            &filter,
+            Some(&page)
+        )
+        .await
+    );
 
-    let results =
-        early_return!(image_query(pool, bkapi, hashes.clone(), opts.distance.unwrap_or(10)).await);
+    let next_cursor = page.next_cursor(&mut results);
 
-    let resp = warp::http::Response::builder()
-        .header("x-rate-limit-total-image", image_remaining.1.to_string())
-        .header(
-            "x-rate-limit-remaining-image",
-            image_remaining.0.to_string(),
-        )
+    let builder = rate_limit_headers(warp::http::Response::builder(), &[image_remaining], false);
+    let resp = page_headers(builder, next_cursor)
+        .header("content-type", "application/json")
+        .body(serde_json::to_string(&results).unwrap())
+        .unwrap();
+
+    Ok(Box::new(resp))
+}
+
+#[utoipa::path(
+    get,
+    path = "/sha256",
+    tag = "search",
+    params(Sha256SearchOpts),
+    responses(
+        (status = 200, description = "Submissions with the digests", body = [SearchResult]),
+        (status = 400, description = "Invalid digests", body = ErrorMessage),
+        (status = 401, description = "Invalid API key", body = ErrorMessage),
+        (status = 429, description = "Rate limit exceeded", body = ErrorMessage),
+    ),
+    security(("api_key" = [])),
+)]
+pub async fn search_sha256(
+    opts: Sha256SearchOpts,
+    db: Pool,
+    limiter: Limiter,
+    api_key: String,
+) -> Result<Box<dyn Reply>, Rejection> {
+    let digests: Result<Vec<Vec<u8>>, _> = opts
+        .sha256
+        .split(',')
+        .map(|digest| hex::decode(digest.trim()))
+        .collect();
+
+    let digests = match digests {
+        Ok(digests)
+            if !digests.is_empty()
+                && digests.len() <= MAX_SHA256_DIGESTS
+                && digests.iter().all(|digest| digest.len() == 32) =>
+        {
+            digests
+        }
+        _ => return Ok(Box::new(Error::InvalidData)),
+    };
+
+    sha256_search(digests, db, limiter, api_key).await
+}
+
+#[utoipa::path(
+    post,
+    path = "/sha256",
+    tag = "search",
+    request_body(content = FileForm, content_type = "multipart/form-data"),
+    responses(
+        (status = 200, description = "Submissions with the same file", body = [SearchResult]),
+        (status = 400, description = "Missing file", body = ErrorMessage),
+        (status = 401, description = "Invalid API key", body = ErrorMessage),
+        (status = 429, description = "Rate limit exceeded", body = ErrorMessage),
+    ),
+    security(("api_key" = [])),
+)]
+pub async fn search_sha256_file(
+    mut form: warp::multipart::FormData,
+    db: Pool,
+    limiter: Limiter,
+    api_key: String,
+) -> Result<Box<dyn Reply>, Rejection> {
+    use sha2::{Digest, Sha256};
+
+    let mut file_part = None;
+
+    while let Ok(Some(part)) = form.try_next().await {
+        if part.name() == "file" {
+            file_part = Some(part);
+        }
+    }
+
+    let file_part = match file_part {
+        Some(part) => part,
+        None => return Ok(Box::new(Error::InvalidData)),
+    };
+
+    let bytes = read_part(file_part).await;
+    let digest = Sha256::digest(&bytes).to_vec();
+
+    sha256_search(vec![digest], db, limiter, api_key).await
+}
+
+/// Look up submissions with identical files, counting each digest against the
+/// SHA-256 rate limit.
+async fn sha256_search(
+    digests: Vec<Vec<u8>>,
+    db: Pool,
+    limiter: Limiter,
+    api_key: String,
+) -> Result<Box<dyn Reply>, Rejection> {
+    let sha256_remaining = rate_limit!(
+        &api_key,
+        &limiter,
+        sha256_limit,
+        "sha256",
+        digests.len() as i16
+    );
+
+    let results = early_return!(sha256_query(&db, digests).await);
+
+    let builder = rate_limit_headers(warp::http::Response::builder(), &[sha256_remaining], false);
+    let resp = builder
         .header("content-type", "application/json")
         .body(serde_json::to_string(&results).unwrap())
         .unwrap();
@@ -253,37 +1002,102 @@ pub async fn search_hashes(
     Ok(Box::new(resp))
 }
 
+#[utoipa::path(
+    get,
+    path = "/file",
+    tag = "search",
+    params(FileSearchOpts),
+    responses(
+        (status = 200, description = "Submissions with the file", body = [SearchResult]),
+        (status = 400, description = "Invalid search", body = ErrorMessage),
+        (status = 401, description = "Invalid API key", body = ErrorMessage),
+        (status = 429, description = "Rate limit exceeded", body = ErrorMessage),
+    ),
+    security(("api_key" = [])),
+)]
 pub async fn search_file(
     opts: FileSearchOpts,
     db: Pool,
+    limiter: Limiter,
     api_key: String,
 ) -> Result<Box<dyn Reply>, Rejection> {
-    use sqlx::Row;
+    let file_remaining = rate_limit!(&api_key, &limiter, name_limit, "file");
 
-    let file_remaining = rate_limit!(&api_key, &db, name_limit, "file");
+    let matches = early_return!(lookup_file(&opts, &db).await);
 
-    let query = if let Some(ref id) = opts.id {
-        sqlx::query(
-            "SELECT
-                    submission.id,
-                    submission.url,
-                    submission.filename,
-                    submission.file_id,
-                    submission.rating,
-                    submission.posted_at,
-                    submission.hash_int,
-                    submission.file_sha256,
-                    artist.name,
-                    array(SELECT tag.name FROM tag_to_post JOIN tag ON tag_to_post.tag_id = tag.id WHERE tag_to_post.post_id = submission.id) tags
+    let builder = rate_limit_headers(warp::http::Response::builder(), &[file_remaining], false);
+    let resp = builder
+        .header("content-type", "application/json")
+        .body(serde_json::to_string(&matches).unwrap())
+        .unwrap();
+
+    Ok(Box::new(resp))
+}
+
+/// Look up submissions on any site by their file or site ID.
+async fn lookup_file(opts: &FileSearchOpts, db: &Pool) -> Result<Vec<SearchResult>, Error> {
+    match opts.site.unwrap_or(Site::FurAffinity) {
+        Site::FurAffinity => furaffinity_file(opts, db).await,
+        site => site_file(site, opts, db).await,
+    }
+}
+
+/// The unique hashes stored for submissions.
+fn stored_hashes(submissions: &[SearchResult]) -> Vec<i64> {
+    let mut hashes: Vec<i64> = submissions
+        .iter()
+        .filter_map(|submission| submission.hash)
+        .collect();
+    hashes.sort_unstable();
+    hashes.dedup();
+
+    hashes
+}
+
+/// The stored hashes of a kind for a site's submissions.
+async fn stored_kind_hashes(
+    db: &Pool,
+    site: Site,
+    kind: HashKind,
+    submissions: &[SearchResult],
+) -> Result<Vec<i64>, Error> {
+    let hashes = stored_hashes(submissions);
+    if kind == HashKind::Gradient || hashes.is_empty() {
+        return Ok(hashes);
+    }
+
+    Ok(crate::models::submission_kind_hashes(db, site, kind, &hashes).await?)
+}
+
+/// Look up FurAffinity submissions by file ID, filename, URL, or site ID.
+async fn furaffinity_file(opts: &FileSearchOpts, db: &Pool) -> Result<Vec<SearchResult>, Error> {
+    use sqlx::Row;
+
+    let query = if let Some(ref id) = opts.id {
+        sqlx::query(
+            "SELECT
+                    submission.id,
+                    submission.url,
+                    submission.filename,
+                    submission.file_id,
+                    submission.rating,
+                    submission.posted_at,
+                    submission.hash_int,
+                    submission.file_sha256,
+                    submission.deleted,
+                    artist.name,
+                    array(SELECT tag.name FROM tag_to_post JOIN tag ON tag_to_post.tag_id = tag.id WHERE tag_to_post.post_id = submission.id) tags
                 FROM
                     submission
                 JOIN artist
                     ON artist.id = submission.artist_id
                 WHERE
                     file_id = $1
+                    AND (NOT submission.deleted OR $2)
                 LIMIT 10",
         )
         .bind(id)
+        .bind(opts.include_deleted)
     } else if let Some(ref name) = opts.name {
         sqlx::query(
             "SELECT
@@ -295,6 +1109,7 @@ pub async fn search_file(
                     submission.posted_at,
                     submission.hash_int,
                     submission.file_sha256,
+                    submission.deleted,
                     artist.name,
                     array(SELECT tag.name FROM tag_to_post JOIN tag ON tag_to_post.tag_id = tag.id WHERE tag_to_post.post_id = submission.id) tags
                 FROM
@@ -303,9 +1118,11 @@ pub async fn search_file(
                     ON artist.id = submission.artist_id
                 WHERE
                     lower(filename) = lower($1)
+                    AND (NOT submission.deleted OR $2)
                 LIMIT 10",
         )
         .bind(name)
+        .bind(opts.include_deleted)
     } else if let Some(ref url) = opts.url {
         sqlx::query(
             "SELECT
@@ -317,6 +1134,7 @@ pub async fn search_file(
                     submission.posted_at,
                     submission.hash_int,
                     submission.file_sha256,
+                    submission.deleted,
                     artist.name,
                     array(SELECT tag.name FROM tag_to_post JOIN tag ON tag_to_post.tag_id = tag.id WHERE tag_to_post.post_id = submission.id) tags
                 FROM
@@ -325,9 +1143,11 @@ pub async fn search_file(
                     ON artist.id = submission.artist_id
                 WHERE
                     lower(url) = lower($1)
+                    AND (NOT submission.deleted OR $2)
                 LIMIT 10",
         )
         .bind(url)
+        .bind(opts.include_deleted)
     } else if let Some(ref site_id) = opts.site_id {
         sqlx::query(
             "SELECT
@@ -339,6 +1159,7 @@ pub async fn search_file(
                     submission.posted_at,
                     submission.hash_int,
                     submission.file_sha256,
+                    submission.deleted,
                     artist.name,
                     array(SELECT tag.name FROM tag_to_post JOIN tag ON tag_to_post.tag_id = tag.id WHERE tag_to_post.post_id = submission.id) tags
                 FROM
@@ -347,14 +1168,16 @@ pub async fn search_file(
                     ON artist.id = submission.artist_id
                 WHERE
                     submission.id = $1
+                    AND (NOT submission.deleted OR $2)
                 LIMIT 10",
         )
         .bind(site_id)
+        .bind(opts.include_deleted)
     } else {
-        return Ok(Box::new(Error::InvalidData));
+        return Err(Error::InvalidData);
     };
 
-    let matches: Result<Vec<SearchResult>, _> = query
+    let matches = query
         .map(|row| SearchResult {
             site_id: row.get::<i32, _>("id") as i64,
             site_id_str: row.get::<i32, _>("id").to_string(),
@@ -365,12 +1188,15 @@ pub async fn search_file(
                 .get::<Option<String>, _>("name")
                 .map(|artist| vec![artist]),
             tags: row.get("tags"),
+            tag_categories: None,
             sha256: row
                 .get::<Option<Vec<u8>>, _>("file_sha256")
                 .map(hex::encode),
+            deleted: row.get("deleted"),
             distance: None,
             hash: row.get::<Option<i64>, _>("hash_int"),
             searched_hash: None,
+            frame: None,
             site_info: Some(SiteInfo::FurAffinity {
                 file_id: row.get("file_id"),
             }),
@@ -378,109 +1204,662 @@ pub async fn search_file(
                 .get::<Option<String>, _>("rating")
                 .and_then(|rating| rating.parse().ok()),
         })
-        .fetch_all(&db)
-        .await;
+        .fetch_all(db)
+        .await?;
 
-    let matches = early_return!(matches);
+    Ok(matches)
+}
 
-    let resp = warp::http::Response::builder()
-        .header("x-rate-limit-total-file", file_remaining.1.to_string())
-        .header("x-rate-limit-remaining-file", file_remaining.0.to_string())
-        .header("content-type", "application/json")
-        .body(serde_json::to_string(&matches).unwrap())
-        .unwrap();
+/// Look up submissions from sites other than FurAffinity.
+///
+/// e621 files may be found by MD5, either directly or from the filename or
+/// URL, Weasyl and Twitter files by their media URL, and any site by its ID.
+async fn site_file(
+    site: Site,
+    opts: &FileSearchOpts,
+    db: &Pool,
+) -> Result<Vec<SearchResult>, Error> {
+    use crate::models::{deleted_condition, search_result_from_row, site_lookup_query};
+
+    let lookup = |condition: &str| {
+        site_lookup_query(
+            site,
+            &format!("{} AND {}", condition, deleted_condition(site, 2)),
+        )
+    };
 
-    Ok(Box::new(resp))
+    let query_str;
+
+    let query = match site {
+        Site::E621 => {
+            let md5 = opts
+                .md5
+                .as_deref()
+                .or_else(|| opts.name.as_deref().and_then(e621_md5))
+                .or_else(|| opts.url.as_deref().and_then(e621_md5));
+
+            if let Some(md5) = md5 {
+                query_str = lookup("e621.data->'file'->>'md5' = $1");
+                sqlx::query(&query_str).bind(md5.to_lowercase())
+            } else if let Some(site_id) = opts.site_id {
+                query_str = lookup("e621.id = $1");
+                sqlx::query(&query_str).bind(site_id)
+            } else {
+                return Err(Error::InvalidData);
+            }
+        }
+        Site::Weasyl => {
+            if let Some(ref url) = opts.url {
+                query_str = lookup("weasyl.data->'media'->'submission'->0->>'url' = $1");
+                sqlx::query(&query_str).bind(url)
+            } else if let Some(site_id) = opts.site_id {
+                query_str = lookup("weasyl.id = $1");
+                sqlx::query(&query_str).bind(site_id)
+            } else {
+                return Err(Error::InvalidData);
+            }
+        }
+        Site::Twitter => {
+            if let Some(ref url) = opts.url {
+                query_str = lookup("tweet_media.url = $1");
+                sqlx::query(&query_str).bind(url)
+            } else if let Some(site_id) = opts.site_id {
+                query_str = lookup("tweet.id = $1");
+                sqlx::query(&query_str).bind(site_id)
+            } else {
+                return Err(Error::InvalidData);
+            }
+        }
+        Site::FurAffinity => return Err(Error::InvalidData),
+    };
+
+    let matches = query
+        .bind(opts.include_deleted)
+        .map(search_result_from_row)
+        .fetch_all(db)
+        .await?;
+
+    Ok(matches)
 }
 
+/// Extract the MD5 from an e621 filename or file URL, which are formatted like
+/// `https://static1.e621.net/data/ab/cd/<md5>.<ext>`.
+fn e621_md5(name: &str) -> Option<&str> {
+    let filename = name.rsplit('/').next()?;
+    let md5 = filename.split('.').next()?;
+
+    if md5.len() == 32 && md5.chars().all(|c| c.is_ascii_hexdigit()) {
+        Some(md5)
+    } else {
+        None
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/handle",
+    tag = "search",
+    params(HandleOpts),
+    responses(
+        (
+            status = 200,
+            description = "What has been indexed for the handle, or only if it is known when using the twitter parameter",
+            body = HandleInfo,
+        ),
+        (status = 400, description = "Missing site or handle", body = ErrorMessage),
+    ),
+)]
 pub async fn check_handle(opts: HandleOpts, db: Pool) -> Result<Box<dyn Reply>, Rejection> {
-    let exists = if let Some(handle) = opts.twitter {
+    if let Some(handle) = opts.twitter {
         let result = sqlx::query_scalar!("SELECT exists(SELECT 1 FROM twitter_user WHERE lower(data->>'screen_name') = lower($1))", handle)
             .fetch_optional(&db)
             .await
             .map(|row| row.flatten().unwrap_or(false));
 
-        early_return!(result)
-    } else {
-        false
+        let exists = early_return!(result);
+
+        return Ok(Box::new(warp::reply::json(&exists)));
+    }
+
+    let (site, handle) = match (opts.site, opts.handle) {
+        (Some(site), Some(handle)) if !handle.trim().is_empty() => (site, handle),
+        _ => return Ok(Box::new(Error::InvalidData)),
     };
 
-    Ok(Box::new(warp::reply::json(&exists)))
+    let info = early_return!(crate::models::handle_info(&db, site, handle.trim()).await);
+
+    Ok(Box::new(warp::reply::json(&info)))
 }
 
-pub async fn search_image_by_url(
-    opts: UrlSearchOpts,
+#[utoipa::path(
+    get,
+    path = "/artist",
+    tag = "artist",
+    params(ArtistOpts, IdPageOpts),
+    responses(
+        (
+            status = 200,
+            description = "The artist's submissions, from the most recently indexed",
+            body = [SearchResult],
+            headers(
+                ("x-has-more" = bool, description = "If there are more results"),
+                ("x-next-cursor" = String, description = "The cursor for the next page"),
+            ),
+        ),
+        (status = 400, description = "Missing site", body = ErrorMessage),
+        (status = 401, description = "Invalid API key", body = ErrorMessage),
+        (status = 429, description = "Rate limit exceeded", body = ErrorMessage),
+    ),
+    security(("api_key" = [])),
+)]
+pub async fn artist_submissions(
+    opts: ArtistOpts,
+    page: IdPageOpts,
+    db: Pool,
+    limiter: Limiter,
+    api_key: String,
+) -> Result<Box<dyn Reply>, Rejection> {
+    let file_remaining = rate_limit!(&api_key, &limiter, name_limit, "file");
+
+    let site = match opts.site {
+        Some(site) => site,
+        None => return Ok(Box::new(Error::InvalidData)),
+    };
+
+    let (results, next_cursor) = early_return!(
+        crate::models::artist_submissions(&db, site, &opts.name, opts.include_deleted, &page).await
+    );
+
+    let builder = rate_limit_headers(warp::http::Response::builder(), &[file_remaining], false);
+    let resp = page_headers(builder, next_cursor)
+        .header("content-type", "application/json")
+        .body(serde_json::to_string(&results).unwrap())
+        .unwrap();
+
+    Ok(Box::new(resp))
+}
+
+/// The number of an artist's most recent submissions searched for related
+/// artists, each counting towards the image rate limit.
+const MAX_RELATED_ARTIST_HASHES: u16 = 10;
+
+/// The largest distance between images for them to be considered the same.
+const RELATED_ARTIST_DISTANCE: i64 = 3;
+
+#[utoipa::path(
+    get,
+    path = "/artist/related",
+    tag = "artist",
+    params(ArtistOpts, HashKindOpts),
+    responses(
+        (
+            status = 200,
+            description = "Artists on other sites who uploaded the same images, with the most shared first",
+            body = [RelatedArtist],
+        ),
+        (status = 400, description = "Missing site", body = ErrorMessage),
+        (status = 401, description = "Invalid API key", body = ErrorMessage),
+        (status = 429, description = "Rate limit exceeded", body = ErrorMessage),
+    ),
+    security(("api_key" = [])),
+)]
+pub async fn related_artists(
+    opts: ArtistOpts,
+    kind: HashKindOpts,
     db: Pool,
+    limiter: Limiter,
     bkapi: bkapi_client::BKApiClient,
     api_key: String,
 ) -> Result<Box<dyn Reply>, Rejection> {
-    use bytes::BufMut;
+    use std::collections::{HashMap, HashSet};
+
+    let file_remaining = rate_limit!(&api_key, &limiter, name_limit, "file");
+
+    let site = match opts.site {
+        Some(site) => site,
+        None => return Ok(Box::new(Error::InvalidData)),
+    };
+
+    let page = IdPageOpts {
+        limit: Some(MAX_RELATED_ARTIST_HASHES),
+        cursor: None,
+    };
 
-    let url = opts.url;
+    let (submissions, _next_cursor) = early_return!(
+        crate::models::artist_submissions(&db, site, &opts.name, opts.include_deleted, &page).await
+    );
 
-    let image_remaining = rate_limit!(&api_key, &db, image_limit, "image");
-    let hash_remaining = rate_limit!(&api_key, &db, hash_limit, "hash");
+    let kind = kind.kind.unwrap_or_default();
+    let hashes = early_return!(stored_kind_hashes(&db, site, kind, &submissions).await);
 
-    let _timer = IMAGE_URL_DOWNLOAD_DURATION.start_timer();
+    let image_remaining = rate_limit!(
+        &api_key,
+        &limiter,
+        image_limit,
+        "image",
+        hashes.len().max(1) as i16
+    );
 
-    let mut resp = match reqwest::get(&url).await {
-        Ok(resp) => resp,
-        Err(_err) => return Ok(Box::new(Error::InvalidImage)),
+    let filter = SearchFilterOpts {
+        include_deleted: opts.include_deleted,
+        ..Default::default()
     };
 
-    let content_length = resp
-        .headers()
-        .get("content-length")
-        .and_then(|len| {
-            String::from_utf8_lossy(len.as_bytes())
-                .parse::<usize>()
-                .ok()
+    let matches = if hashes.is_empty() {
+        Vec::new()
+    } else {
+        early_return!(
+            image_query(
+                db,
+                bkapi,
+                hashes,
+                RELATED_ARTIST_DISTANCE,
+                kind,
+                &filter,
+                None
+            )
+            .await
+        )
+    };
+
+    let mut related: HashMap<(Site, String), (HashSet<i64>, u64)> = HashMap::new();
+
+    for result in matches {
+        let result_site = match result.site_info.as_ref() {
+            Some(site_info) if site_info.site() != site => site_info.site(),
+            _ => continue,
+        };
+
+        for artist in result.artists.iter().flatten() {
+            let (shared, distance) = related
+                .entry((result_site, artist.to_owned()))
+                .or_insert_with(|| (HashSet::new(), u64::MAX));
+
+            shared.extend(result.searched_hash);
+            *distance = (*distance).min(result.distance.unwrap_or_default());
+        }
+    }
+
+    let mut related: Vec<RelatedArtist> = related
+        .into_iter()
+        .map(|((site, name), (shared, distance))| RelatedArtist {
+            site,
+            name,
+            shared: shared.len(),
+            distance,
         })
-        .unwrap_or(0);
+        .collect();
 
-    if content_length > 10_000_000 {
-        return Ok(Box::new(Error::InvalidImage));
+    related.sort_by(|a, b| {
+        b.shared
+            .cmp(&a.shared)
+            .then(a.distance.cmp(&b.distance))
+            .then_with(|| a.name.cmp(&b.name))
+    });
+
+    let builder = rate_limit_headers(
+        warp::http::Response::builder(),
+        &[file_remaining, image_remaining],
+        false,
+    );
+    let resp = builder
+        .header("content-type", "application/json")
+        .body(serde_json::to_string(&related).unwrap())
+        .unwrap();
+
+    Ok(Box::new(resp))
+}
+
+#[utoipa::path(
+    get,
+    path = "/duplicates",
+    tag = "search",
+    params(DuplicateOpts),
+    responses(
+        (status = 200, description = "Every other copy of the submission", body = DuplicateCluster),
+        (status = 400, description = "Missing site", body = ErrorMessage),
+        (status = 401, description = "Invalid API key", body = ErrorMessage),
+        (status = 404, description = "Unknown submission", body = ErrorMessage),
+        (status = 429, description = "Rate limit exceeded", body = ErrorMessage),
+    ),
+    security(("api_key" = [])),
+)]
+pub async fn search_duplicates(
+    opts: DuplicateOpts,
+    db: Pool,
+    limiter: Limiter,
+    bkapi: bkapi_client::BKApiClient,
+    api_key: String,
+) -> Result<Box<dyn Reply>, Rejection> {
+    use std::collections::HashMap;
+
+    let file_remaining = rate_limit!(&api_key, &limiter, name_limit, "file");
+
+    let site = match opts.site {
+        Some(site) => site,
+        None => return Ok(Box::new(Error::InvalidData)),
+    };
+
+    let lookup = FileSearchOpts {
+        site: Some(site),
+        site_id: Some(opts.site_id),
+        include_deleted: true,
+        ..Default::default()
+    };
+
+    let submissions = early_return!(lookup_file(&lookup, &db).await);
+    if submissions.is_empty() {
+        return Ok(Box::new(Error::NotFound));
     }
 
-    let mut buf = bytes::BytesMut::with_capacity(content_length);
+    let kind = opts.kind.unwrap_or_default();
+    let hashes = early_return!(stored_kind_hashes(&db, site, kind, &submissions).await);
+
+    let image_remaining = rate_limit!(
+        &api_key,
+        &limiter,
+        image_limit,
+        "image",
+        hashes.len().max(1) as i16
+    );
+
+    let distance = opts
+        .distance
+        .unwrap_or(DEFAULT_DUPLICATE_DISTANCE)
+        .clamp(0, MAX_DUPLICATE_DISTANCE);
+
+    let filter = SearchFilterOpts {
+        include_deleted: opts.include_deleted,
+        ..Default::default()
+    };
+
+    let results = if hashes.is_empty() {
+        Vec::new()
+    } else {
+        early_return!(image_query(db, bkapi, hashes.clone(), distance, kind, &filter, None).await)
+    };
+
+    // Each copy may have matched more than one of the submission's hashes.
+    let mut copies: HashMap<(Site, i64), SearchResult> = HashMap::new();
+    for result in results {
+        let result_site = match result.site_info.as_ref() {
+            Some(site_info) => site_info.site(),
+            None => continue,
+        };
 
-    while let Some(chunk) = early_return!(resp.chunk().await) {
-        if buf.len() + chunk.len() > 10_000_000 {
-            return Ok(Box::new(Error::InvalidImage));
+        if result_site == site && result.site_id == opts.site_id {
+            continue;
         }
 
-        buf.put(chunk);
+        let existing = copies
+            .entry((result_site, result.site_id))
+            .or_insert_with(|| result.clone());
+        if result.distance < existing.distance {
+            *existing = result;
+        }
     }
 
-    drop(_timer);
+    let mut matches: Vec<SearchResult> = copies.into_values().collect();
+    matches.sort_by(|a, b| match (a.posted_at, b.posted_at) {
+        (Some(a), Some(b)) => a.cmp(&b),
+        (Some(_), None) => std::cmp::Ordering::Less,
+        (None, Some(_)) => std::cmp::Ordering::Greater,
+        (None, None) => a.site_id.cmp(&b.site_id),
+    });
 
-    let _timer = IMAGE_HASH_DURATION.start_timer();
-    let hash = tokio::task::spawn_blocking(move || {
-        let hasher = fuzzysearch_common::get_hasher();
-        let image = image::load_from_memory(&buf).unwrap();
-        hasher.hash_image(&image)
-    })
-    .instrument(span!(tracing::Level::TRACE, "hashing image"))
-    .await
-    .unwrap();
-    drop(_timer);
+    let posted_at = submissions
+        .iter()
+        .filter_map(|submission| submission.posted_at)
+        .min();
+    let earliest_copy = matches.first().and_then(|result| result.posted_at);
 
-    let hash: [u8; 8] = hash.as_bytes().try_into().unwrap();
-    let num = i64::from_be_bytes(hash);
+    let is_original = match (posted_at, earliest_copy) {
+        (Some(posted_at), Some(earliest_copy)) => posted_at <= earliest_copy,
+        (Some(_), None) => true,
+        (None, _) => false,
+    };
 
-    let results = image_query(db.clone(), bkapi.clone(), vec![num], 3)
-        .await
+    let matches = matches
+        .into_iter()
+        .enumerate()
+        .map(|(index, result)| DuplicateMatch {
+            likely_original: index == 0 && !is_original && result.posted_at.is_some(),
+            result,
+        })
+        .collect();
+
+    let cluster = DuplicateCluster {
+        hashes,
+        is_original,
+        matches,
+    };
+
+    let builder = rate_limit_headers(
+        warp::http::Response::builder(),
+        &[file_remaining, image_remaining],
+        false,
+    );
+    let resp = builder
+        .header("content-type", "application/json")
+        .body(serde_json::to_string(&cluster).unwrap())
         .unwrap();
 
-    let resp = warp::http::Response::builder()
-        .header("x-image-hash", num.to_string())
-        .header("x-rate-limit-total-image", image_remaining.1.to_string())
-        .header(
-            "x-rate-limit-remaining-image",
-            image_remaining.0.to_string(),
+    Ok(Box::new(resp))
+}
+
+#[utoipa::path(
+    get,
+    path = "/subscribe",
+    tag = "search",
+    params(SubscribeOpts),
+    responses(
+        (
+            status = 200,
+            description = "A stream of server-sent events, with a match event containing a result each time a similar image is added and a lagged event if some were missed",
+            content_type = "text/event-stream",
+            body = SearchResult,
+        ),
+        (status = 400, description = "Invalid hashes", body = ErrorMessage),
+        (status = 401, description = "Invalid API key", body = ErrorMessage),
+        (
+            status = 429,
+            description = "Rate limit exceeded or too many open subscriptions",
+            body = ErrorMessage,
+        ),
+    ),
+    security(("api_key" = [])),
+)]
+pub async fn subscribe_hashes(
+    opts: SubscribeOpts,
+    hash_added: crate::subscriptions::HashAddedSender,
+    limiter: Limiter,
+    api_key: String,
+) -> Result<Box<dyn Reply>, Rejection> {
+    use tokio::sync::broadcast::error::RecvError;
+
+    let hashes: Vec<i64> = opts
+        .hashes
+        .split(',')
+        .filter_map(|hash| hash.trim().parse().ok())
+        .collect();
+
+    if hashes.is_empty() || hashes.len() > MAX_SUBSCRIPTION_HASHES {
+        return Ok(Box::new(Error::InvalidData));
+    }
+
+    let distance = opts.distance.unwrap_or(3).min(MAX_SUBSCRIPTION_DISTANCE);
+
+    rate_limit!(&api_key, &limiter, hash_limit, "hash");
+
+    let rx = match hash_added.subscribe(&api_key, MAX_SUBSCRIPTIONS_PER_KEY) {
+        Some(rx) => rx,
+        None => return Ok(Box::new(Error::TooManySubscriptions)),
+    };
+
+    tracing::info!(hashes = hashes.len(), distance, "starting subscription");
+
+    let events = futures::stream::unfold(rx, move |mut rx| {
+        let hashes = hashes.clone();
+
+        async move {
+            loop {
+                let added = match rx.recv().await {
+                    Ok(added) => added,
+                    Err(RecvError::Lagged(missed)) => {
+                        let event = warp::sse::Event::default()
+                            .event("lagged")
+                            .data(missed.to_string());
+                        return Some((vec![event], rx));
+                    }
+                    Err(RecvError::Closed) => return None,
+                };
+
+                let closest = hashes
+                    .iter()
+                    .map(|hash| (*hash, (hash ^ added.hash).count_ones()))
+                    .filter(|(_hash, hash_distance)| *hash_distance <= distance)
+                    .min_by_key(|(_hash, hash_distance)| *hash_distance);
+
+                let (searched_hash, hash_distance) = match closest {
+                    Some(closest) => closest,
+                    None => continue,
+                };
+
+                let events: Vec<_> = added
+                    .results
+                    .iter()
+                    .filter_map(|result| {
+                        let result = SearchResult {
+                            searched_hash: Some(searched_hash),
+                            distance: Some(hash_distance as u64),
+                            ..result.clone()
+                        };
+
+                        warp::sse::Event::default()
+                            .event("match")
+                            .json_data(&result)
+                            .ok()
+                    })
+                    .collect();
+
+                if !events.is_empty() {
+                    return Some((events, rx));
+                }
+            }
+        }
+    })
+    .flat_map(futures::stream::iter)
+    .map(Ok::<_, std::convert::Infallible>);
+
+    Ok(Box::new(warp::sse::reply(
+        warp::sse::keep_alive().stream(events),
+    )))
+}
+
+/// Find the stored hashes of a submission from a URL to a post or file on a
+/// site that is indexed, so it doesn't need to be downloaded.
+async fn known_url_hashes(url: &str, db: &Pool) -> Result<Vec<i64>, Error> {
+    let opts = match crate::sites::known_url_opts(url) {
+        Some(opts) => FileSearchOpts {
+            include_deleted: true,
+            ..opts
+        },
+        None => return Ok(Vec::new()),
+    };
+
+    let hashes = stored_hashes(&lookup_file(&opts, db).await?);
+
+    tracing::debug!(?hashes, "found hashes for known url");
+
+    Ok(hashes)
+}
+
+#[utoipa::path(
+    get,
+    path = "/url",
+    tag = "search",
+    params(UrlSearchOpts, SearchFilterOpts, SearchPageOpts),
+    responses(
+        (
+            status = 200,
+            description = "Submissions similar to the image at the URL",
+            body = [SearchResult],
+            headers(
+                ("x-image-hash" = i64, description = "The hash of the image"),
+                ("x-image-hashes" = String, description = "Every hash searched, separated by commas"),
+                ("x-has-more" = bool, description = "If there are more results"),
+                ("x-next-cursor" = String, description = "The cursor for the next page"),
+            ),
+        ),
+        (status = 400, description = "Invalid URL or image", body = ErrorMessage),
+        (status = 413, description = "Image too large", body = ErrorMessage),
+        (status = 401, description = "Invalid API key", body = ErrorMessage),
+        (status = 429, description = "Rate limit exceeded", body = ErrorMessage),
+        (status = 502, description = "Could not download image", body = ErrorMessage),
+        (status = 504, description = "Timed out downloading image", body = ErrorMessage),
+    ),
+    security(("api_key" = [])),
+)]
+#[allow(clippy::too_many_arguments)]
+pub async fn search_image_by_url(
+    opts: UrlSearchOpts,
+    filter: SearchFilterOpts,
+    page: SearchPageOpts,
+    db: Pool,
+    limiter: Limiter,
+    bkapi: bkapi_client::BKApiClient,
+    api_key: String,
+    endpoints: Endpoints,
+) -> Result<Box<dyn Reply>, Rejection> {
+    let image_remaining = rate_limit!(&api_key, &limiter, image_limit, "image");
+    let hash_remaining = rate_limit!(&api_key, &limiter, hash_limit, "hash");
+
+    let mut hashes = early_return!(known_url_hashes(&opts.url, &db).await);
+
+    if hashes.is_empty() {
+        let _timer = IMAGE_URL_DOWNLOAD_DURATION.start_timer();
+        let buf = early_return!(crate::fetch::fetch_image(&opts.url).await);
+        drop(_timer);
+
+        let _timer = IMAGE_HASH_DURATION.start_timer();
+        hashes.push(early_return!(
+            hash_bytes(&endpoints, buf, HashKind::Gradient).await
+        ));
+        drop(_timer);
+    }
+
+    // Known URLs may have several images, but the image hash header has
+    // always been a single hash.
+    let image_hash = hashes[0].to_string();
+    let image_hashes = hashes
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(",");
+
+    let mut results = early_return!(
+        image_query(
+            db.clone(),
+            bkapi.clone(),
+            hashes,
+            3,
+            HashKind::Gradient,
+            &filter,
+            Some(&page),
         )
-        .header("x-rate-limit-total-hash", hash_remaining.1.to_string())
-        .header("x-rate-limit-remaining-hash", hash_remaining.0.to_string())
+        .await
+    );
+
+    let next_cursor = page.next_cursor(&mut results);
+
+    let builder = rate_limit_headers(
+        warp::http::Response::builder(),
+        &[image_remaining, hash_remaining],
+        false,
+    );
+    let resp = page_headers(builder, next_cursor)
+        .header("x-image-hash", image_hash)
+        .header("x-image-hashes", image_hashes)
         .header("content-type", "application/json")
         .body(serde_json::to_string(&results).unwrap())
         .unwrap();
@@ -488,6 +1867,957 @@ pub async fn search_image_by_url(
     Ok(Box::new(resp))
 }
 
+/// Get the requests made by an API key in each rate limit group.
+///
+/// Usage by minute or hour is kept for 7 days, after which it is only
+/// available by day. The returned start time is the requested one moved
+/// forward to the oldest available usage.
+#[utoipa::path(
+    get,
+    path = "/usage",
+    tag = "usage",
+    params(UsageOpts),
+    responses(
+        (status = 200, description = "Requests made by the API key", body = KeyUsage),
+        (status = 401, description = "Invalid API key", body = ErrorMessage),
+    ),
+    security(("api_key" = [])),
+)]
+pub async fn api_key_usage(
+    opts: UsageOpts,
+    db: Pool,
+    limiter: Limiter,
+    api_key: String,
+) -> Result<Box<dyn Reply>, Rejection> {
+    let api_key = match limiter.lookup_api_key(&api_key).await {
+        Some(api_key) => api_key,
+        None => return Ok(Box::new(Error::ApiKey)),
+    };
+
+    let now = chrono::Utc::now();
+    let bucket = opts.bucket.unwrap_or(UsageBucket::Hour);
+    let mut since = opts.since.unwrap_or_else(|| now - bucket.default_range());
+    if let Some(retention) = bucket.retention() {
+        since = since.max(now - retention);
+    }
+
+    let usage = early_return!(crate::models::api_key_usage(&db, api_key.id, bucket, since).await);
+
+    let mut remaining = Vec::with_capacity(RATE_LIMIT_GROUPS.len());
+    for group in RATE_LIMIT_GROUPS {
+        let limit = match api_key.group_limit(group) {
+            Some(limit) => limit,
+            None => continue,
+        };
+
+        let (group_remaining, resets_at) =
+            early_return!(limiter.remaining(&api_key, limit, group).await);
+
+        remaining.push(GroupRemaining {
+            group,
+            limit,
+            remaining: group_remaining,
+            resets_at,
+        });
+    }
+
+    let usage = KeyUsage {
+        bucket,
+        since,
+        usage,
+        remaining,
+    };
+
+    Ok(Box::new(warp::reply::json(&usage)))
+}
+
+/// The maximum number of active API keys an account may have.
+const MAX_API_KEYS: i64 = 10;
+
+/// The most hashes an account may watch.
+const MAX_HASH_WATCHES: i64 = 1000;
+
+/// The distance watched hashes are matched within unless otherwise set, and
+/// the largest distance that may be set.
+const DEFAULT_HASH_WATCH_DISTANCE: i16 = 3;
+const MAX_HASH_WATCH_DISTANCE: i16 = 10;
+
+/// The most webhooks an account may have.
+const MAX_WEBHOOKS: i64 = 10;
+
+/// The longest endpoint a webhook may have.
+const MAX_WEBHOOK_ENDPOINT_LENGTH: usize = 2048;
+
+/// The most artists a webhook may filter on.
+const MAX_WEBHOOK_ARTISTS: usize = 100;
+
+/// The shortest and longest passwords that may be used for an account.
+const PASSWORD_LENGTH: std::ops::RangeInclusive<usize> = 8..=1024;
+
+/// The longest name that may be given to an API key.
+const MAX_API_KEY_NAME_LENGTH: usize = 100;
+
+/// How many accounts may be registered from an IP address, and for an email
+/// address, each hour.
+const REGISTRATIONS_PER_ADDRESS: i16 = 10;
+const REGISTRATIONS_PER_EMAIL: i16 = 3;
+
+/// Credentials given to authenticate an account, along with the client that
+/// gave them.
+pub struct Credentials {
+    /// The contents of the Authorization header.
+    pub authorization: Option<String>,
+    /// The IP address of the client.
+    pub client: Option<std::net::IpAddr>,
+}
+
+/// How many times the wrong password may be given from an IP address, and
+/// for an email address from an IP address, each hour before signing in is
+/// refused.
+const FAILED_LOGINS_PER_ADDRESS: i16 = 50;
+const FAILED_LOGINS_PER_EMAIL: i16 = 10;
+
+/// Authenticate an account from HTTP basic authorization credentials, which
+/// contain the account's email address and password.
+async fn authenticate(db: &Pool, credentials: Credentials) -> Result<Account, Error> {
+    let decoded = credentials
+        .authorization
+        .as_deref()
+        .and_then(|authorization| authorization.strip_prefix("Basic "))
+        .and_then(|authorization| base64::decode(authorization.trim()).ok())
+        .and_then(|authorization| String::from_utf8(authorization).ok())
+        .ok_or(Error::Unauthorized)?;
+
+    let (email, password) = decoded.split_once(':').ok_or(Error::Unauthorized)?;
+
+    // Failed attempts are counted for each client, and for each email address
+    // from that client, so passwords cannot be guessed without other clients
+    // being able to lock an account. Both are checked before spending time on
+    // verifying the password.
+    let client = credentials
+        .client
+        .map(|client| client.to_string())
+        .unwrap_or_default();
+    let attempt_keys = [
+        (format!("login:ip:{}", client), FAILED_LOGINS_PER_ADDRESS),
+        (
+            format!("login:{}:{}", client, email.trim().to_lowercase()),
+            FAILED_LOGINS_PER_EMAIL,
+        ),
+    ];
+
+    for (key, limit) in &attempt_keys {
+        if let RateLimit::Limited(status) =
+            crate::utils::check_account_attempts(db, key, *limit).await?
+        {
+            return Err(Error::RateLimit(status));
+        }
+    }
+
+    let account = match crate::models::lookup_account(email, db).await? {
+        Some(account) => account,
+        None => {
+            record_failed_login(db, &attempt_keys).await?;
+            return Err(Error::Unauthorized);
+        }
+    };
+
+    let password = password.to_string();
+    let hash = account.password.clone();
+    let valid =
+        tokio::task::spawn_blocking(move || crate::utils::verify_password(&password, &hash))
+            .await
+            .unwrap_or(false);
+
+    if !valid {
+        record_failed_login(db, &attempt_keys).await?;
+        return Err(Error::Unauthorized);
+    }
+
+    Ok(account)
+}
+
+/// Count a failed login against each of its attempt keys.
+async fn record_failed_login(db: &Pool, attempt_keys: &[(String, i16)]) -> Result<(), Error> {
+    for (key, limit) in attempt_keys {
+        crate::utils::update_account_attempts(db, key, *limit).await?;
+    }
+
+    Ok(())
+}
+
+/// Authenticate an account that has verified its email address.
+async fn authenticate_verified(db: &Pool, credentials: Credentials) -> Result<Account, Error> {
+    let account = authenticate(db, credentials).await?;
+
+    if account.email_verifier.is_some() {
+        return Err(Error::Unverified);
+    }
+
+    Ok(account)
+}
+
+/// Normalize an API key name, treating blank names as no name.
+fn api_key_name(name: Option<String>) -> Result<Option<String>, Error> {
+    let name = name
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty());
+
+    match name {
+        Some(name) if name.chars().count() > MAX_API_KEY_NAME_LENGTH => Err(Error::InvalidData),
+        name => Ok(name),
+    }
+}
+
+/// Check a webhook endpoint is an HTTP or HTTPS URL with a host that only
+/// resolves to publicly routable addresses.
+///
+/// Hosts may change their addresses, so they are checked again before each
+/// delivery.
+async fn webhook_endpoint(endpoint: &str) -> Result<String, Error> {
+    let endpoint = endpoint.trim();
+
+    if endpoint.len() > MAX_WEBHOOK_ENDPOINT_LENGTH {
+        return Err(Error::InvalidData);
+    }
+
+    let url = match reqwest::Url::parse(endpoint) {
+        Ok(url) if matches!(url.scheme(), "http" | "https") => url,
+        _ => return Err(Error::InvalidData),
+    };
+
+    // IPv6 addresses are bracketed in URLs, but can't be resolved that way.
+    let host = url
+        .host_str()
+        .ok_or(Error::InvalidData)?
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+    let port = url.port_or_known_default().ok_or(Error::InvalidData)?;
+
+    let addrs: Vec<std::net::SocketAddr> = tokio::net::lookup_host((host, port))
+        .await
+        .map_err(|_err| Error::InvalidData)?
+        .collect();
+
+    if addrs.is_empty()
+        || !addrs
+            .iter()
+            .all(|addr| fuzzysearch_common::net::is_public(addr.ip()))
+    {
+        return Err(Error::InvalidData);
+    }
+
+    Ok(url.to_string())
+}
+
+/// Normalize webhook filters, treating empty lists as no filter and matching
+/// artists without case.
+fn webhook_filters(filters: WebhookFilters) -> Result<WebhookFilters, Error> {
+    let sites = filters.sites.filter(|sites| !sites.is_empty());
+
+    let artists = filters.artists.map(|artists| {
+        let mut artists: Vec<String> = artists
+            .iter()
+            .map(|artist| artist.trim().to_lowercase())
+            .filter(|artist| !artist.is_empty())
+            .collect();
+        artists.sort();
+        artists.dedup();
+        artists
+    });
+
+    let artists = match artists {
+        Some(artists) if artists.len() > MAX_WEBHOOK_ARTISTS => return Err(Error::InvalidData),
+        artists => artists.filter(|artists| !artists.is_empty()),
+    };
+
+    Ok(WebhookFilters {
+        sites,
+        artists,
+        ..filters
+    })
+}
+
+/// Register an account and send a code to verify its email address.
+///
+/// The response is the same whether or not the email address already has an
+/// account, so it cannot be used to find out who has registered.
+#[utoipa::path(
+    post,
+    path = "/account",
+    tag = "account",
+    request_body = RegisterAccount,
+    responses(
+        (status = 202, description = "The account was registered, or already exists, and a verification code may have been sent"),
+        (status = 400, description = "Invalid email address or password", body = ErrorMessage),
+        (status = 429, description = "Too many registrations from the address or for the email address", body = ErrorMessage),
+        (status = 503, description = "Registration is unavailable because email is not configured", body = ErrorMessage),
+    ),
+)]
+pub async fn register_account(
+    body: RegisterAccount,
+    client: Option<std::net::IpAddr>,
+    db: Pool,
+    mailer: Option<crate::utils::Mailer>,
+) -> Result<Box<dyn Reply>, Rejection> {
+    let mailer = match mailer {
+        Some(mailer) => mailer,
+        None => return Ok(Box::new(Error::Unavailable)),
+    };
+
+    let email = body.email.trim().to_lowercase();
+    let address: lettre::Address = match email.parse() {
+        Ok(address) => address,
+        Err(_err) => return Ok(Box::new(Error::InvalidData)),
+    };
+
+    if !PASSWORD_LENGTH.contains(&body.password.chars().count()) {
+        return Ok(Box::new(Error::InvalidData));
+    }
+
+    let attempts = client
+        .map(|client| (format!("register:ip:{}", client), REGISTRATIONS_PER_ADDRESS))
+        .into_iter()
+        .chain(std::iter::once((
+            format!("register:email:{}", email),
+            REGISTRATIONS_PER_EMAIL,
+        )));
+
+    for (key, limit) in attempts {
+        if let RateLimit::Limited(status) =
+            early_return!(crate::utils::update_account_attempts(&db, &key, limit).await)
+        {
+            return Ok(Box::new(Error::RateLimit(status)));
+        }
+    }
+
+    let password = body.password;
+    let hash =
+        match tokio::task::spawn_blocking(move || crate::utils::hash_password(&password)).await {
+            Ok(Ok(hash)) => hash,
+            Ok(Err(err)) => {
+                tracing::error!("could not hash password: {}", err);
+                return Ok(Box::new(Error::Internal));
+            }
+            Err(err) => {
+                tracing::error!("could not join password hashing task: {}", err);
+                return Ok(Box::new(Error::Internal));
+            }
+        };
+
+    let email_verifier = crate::utils::generate_token();
+
+    let created =
+        early_return!(crate::models::create_account(&db, &email, &hash, &email_verifier).await);
+
+    // The email is sent in the background so the response takes as long for
+    // existing accounts. If it fails, registering again sends a new code.
+    if created.is_some() {
+        tokio::spawn(async move {
+            if let Err(err) = mailer.send_verification(address, &email_verifier).await {
+                tracing::error!("could not send verification email: {}", err);
+            }
+        });
+    }
+
+    Ok(Box::new(StatusCode::ACCEPTED))
+}
+
+#[utoipa::path(
+    post,
+    path = "/account/verify",
+    tag = "account",
+    request_body = VerifyAccount,
+    responses(
+        (status = 204, description = "The email address was verified"),
+        (status = 400, description = "Invalid email address or verification code", body = ErrorMessage),
+    ),
+)]
+pub async fn verify_account(body: VerifyAccount, db: Pool) -> Result<Box<dyn Reply>, Rejection> {
+    let verified =
+        early_return!(crate::models::verify_account(&db, body.email.trim(), &body.verifier).await);
+
+    if !verified {
+        return Ok(Box::new(Error::InvalidData));
+    }
+
+    Ok(Box::new(StatusCode::NO_CONTENT))
+}
+
+#[utoipa::path(
+    get,
+    path = "/account",
+    tag = "account",
+    responses(
+        (status = 200, description = "The account", body = AccountInfo),
+        (status = 401, description = "Invalid account credentials", body = ErrorMessage),
+        (status = 429, description = "Too many failed logins", body = ErrorMessage),
+    ),
+    security(("basic_auth" = [])),
+)]
+pub async fn get_account(credentials: Credentials, db: Pool) -> Result<Box<dyn Reply>, Rejection> {
+    let account = early_return!(authenticate(&db, credentials).await);
+
+    Ok(Box::new(warp::reply::json(&AccountInfo::from(account))))
+}
+
+#[utoipa::path(
+    get,
+    path = "/account/keys",
+    tag = "account",
+    responses(
+        (status = 200, description = "Every API key owned by the account", body = [ApiKeyInfo]),
+        (status = 401, description = "Invalid account credentials", body = ErrorMessage),
+        (status = 429, description = "Too many failed logins", body = ErrorMessage),
+    ),
+    security(("basic_auth" = [])),
+)]
+pub async fn list_api_keys(
+    credentials: Credentials,
+    db: Pool,
+) -> Result<Box<dyn Reply>, Rejection> {
+    let account = early_return!(authenticate(&db, credentials).await);
+
+    let keys = early_return!(crate::models::list_api_keys(&db, account.id).await);
+
+    Ok(Box::new(warp::reply::json(&keys)))
+}
+
+#[utoipa::path(
+    post,
+    path = "/account/keys",
+    tag = "account",
+    request_body = ApiKeyName,
+    responses(
+        (status = 201, description = "The new API key, including the key", body = ApiKeyInfo),
+        (status = 400, description = "Invalid name", body = ErrorMessage),
+        (status = 401, description = "Invalid account credentials", body = ErrorMessage),
+        (status = 403, description = "Email address has not been verified", body = ErrorMessage),
+        (status = 409, description = "Maximum number of API keys reached", body = ErrorMessage),
+        (status = 429, description = "Too many failed logins", body = ErrorMessage),
+    ),
+    security(("basic_auth" = [])),
+)]
+pub async fn create_api_key(
+    credentials: Credentials,
+    body: ApiKeyName,
+    db: Pool,
+) -> Result<Box<dyn Reply>, Rejection> {
+    let account = early_return!(authenticate_verified(&db, credentials).await);
+    let name = early_return!(api_key_name(body.name));
+
+    let key = crate::utils::generate_token();
+
+    let key = match early_return!(
+        crate::models::create_api_key(&db, account.id, name.as_deref(), &key, MAX_API_KEYS).await
+    ) {
+        Some(key) => key,
+        None => return Ok(Box::new(Error::TooManyApiKeys)),
+    };
+
+    Ok(Box::new(warp::reply::with_status(
+        warp::reply::json(&key),
+        StatusCode::CREATED,
+    )))
+}
+
+#[utoipa::path(
+    post,
+    path = "/account/keys/{key_id}/name",
+    tag = "account",
+    params(("key_id" = i32, Path, description = "The ID of the API key")),
+    request_body = ApiKeyName,
+    responses(
+        (status = 200, description = "The renamed API key", body = ApiKeyInfo),
+        (status = 400, description = "Invalid name", body = ErrorMessage),
+        (status = 401, description = "Invalid account credentials", body = ErrorMessage),
+        (status = 404, description = "API key not found", body = ErrorMessage),
+        (status = 429, description = "Too many failed logins", body = ErrorMessage),
+    ),
+    security(("basic_auth" = [])),
+)]
+pub async fn rename_api_key(
+    key_id: i32,
+    credentials: Credentials,
+    body: ApiKeyName,
+    db: Pool,
+) -> Result<Box<dyn Reply>, Rejection> {
+    let account = early_return!(authenticate(&db, credentials).await);
+    let name = early_return!(api_key_name(body.name));
+
+    match early_return!(
+        crate::models::rename_api_key(&db, account.id, key_id, name.as_deref()).await
+    ) {
+        Some(key) => Ok(Box::new(warp::reply::json(&key))),
+        None => Ok(Box::new(Error::NotFound)),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/account/keys/{key_id}/rotate",
+    tag = "account",
+    params(("key_id" = i32, Path, description = "The ID of the API key")),
+    responses(
+        (status = 200, description = "The API key, including its new key", body = ApiKeyInfo),
+        (status = 401, description = "Invalid account credentials", body = ErrorMessage),
+        (status = 403, description = "Email address has not been verified", body = ErrorMessage),
+        (status = 404, description = "API key not found", body = ErrorMessage),
+        (status = 429, description = "Too many failed logins", body = ErrorMessage),
+    ),
+    security(("basic_auth" = [])),
+)]
+pub async fn rotate_api_key(
+    key_id: i32,
+    credentials: Credentials,
+    db: Pool,
+) -> Result<Box<dyn Reply>, Rejection> {
+    let account = early_return!(authenticate_verified(&db, credentials).await);
+
+    let key = crate::utils::generate_token();
+
+    match early_return!(crate::models::rotate_api_key(&db, account.id, key_id, &key).await) {
+        Some(key) => Ok(Box::new(warp::reply::json(&key))),
+        None => Ok(Box::new(Error::NotFound)),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/account/keys/{key_id}/revoke",
+    tag = "account",
+    params(("key_id" = i32, Path, description = "The ID of the API key")),
+    responses(
+        (status = 200, description = "The revoked API key", body = ApiKeyInfo),
+        (status = 401, description = "Invalid account credentials", body = ErrorMessage),
+        (status = 404, description = "API key not found", body = ErrorMessage),
+        (status = 429, description = "Too many failed logins", body = ErrorMessage),
+    ),
+    security(("basic_auth" = [])),
+)]
+pub async fn revoke_api_key(
+    key_id: i32,
+    credentials: Credentials,
+    db: Pool,
+) -> Result<Box<dyn Reply>, Rejection> {
+    let account = early_return!(authenticate(&db, credentials).await);
+
+    match early_return!(crate::models::revoke_api_key(&db, account.id, key_id).await) {
+        Some(key) => Ok(Box::new(warp::reply::json(&key))),
+        None => Ok(Box::new(Error::NotFound)),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/keys/{key_id}/limits",
+    tag = "account",
+    params(("key_id" = i32, Path, description = "The ID of the API key")),
+    request_body = ApiKeyLimits,
+    responses(
+        (status = 200, description = "The API key with its new limits", body = ApiKeyInfo),
+        (status = 400, description = "Negative limit", body = ErrorMessage),
+        (status = 401, description = "Invalid account credentials", body = ErrorMessage),
+        (status = 403, description = "The account is not an admin", body = ErrorMessage),
+        (status = 404, description = "API key not found", body = ErrorMessage),
+        (status = 429, description = "Too many failed logins", body = ErrorMessage),
+    ),
+    security(("basic_auth" = [])),
+)]
+pub async fn update_api_key_limits(
+    key_id: i32,
+    credentials: Credentials,
+    limits: ApiKeyLimits,
+    db: Pool,
+) -> Result<Box<dyn Reply>, Rejection> {
+    let account = early_return!(authenticate(&db, credentials).await);
+
+    if !account.is_admin {
+        return Ok(Box::new(Error::Forbidden));
+    }
+
+    let any_negative = [
+        limits.name_limit,
+        limits.image_limit,
+        limits.hash_limit,
+        limits.sha256_limit,
+    ]
+    .iter()
+    .flatten()
+    .any(|limit| *limit < 0);
+
+    if any_negative {
+        return Ok(Box::new(Error::InvalidData));
+    }
+
+    match early_return!(crate::models::update_api_key_limits(&db, key_id, &limits).await) {
+        Some(key) => Ok(Box::new(warp::reply::json(&key))),
+        None => Ok(Box::new(Error::NotFound)),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/account/watches",
+    tag = "account",
+    responses(
+        (status = 200, description = "Every hash watched by the account", body = [HashWatch]),
+        (status = 401, description = "Invalid account credentials", body = ErrorMessage),
+        (status = 429, description = "Too many failed logins", body = ErrorMessage),
+    ),
+    security(("basic_auth" = [])),
+)]
+pub async fn list_hash_watches(
+    credentials: Credentials,
+    db: Pool,
+) -> Result<Box<dyn Reply>, Rejection> {
+    let account = early_return!(authenticate(&db, credentials).await);
+
+    let watches = early_return!(crate::models::list_hash_watches(&db, account.id).await);
+
+    Ok(Box::new(warp::reply::json(&watches)))
+}
+
+#[utoipa::path(
+    post,
+    path = "/account/watches",
+    tag = "account",
+    request_body = NewHashWatch,
+    responses(
+        (status = 200, description = "The watched hash", body = HashWatch),
+        (status = 400, description = "Invalid distance", body = ErrorMessage),
+        (status = 401, description = "Invalid account credentials", body = ErrorMessage),
+        (status = 403, description = "Email address has not been verified", body = ErrorMessage),
+        (status = 409, description = "Maximum number of watched hashes reached", body = ErrorMessage),
+        (status = 429, description = "Too many failed logins", body = ErrorMessage),
+    ),
+    security(("basic_auth" = [])),
+)]
+pub async fn watch_hash(
+    credentials: Credentials,
+    body: NewHashWatch,
+    db: Pool,
+) -> Result<Box<dyn Reply>, Rejection> {
+    let account = early_return!(authenticate_verified(&db, credentials).await);
+
+    let distance = body.distance.unwrap_or(DEFAULT_HASH_WATCH_DISTANCE);
+    if !(0..=MAX_HASH_WATCH_DISTANCE).contains(&distance) {
+        return Ok(Box::new(Error::InvalidData));
+    }
+
+    match early_return!(
+        crate::models::watch_hash(&db, account.id, body.hash, distance, MAX_HASH_WATCHES).await
+    ) {
+        Some(watch) => Ok(Box::new(warp::reply::json(&watch))),
+        None => Ok(Box::new(Error::TooManyHashWatches)),
+    }
+}
+
+#[utoipa::path(
+    delete,
+    path = "/account/watches/{watch_id}",
+    tag = "account",
+    params(("watch_id" = i32, Path, description = "The ID of the watched hash")),
+    responses(
+        (status = 204, description = "The hash is no longer watched"),
+        (status = 401, description = "Invalid account credentials", body = ErrorMessage),
+        (status = 404, description = "Watched hash not found", body = ErrorMessage),
+        (status = 429, description = "Too many failed logins", body = ErrorMessage),
+    ),
+    security(("basic_auth" = [])),
+)]
+pub async fn unwatch_hash(
+    watch_id: i32,
+    credentials: Credentials,
+    db: Pool,
+) -> Result<Box<dyn Reply>, Rejection> {
+    let account = early_return!(authenticate(&db, credentials).await);
+
+    if !early_return!(crate::models::unwatch_hash(&db, account.id, watch_id).await) {
+        return Ok(Box::new(Error::NotFound));
+    }
+
+    Ok(Box::new(StatusCode::NO_CONTENT))
+}
+
+#[utoipa::path(
+    get,
+    path = "/account/webhooks",
+    tag = "account",
+    responses(
+        (status = 200, description = "Every webhook owned by the account", body = [WebhookInfo]),
+        (status = 401, description = "Invalid account credentials", body = ErrorMessage),
+        (status = 429, description = "Too many failed logins", body = ErrorMessage),
+    ),
+    security(("basic_auth" = [])),
+)]
+pub async fn list_webhooks(
+    credentials: Credentials,
+    db: Pool,
+) -> Result<Box<dyn Reply>, Rejection> {
+    let account = early_return!(authenticate(&db, credentials).await);
+
+    let webhooks: Vec<WebhookInfo> =
+        early_return!(crate::models::list_webhooks(&db, account.id).await)
+            .into_iter()
+            .map(WebhookInfo::from)
+            .collect();
+
+    Ok(Box::new(warp::reply::json(&webhooks)))
+}
+
+#[utoipa::path(
+    post,
+    path = "/account/webhooks",
+    tag = "account",
+    request_body = NewWebhook,
+    responses(
+        (status = 201, description = "The new webhook, including its secret", body = WebhookInfo),
+        (status = 400, description = "Invalid endpoint or filters", body = ErrorMessage),
+        (status = 401, description = "Invalid account credentials", body = ErrorMessage),
+        (status = 403, description = "Email address has not been verified", body = ErrorMessage),
+        (status = 409, description = "Maximum number of webhooks reached", body = ErrorMessage),
+        (status = 429, description = "Too many failed logins", body = ErrorMessage),
+    ),
+    security(("basic_auth" = [])),
+)]
+pub async fn create_webhook(
+    credentials: Credentials,
+    body: NewWebhook,
+    db: Pool,
+) -> Result<Box<dyn Reply>, Rejection> {
+    let account = early_return!(authenticate_verified(&db, credentials).await);
+    let endpoint = early_return!(webhook_endpoint(&body.endpoint).await);
+    let filters = early_return!(webhook_filters(body.filters));
+
+    let secret = crate::utils::generate_token();
+
+    let webhook = match early_return!(
+        crate::models::create_webhook(&db, account.id, &endpoint, &filters, &secret, MAX_WEBHOOKS)
+            .await
+    ) {
+        Some(webhook) => webhook,
+        None => return Ok(Box::new(Error::TooManyWebhooks)),
+    };
+
+    Ok(Box::new(warp::reply::with_status(
+        warp::reply::json(&WebhookInfo::from(webhook)),
+        StatusCode::CREATED,
+    )))
+}
+
+#[utoipa::path(
+    post,
+    path = "/account/webhooks/{webhook_id}/filters",
+    tag = "account",
+    params(("webhook_id" = i32, Path, description = "The ID of the webhook")),
+    request_body = WebhookFilters,
+    responses(
+        (status = 200, description = "The webhook with its new filters", body = WebhookInfo),
+        (status = 400, description = "Invalid filters", body = ErrorMessage),
+        (status = 401, description = "Invalid account credentials", body = ErrorMessage),
+        (status = 404, description = "Webhook not found", body = ErrorMessage),
+        (status = 429, description = "Too many failed logins", body = ErrorMessage),
+    ),
+    security(("basic_auth" = [])),
+)]
+pub async fn update_webhook_filters(
+    webhook_id: i32,
+    credentials: Credentials,
+    body: WebhookFilters,
+    db: Pool,
+) -> Result<Box<dyn Reply>, Rejection> {
+    let account = early_return!(authenticate(&db, credentials).await);
+    let filters = early_return!(webhook_filters(body));
+
+    match early_return!(
+        crate::models::update_webhook_filters(&db, account.id, webhook_id, &filters).await
+    ) {
+        Some(webhook) => Ok(Box::new(warp::reply::json(&WebhookInfo::from(webhook)))),
+        None => Ok(Box::new(Error::NotFound)),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/account/webhooks/{webhook_id}/secret",
+    tag = "account",
+    params(("webhook_id" = i32, Path, description = "The ID of the webhook")),
+    responses(
+        (status = 200, description = "The webhook, including its new secret", body = WebhookInfo),
+        (status = 401, description = "Invalid account credentials", body = ErrorMessage),
+        (status = 403, description = "Email address has not been verified", body = ErrorMessage),
+        (status = 404, description = "Webhook not found", body = ErrorMessage),
+        (status = 429, description = "Too many failed logins", body = ErrorMessage),
+    ),
+    security(("basic_auth" = [])),
+)]
+pub async fn rotate_webhook_secret(
+    webhook_id: i32,
+    credentials: Credentials,
+    db: Pool,
+) -> Result<Box<dyn Reply>, Rejection> {
+    let account = early_return!(authenticate_verified(&db, credentials).await);
+
+    let secret = crate::utils::generate_token();
+
+    match early_return!(
+        crate::models::rotate_webhook_secret(&db, account.id, webhook_id, &secret).await
+    ) {
+        Some(webhook) => Ok(Box::new(warp::reply::json(&WebhookInfo::from(webhook)))),
+        None => Ok(Box::new(Error::NotFound)),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/account/webhooks/{webhook_id}/enable",
+    tag = "account",
+    params(("webhook_id" = i32, Path, description = "The ID of the webhook")),
+    responses(
+        (status = 200, description = "The enabled webhook", body = WebhookInfo),
+        (status = 401, description = "Invalid account credentials", body = ErrorMessage),
+        (status = 404, description = "Webhook not found", body = ErrorMessage),
+        (status = 429, description = "Too many failed logins", body = ErrorMessage),
+    ),
+    security(("basic_auth" = [])),
+)]
+pub async fn enable_webhook(
+    webhook_id: i32,
+    credentials: Credentials,
+    db: Pool,
+) -> Result<Box<dyn Reply>, Rejection> {
+    let account = early_return!(authenticate(&db, credentials).await);
+
+    match early_return!(crate::models::enable_webhook(&db, account.id, webhook_id).await) {
+        Some(webhook) => Ok(Box::new(warp::reply::json(&WebhookInfo::from(webhook)))),
+        None => Ok(Box::new(Error::NotFound)),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/account/webhooks/{webhook_id}/deliveries",
+    tag = "account",
+    params(("webhook_id" = i32, Path, description = "The ID of the webhook"), IdPageOpts),
+    responses(
+        (
+            status = 200,
+            description = "Attempts to send payloads to the webhook, from the most recent",
+            body = [WebhookDelivery],
+            headers(
+                ("x-has-more" = bool, description = "If there are more results"),
+                ("x-next-cursor" = String, description = "The cursor for the next page"),
+            ),
+        ),
+        (status = 401, description = "Invalid account credentials", body = ErrorMessage),
+        (status = 404, description = "Webhook not found", body = ErrorMessage),
+        (status = 429, description = "Too many failed logins", body = ErrorMessage),
+    ),
+    security(("basic_auth" = [])),
+)]
+pub async fn list_webhook_deliveries(
+    webhook_id: i32,
+    page: IdPageOpts,
+    credentials: Credentials,
+    db: Pool,
+) -> Result<Box<dyn Reply>, Rejection> {
+    let account = early_return!(authenticate(&db, credentials).await);
+
+    if !early_return!(crate::models::webhook_exists(&db, account.id, webhook_id).await) {
+        return Ok(Box::new(Error::NotFound));
+    }
+
+    let (deliveries, next_cursor) =
+        early_return!(crate::models::webhook_deliveries(&db, webhook_id, &page).await);
+
+    let resp = page_headers(warp::http::Response::builder(), next_cursor)
+        .header("content-type", "application/json")
+        .body(serde_json::to_string(&deliveries).unwrap())
+        .unwrap();
+
+    Ok(Box::new(resp))
+}
+
+/// Send the payload of a previous delivery to a webhook again, as a new
+/// delivery.
+#[utoipa::path(
+    post,
+    path = "/account/webhooks/{webhook_id}/deliveries/{delivery_id}/redeliver",
+    tag = "account",
+    params(("webhook_id" = i32, Path, description = "The ID of the webhook"), ("delivery_id" = i64, Path, description = "The ID of the delivery attempt to send again")),
+    responses(
+        (status = 202, description = "The payload will be sent again"),
+        (status = 401, description = "Invalid account credentials", body = ErrorMessage),
+        (status = 403, description = "Email address has not been verified", body = ErrorMessage),
+        (status = 404, description = "Delivery not found", body = ErrorMessage),
+        (status = 409, description = "The webhook is disabled", body = ErrorMessage),
+        (status = 429, description = "Too many failed logins", body = ErrorMessage),
+        (status = 503, description = "Redelivering webhooks is not configured", body = ErrorMessage),
+    ),
+    security(("basic_auth" = [])),
+)]
+pub async fn redeliver_webhook(
+    webhook_id: i32,
+    delivery_id: i64,
+    credentials: Credentials,
+    db: Pool,
+    faktory: Option<fuzzysearch_common::faktory::FaktoryClient>,
+) -> Result<Box<dyn Reply>, Rejection> {
+    let account = early_return!(authenticate_verified(&db, credentials).await);
+
+    let faktory = match faktory {
+        Some(faktory) => faktory,
+        None => return Ok(Box::new(Error::Unavailable)),
+    };
+
+    let (payload, disabled) = match early_return!(
+        crate::models::webhook_delivery_payload(&db, account.id, webhook_id, delivery_id).await
+    ) {
+        Some(delivery) => delivery,
+        None => return Ok(Box::new(Error::NotFound)),
+    };
+
+    // The worker would skip the delivery, so it must be enabled first.
+    if disabled {
+        return Ok(Box::new(Error::WebhookDisabled));
+    }
+
+    if let Err(err) = faktory.redeliver_webhook(payload, webhook_id).await {
+        tracing::error!("could not enqueue webhook redelivery: {}", err);
+        return Ok(Box::new(Error::Internal));
+    }
+
+    Ok(Box::new(StatusCode::ACCEPTED))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/account/webhooks/{webhook_id}",
+    tag = "account",
+    params(("webhook_id" = i32, Path, description = "The ID of the webhook")),
+    responses(
+        (status = 204, description = "The webhook was deleted"),
+        (status = 401, description = "Invalid account credentials", body = ErrorMessage),
+        (status = 404, description = "Webhook not found", body = ErrorMessage),
+        (status = 429, description = "Too many failed logins", body = ErrorMessage),
+    ),
+    security(("basic_auth" = [])),
+)]
+pub async fn delete_webhook(
+    webhook_id: i32,
+    credentials: Credentials,
+    db: Pool,
+) -> Result<Box<dyn Reply>, Rejection> {
+    let account = early_return!(authenticate(&db, credentials).await);
+
+    if !early_return!(crate::models::delete_webhook(&db, account.id, webhook_id).await) {
+        return Ok(Box::new(Error::NotFound));
+    }
+
+    Ok(Box::new(StatusCode::NO_CONTENT))
+}
+
 #[tracing::instrument]
 pub async fn handle_rejection(err: Rejection) -> Result<Box<dyn Reply>, std::convert::Infallible> {
     warn!("had rejection");
@@ -517,3 +2847,144 @@ pub async fn handle_rejection(err: Rejection) -> Result<Box<dyn Reply>, std::con
 
     Ok(Box::new(warp::reply::with_status(json, code)))
 }
+
+#[cfg(test)]
+mod tests {
+    use fuzzysearch_common::types::SearchResult;
+
+    use super::{group_batch_results, rate_limit_headers, Error};
+    use crate::types::RateLimitStatus;
+
+    fn result(site_id: i64, searched_hash: i64, distance: u64) -> SearchResult {
+        SearchResult {
+            site_id,
+            searched_hash: Some(searched_hash),
+            distance: Some(distance),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn groups_batch_results_by_image() {
+        let filenames = vec![
+            Some("a.png".to_string()),
+            None,
+            Some("b.png".to_string()),
+            Some("a-copy.png".to_string()),
+        ];
+        let hashes = vec![Ok(1), Err(Error::UnsupportedImage), Ok(2), Ok(1)];
+        let results = vec![
+            result(10, 2, 3),
+            result(11, 1, 0),
+            result(12, 2, 1),
+            result(13, 1, 4),
+        ];
+
+        let similarities = group_batch_results(filenames.clone(), hashes, &results, false);
+        assert_eq!(similarities.len(), 4);
+
+        for (index, similarity) in similarities.iter().enumerate() {
+            assert_eq!(similarity.index, index);
+            assert_eq!(similarity.filename, filenames[index]);
+        }
+
+        let site_ids = |index: usize| {
+            similarities[index]
+                .matches
+                .iter()
+                .map(|result| result.site_id)
+                .collect::<Vec<_>>()
+        };
+
+        // Close matches are dropped when there was an exact match.
+        assert_eq!(similarities[0].hash, Some(1));
+        assert_eq!(site_ids(0), vec![11]);
+        assert!(similarities[0].error.is_none());
+
+        assert_eq!(similarities[1].hash, None);
+        assert!(similarities[1].matches.is_empty());
+        assert_eq!(
+            similarities[1].error.as_ref().map(|err| err.code),
+            Some(415)
+        );
+
+        // Without an exact match every close match is included, closest
+        // first.
+        assert_eq!(similarities[2].hash, Some(2));
+        assert_eq!(site_ids(2), vec![12, 10]);
+
+        assert_eq!(site_ids(3), vec![11]);
+
+        let hashes = vec![Ok(1), Err(Error::UnsupportedImage), Ok(2), Ok(1)];
+        let forced = group_batch_results(filenames, hashes, &results, true);
+        assert_eq!(
+            forced[0]
+                .matches
+                .iter()
+                .map(|result| result.site_id)
+                .collect::<Vec<_>>(),
+            vec![11, 13]
+        );
+    }
+
+    fn status(group: Option<&'static str>, remaining: i16, reset: u64) -> RateLimitStatus {
+        RateLimitStatus {
+            group,
+            remaining,
+            limit: 60,
+            reset,
+        }
+    }
+
+    fn header<'a>(headers: &'a warp::http::HeaderMap, name: &str) -> Option<&'a str> {
+        headers.get(name).map(|value| value.to_str().unwrap())
+    }
+
+    #[test]
+    fn rate_limit_headers_describe_each_group() {
+        let statuses = [status(Some("image"), 40, 20), status(Some("hash"), 10, 50)];
+        let resp = rate_limit_headers(warp::http::Response::builder(), &statuses, false)
+            .body(())
+            .unwrap();
+        let headers = resp.headers();
+
+        for (name, value) in [
+            ("x-rate-limit-total-image", "60"),
+            ("x-rate-limit-remaining-image", "40"),
+            ("x-rate-limit-total-hash", "60"),
+            ("x-rate-limit-remaining-hash", "10"),
+            ("ratelimit-limit", "60"),
+            ("ratelimit-remaining", "10"),
+            ("ratelimit-reset", "50"),
+        ] {
+            assert_eq!(header(headers, name), Some(value), "{}", name);
+        }
+        assert_eq!(header(headers, "retry-after"), None);
+
+        let resp = rate_limit_headers(warp::http::Response::builder(), &[], false)
+            .body(())
+            .unwrap();
+        assert!(resp.headers().is_empty());
+    }
+
+    #[test]
+    fn rate_limited_responses_give_retry_after() {
+        let resp = warp::Reply::into_response(Error::RateLimit(status(Some("image"), 0, 12)));
+        let headers = resp.headers();
+
+        assert_eq!(resp.status(), 429);
+        assert_eq!(header(headers, "retry-after"), Some("12"));
+        assert_eq!(header(headers, "ratelimit-remaining"), Some("0"));
+        assert_eq!(header(headers, "x-rate-limit-remaining-image"), Some("0"));
+
+        // Limits that are not for an API key group have no legacy headers.
+        let resp = warp::Reply::into_response(Error::RateLimit(status(None, 0, 30)));
+        let headers = resp.headers();
+
+        assert_eq!(resp.status(), 429);
+        assert_eq!(header(headers, "retry-after"), Some("30"));
+        assert!(!headers
+            .keys()
+            .any(|name| name.as_str().starts_with("x-rate-limit")));
+    }
+}
@@ -0,0 +1,458 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+
+use crate::types::*;
+use crate::Pool;
+
+/// A shared rate limiter.
+pub type Limiter = Arc<dyn RateLimiter>;
+
+/// Looks up API keys and tracks how many requests each key has made.
+#[async_trait]
+pub trait RateLimiter: Send + Sync {
+    async fn lookup_api_key(&self, key: &str) -> Option<ApiKey>;
+
+    /// Record requests made by an API key for a group, returning if they are
+    /// allowed.
+    async fn update_rate_limit(
+        &self,
+        api_key: &ApiKey,
+        group_limit: i16,
+        group_name: &'static str,
+        incr_by: i16,
+    ) -> Result<RateLimit, sqlx::Error>;
+
+    /// Get the number of requests an API key may currently make for a group,
+    /// and when it will be able to make the full limit of requests again.
+    async fn remaining(
+        &self,
+        api_key: &ApiKey,
+        group_limit: i16,
+        group_name: &'static str,
+    ) -> Result<(i16, chrono::DateTime<chrono::Utc>), sqlx::Error>;
+}
+
+/// Create the rate limiter named by configuration, either `postgres` or
+/// `memory`. Unknown names fall back to `postgres`.
+pub fn from_config(name: &str, db: Pool) -> Limiter {
+    match name {
+        "postgres" => Arc::new(PostgresRateLimiter { db }),
+        "memory" => {
+            let limiter = Arc::new(MemoryRateLimiter::new(db));
+            tokio::spawn(limiter.clone().flush_counts());
+            limiter
+        }
+        _ => {
+            tracing::warn!("Unknown rate limiter {}, using postgres", name);
+            Arc::new(PostgresRateLimiter { db })
+        }
+    }
+}
+
+/// A fixed window rate limiter storing every request in the database.
+pub struct PostgresRateLimiter {
+    db: Pool,
+}
+
+#[async_trait]
+impl RateLimiter for PostgresRateLimiter {
+    async fn lookup_api_key(&self, key: &str) -> Option<ApiKey> {
+        crate::models::lookup_api_key(key, &self.db).await
+    }
+
+    async fn update_rate_limit(
+        &self,
+        api_key: &ApiKey,
+        group_limit: i16,
+        group_name: &'static str,
+        incr_by: i16,
+    ) -> Result<RateLimit, sqlx::Error> {
+        crate::utils::update_rate_limit(&self.db, api_key.id, group_limit, group_name, incr_by)
+            .await
+    }
+
+    async fn remaining(
+        &self,
+        api_key: &ApiKey,
+        group_limit: i16,
+        group_name: &'static str,
+    ) -> Result<(i16, chrono::DateTime<chrono::Utc>), sqlx::Error> {
+        let time_window = crate::utils::rate_limit_window(chrono::Utc::now());
+        let counts =
+            crate::models::api_key_window_counts(&self.db, api_key.id, time_window).await?;
+        let count = counts.get(group_name).copied().unwrap_or(0);
+
+        let resets_at = chrono::TimeZone::timestamp_opt(&chrono::Utc, time_window + 60, 0).unwrap();
+
+        Ok((group_limit.saturating_sub(count).max(0), resets_at))
+    }
+}
+
+/// How long API key lookups are cached, which is also how long it takes for
+/// a revoked key or changed limit to take effect.
+const API_KEY_CACHE_TTL: Duration = Duration::from_secs(60);
+
+/// The most unknown API keys that are cached, so requests with made up keys
+/// cannot grow the cache without bound.
+const MAX_UNKNOWN_API_KEYS: usize = 10_000;
+
+/// How often request counts are written to the database.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The number of whole seconds until a bucket with a capacity has refilled
+/// some number of tokens, which is never more than a minute.
+fn seconds_until(tokens: f64, capacity: f64) -> u64 {
+    if capacity <= 0.0 {
+        return 60;
+    }
+
+    (tokens * 60.0 / capacity).ceil().clamp(0.0, 60.0) as u64
+}
+
+/// A token bucket for a group, holding up to the group's limit and refilling
+/// at the limit per minute.
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, capacity: f64, now: Instant) {
+        let elapsed = now.duration_since(self.updated_at).as_secs_f64();
+
+        self.tokens = (self.tokens + elapsed * capacity / 60.0).min(capacity);
+        self.updated_at = now;
+    }
+}
+
+#[derive(Default)]
+struct MemoryState {
+    api_keys: HashMap<String, (ApiKey, Instant)>,
+    /// Keys that were looked up and did not exist.
+    unknown_api_keys: HashMap<String, Instant>,
+    buckets: HashMap<(i32, &'static str), Bucket>,
+    /// Request counts by API key ID, time window, and group that have not yet
+    /// been written to the database.
+    counts: HashMap<(i32, i64, &'static str), i16>,
+}
+
+impl MemoryState {
+    /// Get a cached API key lookup, if it has not expired.
+    fn cached_api_key(&self, key: &str, now: Instant) -> Option<Option<ApiKey>> {
+        if let Some((api_key, cached_at)) = self.api_keys.get(key) {
+            if now - *cached_at < API_KEY_CACHE_TTL {
+                return Some(Some(api_key.clone()));
+            }
+        }
+
+        match self.unknown_api_keys.get(key) {
+            Some(cached_at) if now - *cached_at < API_KEY_CACHE_TTL => Some(None),
+            _ => None,
+        }
+    }
+
+    fn cache_api_key(&mut self, key: &str, api_key: Option<ApiKey>, now: Instant) {
+        match api_key {
+            Some(api_key) => {
+                self.unknown_api_keys.remove(key);
+                self.api_keys.insert(key.to_string(), (api_key, now));
+            }
+            None => {
+                self.api_keys.remove(key);
+
+                if self.unknown_api_keys.len() < MAX_UNKNOWN_API_KEYS
+                    || self.unknown_api_keys.contains_key(key)
+                {
+                    self.unknown_api_keys.insert(key.to_string(), now);
+                }
+            }
+        }
+    }
+
+    /// Remove expired API keys and full buckets.
+    fn expire(&mut self, now: Instant) {
+        self.api_keys
+            .retain(|_key, (_api_key, cached_at)| now - *cached_at < API_KEY_CACHE_TTL);
+        self.unknown_api_keys
+            .retain(|_key, cached_at| now - *cached_at < API_KEY_CACHE_TTL);
+        self.buckets
+            .retain(|_key, bucket| now - bucket.updated_at < Duration::from_secs(60));
+    }
+
+    /// Add back request counts that could not be written to the database.
+    fn restore_counts(&mut self, counts: HashMap<(i32, i64, &'static str), i16>) {
+        for (key, count) in counts {
+            let existing = self.counts.entry(key).or_default();
+            *existing = existing.saturating_add(count);
+        }
+    }
+}
+
+/// A token bucket rate limiter kept in memory, with request counts
+/// periodically written to the database for usage statistics.
+///
+/// Limits are only enforced within this process, so each instance of the API
+/// allows the full limit.
+pub struct MemoryRateLimiter {
+    db: Pool,
+    state: Mutex<MemoryState>,
+}
+
+impl MemoryRateLimiter {
+    pub fn new(db: Pool) -> Self {
+        Self {
+            db,
+            state: Default::default(),
+        }
+    }
+
+    /// Periodically write request counts to the database and remove expired
+    /// API keys and full buckets.
+    pub async fn flush_counts(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(FLUSH_INTERVAL);
+
+        loop {
+            interval.tick().await;
+
+            let counts = {
+                let mut state = self.state.lock().unwrap();
+                state.expire(Instant::now());
+
+                std::mem::take(&mut state.counts)
+            };
+
+            if counts.is_empty() {
+                continue;
+            }
+
+            if let Err(err) = crate::models::add_rate_limit_counts(&self.db, &counts).await {
+                tracing::error!("could not flush rate limit counts: {:?}", err);
+
+                self.state.lock().unwrap().restore_counts(counts);
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl RateLimiter for MemoryRateLimiter {
+    async fn lookup_api_key(&self, key: &str) -> Option<ApiKey> {
+        if let Some(api_key) = self
+            .state
+            .lock()
+            .unwrap()
+            .cached_api_key(key, Instant::now())
+        {
+            return api_key;
+        }
+
+        let api_key = crate::models::lookup_api_key(key, &self.db).await;
+
+        self.state
+            .lock()
+            .unwrap()
+            .cache_api_key(key, api_key.clone(), Instant::now());
+
+        api_key
+    }
+
+    async fn update_rate_limit(
+        &self,
+        api_key: &ApiKey,
+        group_limit: i16,
+        group_name: &'static str,
+        incr_by: i16,
+    ) -> Result<RateLimit, sqlx::Error> {
+        let now = Instant::now();
+        let capacity = f64::from(group_limit.max(0));
+        let time_window = crate::utils::rate_limit_window(chrono::Utc::now());
+
+        let mut state = self.state.lock().unwrap();
+
+        let count = state
+            .counts
+            .entry((api_key.id, time_window, group_name))
+            .or_default();
+        *count = count.saturating_add(incr_by);
+
+        let bucket = state
+            .buckets
+            .entry((api_key.id, group_name))
+            .or_insert(Bucket {
+                tokens: capacity,
+                updated_at: now,
+            });
+        bucket.refill(capacity, now);
+
+        let requested = f64::from(incr_by);
+        if bucket.tokens < requested {
+            return Ok(RateLimit::Limited(RateLimitStatus {
+                group: Some(group_name),
+                remaining: bucket.tokens as i16,
+                limit: group_limit,
+                reset: seconds_until(requested - bucket.tokens, capacity),
+            }));
+        }
+
+        bucket.tokens -= requested;
+
+        Ok(RateLimit::Available(RateLimitStatus {
+            group: Some(group_name),
+            remaining: bucket.tokens as i16,
+            limit: group_limit,
+            reset: seconds_until(capacity - bucket.tokens, capacity),
+        }))
+    }
+
+    async fn remaining(
+        &self,
+        api_key: &ApiKey,
+        group_limit: i16,
+        group_name: &'static str,
+    ) -> Result<(i16, chrono::DateTime<chrono::Utc>), sqlx::Error> {
+        let now = Instant::now();
+        let capacity = f64::from(group_limit.max(0));
+
+        let tokens = match self
+            .state
+            .lock()
+            .unwrap()
+            .buckets
+            .get_mut(&(api_key.id, group_name))
+        {
+            Some(bucket) => {
+                bucket.refill(capacity, now);
+                bucket.tokens
+            }
+            None => capacity,
+        };
+
+        let resets_at = chrono::Utc::now()
+            + chrono::Duration::seconds(seconds_until(capacity - tokens, capacity) as i64);
+
+        Ok((tokens as i16, resets_at))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn api_key(id: i32) -> ApiKey {
+        ApiKey {
+            id,
+            name: None,
+            owner_email: "test@example.com".to_string(),
+            name_limit: 60,
+            image_limit: 60,
+            hash_limit: 15,
+            sha256_limit: 60,
+        }
+    }
+
+    #[test]
+    fn buckets_refill_at_capacity_per_minute() {
+        let start = Instant::now();
+        let mut bucket = Bucket {
+            tokens: 0.0,
+            updated_at: start,
+        };
+
+        bucket.refill(60.0, start + Duration::from_secs(15));
+        assert_eq!(bucket.tokens, 15.0);
+
+        bucket.refill(60.0, start + Duration::from_secs(45));
+        assert_eq!(bucket.tokens, 45.0);
+
+        // Buckets never hold more than their capacity.
+        bucket.refill(60.0, start + Duration::from_secs(120));
+        assert_eq!(bucket.tokens, 60.0);
+        assert_eq!(bucket.updated_at, start + Duration::from_secs(120));
+    }
+
+    #[test]
+    fn seconds_until_refilled() {
+        for (tokens, capacity, expected) in [
+            (0.0, 60.0, 0),
+            (1.0, 60.0, 1),
+            (0.5, 60.0, 1),
+            (30.0, 60.0, 30),
+            (1.0, 15.0, 4),
+            (15.0, 15.0, 60),
+            (120.0, 60.0, 60),
+            (-1.0, 60.0, 0),
+            (1.0, 0.0, 60),
+        ] {
+            assert_eq!(
+                seconds_until(tokens, capacity),
+                expected,
+                "{} tokens at {} per minute",
+                tokens,
+                capacity
+            );
+        }
+    }
+
+    #[test]
+    fn failed_flushes_keep_counts() {
+        let mut state = MemoryState::default();
+        state.counts.insert((1, 60, "image"), 3);
+
+        let flushed = std::mem::take(&mut state.counts);
+
+        // Requests made while the counts were being written are kept too.
+        state.counts.insert((1, 60, "image"), 2);
+        state.counts.insert((1, 60, "hash"), 1);
+        state.restore_counts(flushed);
+
+        assert_eq!(state.counts.len(), 2);
+        assert_eq!(state.counts[&(1, 60, "image")], 5);
+        assert_eq!(state.counts[&(1, 60, "hash")], 1);
+
+        state.restore_counts(vec![((1, 60, "hash"), i16::MAX)].into_iter().collect());
+        assert_eq!(state.counts[&(1, 60, "hash")], i16::MAX);
+    }
+
+    #[test]
+    fn cached_api_keys_expire() {
+        let start = Instant::now();
+        let mut state = MemoryState::default();
+
+        state.cache_api_key("known", Some(api_key(1)), start);
+        state.cache_api_key("unknown", None, start);
+
+        let later = start + API_KEY_CACHE_TTL - Duration::from_secs(1);
+        assert!(matches!(
+            state.cached_api_key("known", later),
+            Some(Some(api_key)) if api_key.id == 1
+        ));
+        assert!(matches!(state.cached_api_key("unknown", later), Some(None)));
+        assert!(state.cached_api_key("other", later).is_none());
+
+        let expired = start + API_KEY_CACHE_TTL;
+        assert!(state.cached_api_key("known", expired).is_none());
+        assert!(state.cached_api_key("unknown", expired).is_none());
+
+        state.expire(expired);
+        assert!(state.api_keys.is_empty());
+        assert!(state.unknown_api_keys.is_empty());
+    }
+
+    #[test]
+    fn unknown_api_keys_are_limited() {
+        let now = Instant::now();
+        let mut state = MemoryState::default();
+
+        for i in 0..MAX_UNKNOWN_API_KEYS + 10 {
+            state.cache_api_key(&i.to_string(), None, now);
+        }
+        assert_eq!(state.unknown_api_keys.len(), MAX_UNKNOWN_API_KEYS);
+
+        // Keys that exist are always cached.
+        state.cache_api_key("known", Some(api_key(1)), now);
+        assert!(state.cached_api_key("known", now).is_some());
+    }
+}
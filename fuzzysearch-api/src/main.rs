@@ -2,9 +2,14 @@
 
 use warp::Filter;
 
+mod fetch;
 mod filters;
 mod handlers;
+mod limiter;
 mod models;
+mod openapi;
+mod sites;
+mod subscriptions;
 mod types;
 mod utils;
 
@@ -13,6 +18,9 @@ type Pool = sqlx::PgPool;
 #[derive(Clone)]
 pub struct Endpoints {
     pub hash_input: String,
+    /// The hash input service's endpoint for animations, which disables
+    /// animation searches when unset.
+    pub hash_input_video: Option<String>,
     pub bkapi: String,
 }
 
@@ -29,20 +37,65 @@ async fn main() {
 
     let endpoints = Endpoints {
         hash_input: std::env::var("ENDPOINT_HASH_INPUT").expect("Missing ENDPOINT_HASH_INPUT"),
+        hash_input_video: std::env::var("ENDPOINT_HASH_INPUT_VIDEO").ok(),
         bkapi: std::env::var("ENDPOINT_BKAPI").expect("Missing ENDPOINT_BKAPI"),
     };
 
     let bkapi = bkapi_client::BKApiClient::new(&endpoints.bkapi);
 
+    tokio::spawn(utils::rollup_rate_limits(db_pool.clone()));
+    tokio::spawn(utils::prune_webhook_deliveries(db_pool.clone()));
+
+    let limiter = limiter::from_config(
+        &std::env::var("RATE_LIMITER").unwrap_or_else(|_| "postgres".to_string()),
+        db_pool.clone(),
+    );
+
+    let hash_added = subscriptions::listen(db_pool.clone());
+
+    // Email is only needed to verify new accounts, so registration is
+    // unavailable without it.
+    let mailer = match (std::env::var("SMTP_URL"), std::env::var("EMAIL_FROM")) {
+        (Ok(url), Ok(from)) => {
+            Some(utils::Mailer::new(&url, &from).expect("Unable to create mailer"))
+        }
+        _ => {
+            tracing::warn!("Missing SMTP_URL or EMAIL_FROM, accounts cannot be registered");
+            None
+        }
+    };
+
+    // Faktory is only needed to redeliver webhooks, which is unavailable
+    // without it.
+    let faktory = match std::env::var("FAKTORY_URL") {
+        Ok(url) => Some(
+            fuzzysearch_common::faktory::FaktoryClient::connect(url)
+                .await
+                .expect("Unable to connect to Faktory"),
+        ),
+        Err(_err) => {
+            tracing::warn!("Missing FAKTORY_URL, webhooks cannot be redelivered");
+            None
+        }
+    };
+
     let log = warp::log("fuzzysearch-api");
     let cors = warp::cors()
         .allow_any_origin()
-        .allow_headers(vec!["x-api-key"])
-        .allow_methods(vec!["GET", "POST"]);
+        .allow_headers(vec!["x-api-key", "authorization", "content-type"])
+        .allow_methods(vec!["GET", "POST", "DELETE"]);
 
     let options = warp::options().map(|| "✓");
 
-    let api = options.or(filters::search(db_pool, bkapi, endpoints));
+    let api = options
+        .or(filters::search(
+            db_pool.clone(),
+            limiter,
+            bkapi,
+            endpoints,
+            hash_added,
+        ))
+        .or(filters::accounts(db_pool, mailer, faktory));
     let routes = api
         .or(warp::path::end()
             .map(|| warp::redirect(warp::http::Uri::from_static("https://fuzzysearch.net"))))
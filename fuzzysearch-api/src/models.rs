@@ -3,7 +3,7 @@ use prometheus::{register_histogram, Histogram};
 
 use crate::types::*;
 use crate::Pool;
-use fuzzysearch_common::types::{SearchResult, SiteInfo};
+use fuzzysearch_common::types::{HashKind, Rating, SearchResult, Site, SiteInfo};
 
 lazy_static! {
     static ref IMAGE_QUERY_DURATION: Histogram = register_histogram!(
@@ -11,6 +11,11 @@ lazy_static! {
         "Duration to perform a single image lookup query"
     )
     .unwrap();
+    static ref SHA256_QUERY_DURATION: Histogram = register_histogram!(
+        "fuzzysearch_api_sha256_query_seconds",
+        "Duration to perform a SHA-256 lookup query"
+    )
+    .unwrap();
 }
 
 #[tracing::instrument(skip(db))]
@@ -22,6 +27,7 @@ pub async fn lookup_api_key(key: &str, db: &sqlx::PgPool) -> Option<ApiKey> {
             api_key.name_limit,
             api_key.image_limit,
             api_key.hash_limit,
+            api_key.sha256_limit,
             api_key.name,
             account.email owner_email
         FROM
@@ -30,6 +36,7 @@ pub async fn lookup_api_key(key: &str, db: &sqlx::PgPool) -> Option<ApiKey> {
             ON account.id = api_key.user_id
         WHERE
             api_key.key = $1
+            AND api_key.revoked_at IS NULL
     ",
         key
     )
@@ -39,6 +46,777 @@ pub async fn lookup_api_key(key: &str, db: &sqlx::PgPool) -> Option<ApiKey> {
     .flatten()
 }
 
+#[tracing::instrument(skip(db))]
+pub async fn lookup_account(email: &str, db: &Pool) -> Result<Option<Account>, sqlx::Error> {
+    sqlx::query_as!(
+        Account,
+        "SELECT id, email, password, email_verifier, is_admin
+        FROM account
+        WHERE lower(email) = lower($1)",
+        email
+    )
+    .fetch_optional(db)
+    .await
+}
+
+/// Create a new account, returning its ID.
+///
+/// An account that has not verified its email address is given the new
+/// verifier instead, so registering again sends a new code, but keeps its
+/// password so registering cannot be used to take over an account before it
+/// is verified. No ID is returned for a verified account, which is left
+/// unchanged.
+#[tracing::instrument(skip(executor, password, email_verifier))]
+pub async fn create_account<'c, E>(
+    executor: E,
+    email: &str,
+    password: &str,
+    email_verifier: &str,
+) -> Result<Option<i32>, sqlx::Error>
+where
+    E: sqlx::Executor<'c, Database = sqlx::Postgres>,
+{
+    sqlx::query_scalar!(
+        "INSERT INTO account (email, password, email_verifier)
+        VALUES ($1, $2, $3)
+        ON CONFLICT (lower(email)) DO UPDATE
+            SET email_verifier = EXCLUDED.email_verifier
+            WHERE account.email_verifier IS NOT NULL
+        RETURNING id",
+        email,
+        password,
+        email_verifier
+    )
+    .fetch_optional(executor)
+    .await
+}
+
+/// Count an attempt at an account action, such as registering or signing
+/// in, returning the number of attempts made with the key in the window.
+#[tracing::instrument(skip(db))]
+pub async fn record_account_attempt(
+    db: &Pool,
+    key: &str,
+    time_window: i64,
+) -> Result<i16, sqlx::Error> {
+    sqlx::query_scalar!(
+        "INSERT INTO account_attempt (key, time_window, count)
+        VALUES ($1, $2, 1)
+        ON CONFLICT (key, time_window)
+            DO UPDATE SET count = account_attempt.count + 1
+        RETURNING count",
+        key,
+        time_window
+    )
+    .fetch_one(db)
+    .await
+}
+
+/// The number of attempts made with a key in the window.
+#[tracing::instrument(skip(db))]
+pub async fn account_attempts(db: &Pool, key: &str, time_window: i64) -> Result<i16, sqlx::Error> {
+    let count = sqlx::query_scalar!(
+        "SELECT count FROM account_attempt WHERE key = $1 AND time_window = $2",
+        key,
+        time_window
+    )
+    .fetch_optional(db)
+    .await?;
+
+    Ok(count.unwrap_or(0))
+}
+
+/// Delete account attempts counted in windows starting before a time.
+#[tracing::instrument(skip(db))]
+pub async fn prune_account_attempts(db: &Pool, before: i64) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query!("DELETE FROM account_attempt WHERE time_window < $1", before)
+        .execute(db)
+        .await?;
+
+    Ok(result.rows_affected())
+}
+
+/// Mark an account's email address as verified, if the verifier matches.
+#[tracing::instrument(skip(db, email_verifier))]
+pub async fn verify_account(
+    db: &Pool,
+    email: &str,
+    email_verifier: &str,
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query!(
+        "UPDATE account SET email_verifier = NULL
+        WHERE lower(email) = lower($1) AND email_verifier = $2",
+        email,
+        email_verifier
+    )
+    .execute(db)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+#[tracing::instrument(skip(db))]
+pub async fn list_api_keys(db: &Pool, account_id: i32) -> Result<Vec<ApiKeyInfo>, sqlx::Error> {
+    sqlx::query_as!(
+        ApiKeyInfo,
+        r#"SELECT
+            id, name, null::text "key", name_limit, image_limit, hash_limit, sha256_limit,
+            created_at, revoked_at
+        FROM api_key
+        WHERE user_id = $1
+        ORDER BY id"#,
+        account_id
+    )
+    .fetch_all(db)
+    .await
+}
+
+/// Create a new API key with the default limits, unless the account already
+/// has the maximum number of active keys.
+#[tracing::instrument(skip(db, key))]
+pub async fn create_api_key(
+    db: &Pool,
+    account_id: i32,
+    name: Option<&str>,
+    key: &str,
+    max_keys: i64,
+) -> Result<Option<ApiKeyInfo>, sqlx::Error> {
+    sqlx::query_as!(
+        ApiKeyInfo,
+        r#"INSERT INTO api_key (user_id, name, key)
+        SELECT $1, $2, $3
+        WHERE (SELECT count(*) FROM api_key WHERE user_id = $1 AND revoked_at IS NULL) < $4
+        RETURNING
+            id, name, key "key?", name_limit, image_limit, hash_limit, sha256_limit,
+            created_at, revoked_at"#,
+        account_id,
+        name,
+        key,
+        max_keys
+    )
+    .fetch_optional(db)
+    .await
+}
+
+#[tracing::instrument(skip(db))]
+pub async fn rename_api_key(
+    db: &Pool,
+    account_id: i32,
+    key_id: i32,
+    name: Option<&str>,
+) -> Result<Option<ApiKeyInfo>, sqlx::Error> {
+    sqlx::query_as!(
+        ApiKeyInfo,
+        r#"UPDATE api_key SET name = $3
+        WHERE id = $2 AND user_id = $1
+        RETURNING
+            id, name, null::text "key", name_limit, image_limit, hash_limit, sha256_limit,
+            created_at, revoked_at"#,
+        account_id,
+        key_id,
+        name
+    )
+    .fetch_optional(db)
+    .await
+}
+
+/// Replace an active API key with a new key, keeping its name and limits.
+#[tracing::instrument(skip(db, key))]
+pub async fn rotate_api_key(
+    db: &Pool,
+    account_id: i32,
+    key_id: i32,
+    key: &str,
+) -> Result<Option<ApiKeyInfo>, sqlx::Error> {
+    sqlx::query_as!(
+        ApiKeyInfo,
+        r#"UPDATE api_key SET key = $3
+        WHERE id = $2 AND user_id = $1 AND revoked_at IS NULL
+        RETURNING
+            id, name, key "key?", name_limit, image_limit, hash_limit, sha256_limit,
+            created_at, revoked_at"#,
+        account_id,
+        key_id,
+        key
+    )
+    .fetch_optional(db)
+    .await
+}
+
+#[tracing::instrument(skip(db))]
+pub async fn revoke_api_key(
+    db: &Pool,
+    account_id: i32,
+    key_id: i32,
+) -> Result<Option<ApiKeyInfo>, sqlx::Error> {
+    sqlx::query_as!(
+        ApiKeyInfo,
+        r#"UPDATE api_key SET revoked_at = coalesce(revoked_at, current_timestamp)
+        WHERE id = $2 AND user_id = $1
+        RETURNING
+            id, name, null::text "key", name_limit, image_limit, hash_limit, sha256_limit,
+            created_at, revoked_at"#,
+        account_id,
+        key_id
+    )
+    .fetch_optional(db)
+    .await
+}
+
+/// Update the limits of any API key.
+#[tracing::instrument(skip(db))]
+pub async fn update_api_key_limits(
+    db: &Pool,
+    key_id: i32,
+    limits: &ApiKeyLimits,
+) -> Result<Option<ApiKeyInfo>, sqlx::Error> {
+    sqlx::query_as!(
+        ApiKeyInfo,
+        r#"UPDATE api_key SET
+            name_limit = coalesce($2, name_limit),
+            image_limit = coalesce($3, image_limit),
+            hash_limit = coalesce($4, hash_limit),
+            sha256_limit = coalesce($5, sha256_limit)
+        WHERE id = $1
+        RETURNING
+            id, name, null::text "key", name_limit, image_limit, hash_limit, sha256_limit,
+            created_at, revoked_at"#,
+        key_id,
+        limits.name_limit,
+        limits.image_limit,
+        limits.hash_limit,
+        limits.sha256_limit
+    )
+    .fetch_optional(db)
+    .await
+}
+
+#[tracing::instrument(skip(db))]
+pub async fn list_hash_watches(db: &Pool, account_id: i32) -> Result<Vec<HashWatch>, sqlx::Error> {
+    sqlx::query_as!(
+        HashWatch,
+        "SELECT id, hash, distance, created_at
+        FROM hash_watch
+        WHERE account_id = $1
+        ORDER BY id",
+        account_id
+    )
+    .fetch_all(db)
+    .await
+}
+
+/// Watch a hash, or update the distance of a hash that was already watched,
+/// unless the account already watches the maximum number of hashes.
+#[tracing::instrument(skip(db))]
+pub async fn watch_hash(
+    db: &Pool,
+    account_id: i32,
+    hash: i64,
+    distance: i16,
+    max_watches: i64,
+) -> Result<Option<HashWatch>, sqlx::Error> {
+    sqlx::query_as!(
+        HashWatch,
+        "INSERT INTO hash_watch (account_id, hash, distance)
+        SELECT $1, $2, $3
+        WHERE
+            (SELECT count(*) FROM hash_watch WHERE account_id = $1) < $4 OR
+            EXISTS (SELECT 1 FROM hash_watch WHERE account_id = $1 AND hash = $2)
+        ON CONFLICT (account_id, hash) DO UPDATE SET distance = EXCLUDED.distance
+        RETURNING id, hash, distance, created_at",
+        account_id,
+        hash,
+        distance,
+        max_watches
+    )
+    .fetch_optional(db)
+    .await
+}
+
+#[tracing::instrument(skip(db))]
+pub async fn unwatch_hash(db: &Pool, account_id: i32, watch_id: i32) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query!(
+        "DELETE FROM hash_watch WHERE id = $2 AND account_id = $1",
+        account_id,
+        watch_id
+    )
+    .execute(db)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+#[tracing::instrument(skip(db))]
+pub async fn list_webhooks(db: &Pool, account_id: i32) -> Result<Vec<Webhook>, sqlx::Error> {
+    sqlx::query_as!(
+        Webhook,
+        r#"SELECT
+            id, endpoint, sites, artists, max_rating, require_hash, created_at,
+            null::text "secret", consecutive_failures, disabled_at
+        FROM webhook
+        WHERE account_id = $1
+        ORDER BY id"#,
+        account_id
+    )
+    .fetch_all(db)
+    .await
+}
+
+/// The sites and rating of webhook filters, as they are stored.
+fn stored_webhook_filters(filters: &WebhookFilters) -> (Option<Vec<String>>, Option<i16>) {
+    let sites = filters
+        .sites
+        .as_ref()
+        .map(|sites| sites.iter().map(ToString::to_string).collect());
+    let max_rating = filters.max_rating.as_ref().map(Rating::level);
+
+    (sites, max_rating)
+}
+
+/// Create a new webhook, unless the account already has the maximum number
+/// of webhooks.
+#[tracing::instrument(skip(db, secret))]
+pub async fn create_webhook(
+    db: &Pool,
+    account_id: i32,
+    endpoint: &str,
+    filters: &WebhookFilters,
+    secret: &str,
+    max_webhooks: i64,
+) -> Result<Option<Webhook>, sqlx::Error> {
+    let (sites, max_rating) = stored_webhook_filters(filters);
+
+    sqlx::query_as!(
+        Webhook,
+        r#"INSERT INTO webhook
+            (account_id, endpoint, sites, artists, max_rating, require_hash, secret)
+        SELECT $1, $2, $3, $4, $5, $6, $7
+        WHERE (SELECT count(*) FROM webhook WHERE account_id = $1) < $8
+        RETURNING
+            id, endpoint, sites, artists, max_rating, require_hash, created_at,
+            secret "secret?", consecutive_failures, disabled_at"#,
+        account_id,
+        endpoint,
+        sites.as_deref(),
+        filters.artists.as_deref(),
+        max_rating,
+        filters.require_hash,
+        secret,
+        max_webhooks
+    )
+    .fetch_optional(db)
+    .await
+}
+
+/// Replace all of a webhook's filters.
+#[tracing::instrument(skip(db))]
+pub async fn update_webhook_filters(
+    db: &Pool,
+    account_id: i32,
+    webhook_id: i32,
+    filters: &WebhookFilters,
+) -> Result<Option<Webhook>, sqlx::Error> {
+    let (sites, max_rating) = stored_webhook_filters(filters);
+
+    sqlx::query_as!(
+        Webhook,
+        r#"UPDATE webhook SET sites = $3, artists = $4, max_rating = $5, require_hash = $6
+        WHERE id = $2 AND account_id = $1
+        RETURNING
+            id, endpoint, sites, artists, max_rating, require_hash, created_at,
+            null::text "secret", consecutive_failures, disabled_at"#,
+        account_id,
+        webhook_id,
+        sites.as_deref(),
+        filters.artists.as_deref(),
+        max_rating,
+        filters.require_hash
+    )
+    .fetch_optional(db)
+    .await
+}
+
+/// Replace the secret used to sign a webhook's payloads.
+#[tracing::instrument(skip(db, secret))]
+pub async fn rotate_webhook_secret(
+    db: &Pool,
+    account_id: i32,
+    webhook_id: i32,
+    secret: &str,
+) -> Result<Option<Webhook>, sqlx::Error> {
+    sqlx::query_as!(
+        Webhook,
+        r#"UPDATE webhook SET secret = $3
+        WHERE id = $2 AND account_id = $1
+        RETURNING
+            id, endpoint, sites, artists, max_rating, require_hash, created_at,
+            secret "secret?", consecutive_failures, disabled_at"#,
+        account_id,
+        webhook_id,
+        secret
+    )
+    .fetch_optional(db)
+    .await
+}
+
+/// Enable a webhook that was disabled after too many failures.
+#[tracing::instrument(skip(db))]
+pub async fn enable_webhook(
+    db: &Pool,
+    account_id: i32,
+    webhook_id: i32,
+) -> Result<Option<Webhook>, sqlx::Error> {
+    sqlx::query_as!(
+        Webhook,
+        r#"UPDATE webhook SET disabled_at = NULL, consecutive_failures = 0
+        WHERE id = $2 AND account_id = $1
+        RETURNING
+            id, endpoint, sites, artists, max_rating, require_hash, created_at,
+            null::text "secret", consecutive_failures, disabled_at"#,
+        account_id,
+        webhook_id
+    )
+    .fetch_optional(db)
+    .await
+}
+
+#[tracing::instrument(skip(db))]
+pub async fn webhook_exists(
+    db: &Pool,
+    account_id: i32,
+    webhook_id: i32,
+) -> Result<bool, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"SELECT EXISTS (SELECT 1 FROM webhook WHERE id = $2 AND account_id = $1) "exists!""#,
+        account_id,
+        webhook_id
+    )
+    .fetch_one(db)
+    .await
+}
+
+/// Get a page of attempts to send payloads to a webhook.
+#[tracing::instrument(skip(db))]
+pub async fn webhook_deliveries(
+    db: &Pool,
+    webhook_id: i32,
+    page: &IdPageOpts,
+) -> Result<(Vec<WebhookDelivery>, Option<i64>), sqlx::Error> {
+    let limit = page.limit() as usize;
+
+    let mut deliveries = sqlx::query_as!(
+        WebhookDelivery,
+        "SELECT
+            id, delivery_id, attempt, payload, status_code, latency_ms, error, created_at
+        FROM webhook_delivery
+        WHERE webhook_id = $1 AND ($2::bigint IS NULL OR id < $2)
+        ORDER BY id DESC
+        LIMIT $3",
+        webhook_id,
+        page.cursor,
+        limit as i64 + 1
+    )
+    .fetch_all(db)
+    .await?;
+
+    let next_cursor = if deliveries.len() > limit {
+        deliveries.truncate(limit);
+        deliveries.last().map(|delivery| delivery.id)
+    } else {
+        None
+    };
+
+    Ok((deliveries, next_cursor))
+}
+
+/// Get the payload of an attempt to send to an account's webhook, and if the
+/// webhook is disabled.
+#[tracing::instrument(skip(db))]
+pub async fn webhook_delivery_payload(
+    db: &Pool,
+    account_id: i32,
+    webhook_id: i32,
+    delivery_id: i64,
+) -> Result<Option<(serde_json::Value, bool)>, sqlx::Error> {
+    let row = sqlx::query!(
+        r#"SELECT webhook_delivery.payload, webhook.disabled_at IS NOT NULL "disabled!"
+        FROM webhook_delivery
+        JOIN webhook ON webhook.id = webhook_delivery.webhook_id
+        WHERE webhook_delivery.id = $3 AND webhook.id = $2 AND webhook.account_id = $1"#,
+        account_id,
+        webhook_id,
+        delivery_id
+    )
+    .fetch_optional(db)
+    .await?;
+
+    Ok(row.map(|row| (row.payload, row.disabled)))
+}
+
+/// Delete attempts to send to webhooks made before a time.
+#[tracing::instrument(skip(db))]
+pub async fn prune_webhook_deliveries(
+    db: &Pool,
+    before: chrono::DateTime<chrono::Utc>,
+) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query!("DELETE FROM webhook_delivery WHERE created_at < $1", before)
+        .execute(db)
+        .await?;
+
+    Ok(result.rows_affected())
+}
+
+#[tracing::instrument(skip(db))]
+pub async fn delete_webhook(
+    db: &Pool,
+    account_id: i32,
+    webhook_id: i32,
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query!(
+        "DELETE FROM webhook WHERE id = $2 AND account_id = $1",
+        account_id,
+        webhook_id
+    )
+    .execute(db)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Get an API key's usage of each rate limit group since a time, aggregated
+/// into buckets.
+///
+/// Usage older than the raw retention period is only available by day.
+#[tracing::instrument(skip(executor))]
+pub async fn api_key_usage<'c, E>(
+    executor: E,
+    key_id: i32,
+    bucket: UsageBucket,
+    since: chrono::DateTime<chrono::Utc>,
+) -> Result<Vec<GroupUsage>, sqlx::Error>
+where
+    E: sqlx::Executor<'c, Database = sqlx::Postgres>,
+{
+    sqlx::query_as!(
+        GroupUsage,
+        r#"SELECT group_name "group!", bucket "time!", sum(count)::bigint "count!"
+        FROM (
+            SELECT
+                group_name,
+                date_trunc($2, to_timestamp(time_window) AT TIME ZONE 'UTC') AT TIME ZONE 'UTC' bucket,
+                count::bigint count
+            FROM rate_limit
+            WHERE api_key_id = $1 AND to_timestamp(time_window) >= $3
+            UNION ALL
+            SELECT group_name, day::timestamp AT TIME ZONE 'UTC' bucket, count::bigint count
+            FROM rate_limit_daily
+            WHERE api_key_id = $1 AND $2 = 'day' AND day >= ($3 AT TIME ZONE 'UTC')::date
+        ) usage
+        GROUP BY group_name, bucket
+        ORDER BY bucket, group_name"#,
+        key_id,
+        bucket.as_str(),
+        since
+    )
+    .fetch_all(executor)
+    .await
+}
+
+/// Get the number of requests an API key has made in each rate limit group
+/// during a time window.
+#[tracing::instrument(skip(db))]
+pub async fn api_key_window_counts(
+    db: &Pool,
+    key_id: i32,
+    time_window: i64,
+) -> Result<std::collections::HashMap<String, i16>, sqlx::Error> {
+    let counts = sqlx::query!(
+        "SELECT group_name, count FROM rate_limit WHERE api_key_id = $1 AND time_window = $2",
+        key_id,
+        time_window
+    )
+    .fetch_all(db)
+    .await?
+    .into_iter()
+    .map(|row| (row.group_name, row.count))
+    .collect();
+
+    Ok(counts)
+}
+
+/// Add request counts to rate limit windows, keyed by API key ID, time
+/// window, and group name.
+#[tracing::instrument(skip(db, counts), fields(counts = counts.len()))]
+pub async fn add_rate_limit_counts(
+    db: &Pool,
+    counts: &std::collections::HashMap<(i32, i64, &'static str), i16>,
+) -> Result<(), sqlx::Error> {
+    let mut key_ids = Vec::with_capacity(counts.len());
+    let mut time_windows = Vec::with_capacity(counts.len());
+    let mut group_names = Vec::with_capacity(counts.len());
+    let mut group_counts = Vec::with_capacity(counts.len());
+
+    for ((key_id, time_window, group_name), count) in counts {
+        key_ids.push(*key_id);
+        time_windows.push(*time_window);
+        group_names.push(group_name.to_string());
+        group_counts.push(*count);
+    }
+
+    sqlx::query!(
+        "INSERT INTO rate_limit (api_key_id, time_window, group_name, count)
+        SELECT * FROM unnest($1::integer[], $2::bigint[], $3::text[], $4::smallint[])
+        ON CONFLICT ON CONSTRAINT unique_window
+            DO UPDATE SET count = rate_limit.count + EXCLUDED.count",
+        &key_ids,
+        &time_windows,
+        &group_names,
+        &group_counts
+    )
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
+
+/// Move rate limit windows from before a time into daily totals, returning
+/// the number of daily totals that were updated.
+///
+/// The time should be the start of a day so that each daily total is only
+/// written once, but totals are added together if it is not.
+#[tracing::instrument(skip(executor))]
+pub async fn rollup_rate_limits<'c, E>(executor: E, before: i64) -> Result<u64, sqlx::Error>
+where
+    E: sqlx::Executor<'c, Database = sqlx::Postgres>,
+{
+    let result = sqlx::query!(
+        "WITH rolled AS (
+            DELETE FROM rate_limit
+            WHERE time_window < $1
+            RETURNING api_key_id, time_window, group_name, count
+        )
+        INSERT INTO rate_limit_daily (api_key_id, day, group_name, count)
+        SELECT api_key_id, (to_timestamp(time_window) AT TIME ZONE 'UTC')::date, group_name, sum(count)
+        FROM rolled
+        GROUP BY 1, 2, 3
+        ON CONFLICT (api_key_id, day, group_name)
+            DO UPDATE SET count = rate_limit_daily.count + EXCLUDED.count",
+        before
+    )
+    .execute(executor)
+    .await?;
+
+    Ok(result.rows_affected())
+}
+
+/// Look up what has been indexed for an artist's handle on a site.
+///
+/// Handles are compared case insensitively.
+pub async fn handle_info(db: &Pool, site: Site, handle: &str) -> Result<HandleInfo, sqlx::Error> {
+    let mut info = HandleInfo {
+        site,
+        handle: handle.to_string(),
+        indexed: false,
+        submissions: 0,
+        first_posted_at: None,
+        last_posted_at: None,
+        backfill: None,
+    };
+
+    match site {
+        Site::FurAffinity => {
+            let row = sqlx::query!(
+                "SELECT
+                    count(submission.id) submissions,
+                    min(submission.posted_at) first_posted_at,
+                    max(submission.posted_at) last_posted_at
+                FROM artist
+                LEFT JOIN submission ON submission.artist_id = artist.id
+                WHERE lower(artist.name) = lower($1)
+                GROUP BY artist.id",
+                handle
+            )
+            .fetch_optional(db)
+            .await?;
+
+            if let Some(row) = row {
+                info.indexed = true;
+                info.submissions = row.submissions.unwrap_or(0);
+                info.first_posted_at = row.first_posted_at;
+                info.last_posted_at = row.last_posted_at;
+            }
+        }
+        Site::E621 => {
+            let tag = e621_artist_tag(handle);
+
+            let row = sqlx::query!(
+                r#"SELECT
+                    count(*) submissions,
+                    min(to_timestamp(data->>'created_at', 'YYYY-MM-DD"T"HH24:MI:SS"Z"')) first_posted_at,
+                    max(to_timestamp(data->>'created_at', 'YYYY-MM-DD"T"HH24:MI:SS"Z"')) last_posted_at
+                FROM e621
+                WHERE data->'tags'->'artist' ? $1"#,
+                tag
+            )
+            .fetch_one(db)
+            .await?;
+
+            info.submissions = row.submissions.unwrap_or(0);
+            info.indexed = info.submissions > 0;
+            info.first_posted_at = row.first_posted_at;
+            info.last_posted_at = row.last_posted_at;
+        }
+        Site::Weasyl => {
+            let row = sqlx::query!(
+                r#"SELECT
+                    count(*) submissions,
+                    min(to_timestamp(data->>'posted_at', 'YYYY-MM-DD"T"HH24:MI:SS"Z"')) first_posted_at,
+                    max(to_timestamp(data->>'posted_at', 'YYYY-MM-DD"T"HH24:MI:SS"Z"')) last_posted_at
+                FROM weasyl
+                WHERE lower(data->>'owner_login') = lower($1)"#,
+                handle
+            )
+            .fetch_one(db)
+            .await?;
+
+            info.submissions = row.submissions.unwrap_or(0);
+            info.indexed = info.submissions > 0;
+            info.first_posted_at = row.first_posted_at;
+            info.last_posted_at = row.last_posted_at;
+        }
+        Site::Twitter => {
+            let row = sqlx::query!(
+                "SELECT
+                    twitter_user.completed_back,
+                    twitter_user.min_id,
+                    count(tweet.id) submissions,
+                    min(to_timestamp(tweet.data->>'created_at', 'DY Mon DD HH24:MI:SS +0000 YYYY')) first_posted_at,
+                    max(to_timestamp(tweet.data->>'created_at', 'DY Mon DD HH24:MI:SS +0000 YYYY')) last_posted_at
+                FROM twitter_user
+                LEFT JOIN tweet ON tweet.twitter_user_id = twitter_user.twitter_id
+                WHERE lower(twitter_user.data->>'screen_name') = lower($1)
+                GROUP BY twitter_user.twitter_id",
+                handle
+            )
+            .fetch_optional(db)
+            .await?;
+
+            if let Some(row) = row {
+                info.indexed = true;
+                info.submissions = row.submissions.unwrap_or(0);
+                info.first_posted_at = row.first_posted_at;
+                info.last_posted_at = row.last_posted_at;
+                info.backfill = Some(TwitterBackfill {
+                    completed: row.completed_back,
+                    min_id: row.min_id,
+                });
+            }
+        }
+    }
+
+    Ok(info)
+}
+
 #[derive(serde::Serialize)]
 struct HashSearch {
     searched_hash: i64,
@@ -46,151 +824,1188 @@ struct HashSearch {
     distance: u64,
 }
 
+/// Find all hashes within distance of the searched hashes for a hash kind
+/// other than the gradient hash, which is instead searched using bkapi.
+const HASH_KIND_SEARCH: &str = "SELECT DISTINCT
+        searched.hash searched_hash,
+        submission_hash.hash found_hash,
+        length(replace((searched.hash # submission_hash.hash)::bit(64)::text, '0', ''))::bigint distance
+    FROM unnest($1::bigint[]) searched(hash)
+    JOIN submission_hash
+        ON submission_hash.kind = $2 AND submission_hash.hash <@ (searched.hash, $3)";
+
+const FURAFFINITY_COLUMNS: &str = "'FurAffinity' site,
+    submission.id::bigint id,
+    hashes.found_hash hash,
+    submission.url,
+    submission.filename,
+    ARRAY(SELECT artist.name) artists,
+    submission.file_id,
+    null::text[] sources,
+    submission.rating::text rating,
+    submission.posted_at,
+    hashes.searched_hash,
+    hashes.distance,
+    submission.file_sha256 sha256,
+    submission.deleted";
+
+const E621_COLUMNS: &str = r#"'e621' site,
+    e621.id::bigint id,
+    hashes.found_hash hash,
+    e621.data->'file'->>'url' url,
+    (e621.data->'file'->>'md5') || '.' || (e621.data->'file'->>'ext') filename,
+    ARRAY(SELECT jsonb_array_elements_text(e621.data->'tags'->'artist')) artists,
+    null::integer file_id,
+    ARRAY(SELECT jsonb_array_elements_text(e621.data->'sources')) sources,
+    e621.data->>'rating' rating,
+    to_timestamp(e621.data->>'created_at', 'YYYY-MM-DD"T"HH24:MI:SS"Z"') posted_at,
+    hashes.searched_hash,
+    hashes.distance,
+    e621.sha256,
+    e621.deleted"#;
+
+const WEASYL_COLUMNS: &str = r#"'Weasyl' site,
+    weasyl.id::bigint id,
+    hashes.found_hash hash,
+    weasyl.data->>'link' url,
+    null::text filename,
+    ARRAY(SELECT weasyl.data->>'owner_login') artists,
+    null::integer file_id,
+    null::text[] sources,
+    weasyl.data->>'rating' rating,
+    to_timestamp(weasyl.data->>'posted_at', 'YYYY-MM-DD"T"HH24:MI:SS"Z"') posted_at,
+    hashes.searched_hash,
+    hashes.distance,
+    weasyl.sha256,
+    weasyl.deleted"#;
+
+const TWITTER_COLUMNS: &str = "'Twitter' site,
+    tweet.id,
+    hashes.found_hash hash,
+    tweet_media.url,
+    null::text filename,
+    ARRAY(SELECT tweet.data->'user'->>'screen_name') artists,
+    null::integer file_id,
+    null::text[] sources,
+    CASE
+        WHEN (tweet.data->'possibly_sensitive')::boolean IS true THEN 'adult'
+        WHEN (tweet.data->'possibly_sensitive')::boolean IS false THEN 'general'
+    END rating,
+    to_timestamp(tweet.data->>'created_at', 'DY Mon DD HH24:MI:SS +0000 YYYY') posted_at,
+    hashes.searched_hash,
+    hashes.distance,
+    null::bytea sha256,
+    false deleted";
+
+/// Where a found hash is stored for a submission.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum HashSource {
+    /// The gradient hash stored on each site's table.
+    Submission,
+    /// Another kind of hash, stored in the `submission_hash` table.
+    Kind,
+    /// A gradient hash of an animation keyframe, stored in the
+    /// `submission_frame` table.
+    Frame,
+}
+
+/// The table and columns needed to select search results for a site.
+struct SiteTable {
+    table: &'static str,
+    hash_column: &'static str,
+    id_column: &'static str,
+    columns: &'static str,
+    joins: &'static str,
+}
+
+fn site_table(site: Site) -> SiteTable {
+    match site {
+        Site::FurAffinity => SiteTable {
+            table: "submission",
+            hash_column: "hash_int",
+            id_column: "id",
+            columns: FURAFFINITY_COLUMNS,
+            joins: "JOIN artist ON submission.artist_id = artist.id",
+        },
+        Site::E621 => SiteTable {
+            table: "e621",
+            hash_column: "hash",
+            id_column: "id",
+            columns: E621_COLUMNS,
+            joins: "",
+        },
+        Site::Weasyl => SiteTable {
+            table: "weasyl",
+            hash_column: "hash",
+            id_column: "id",
+            columns: WEASYL_COLUMNS,
+            joins: "",
+        },
+        Site::Twitter => SiteTable {
+            table: "tweet_media",
+            hash_column: "hash",
+            id_column: "media_id",
+            columns: TWITTER_COLUMNS,
+            joins: "JOIN tweet ON tweet_media.tweet_id = tweet.id",
+        },
+    }
+}
+
+/// Build the columns containing a site's tags.
+///
+/// Tags are only selected when requested as they are expensive to look up.
+/// e621 tags are flattened across each of their categories, which may also be
+/// selected separately.
+fn tag_columns(site: Site, include_tags: bool, tag_categories: bool) -> String {
+    let tags = match site {
+        Site::FurAffinity if include_tags => {
+            "ARRAY(SELECT tag.name FROM tag_to_post JOIN tag ON tag_to_post.tag_id = tag.id WHERE tag_to_post.post_id = submission.id)"
+        }
+        Site::E621 if include_tags => {
+            "ARRAY(SELECT jsonb_array_elements_text(categories.tags) FROM jsonb_each(e621.data->'tags') categories(category, tags))"
+        }
+        _ => "null::text[]",
+    };
+
+    let categories = match site {
+        Site::E621 if include_tags && tag_categories => "e621.data->'tags'",
+        _ => "null::jsonb",
+    };
+
+    format!("{} tags, {} tag_categories", tags, categories)
+}
+
+/// Build the query to look up submissions for a site from the found hashes.
+fn site_query(site: Site, source: HashSource, filter: &SearchFilterOpts) -> String {
+    let SiteTable {
+        table,
+        hash_column,
+        id_column,
+        columns,
+        joins,
+    } = site_table(site);
+
+    let mut conditions = Vec::new();
+
+    let (hash_join, frame_column) = match source {
+        HashSource::Submission => {
+            conditions.push(format!(
+                "{}.{} IN (SELECT hashes.found_hash)",
+                table, hash_column
+            ));
+
+            (
+                format!(
+                    "JOIN {table} ON hashes.found_hash = {table}.{column}",
+                    table = table,
+                    column = hash_column
+                ),
+                "null::integer",
+            )
+        }
+        HashSource::Kind => (
+            format!(
+                "JOIN submission_hash
+                    ON submission_hash.hash = hashes.found_hash
+                        AND submission_hash.kind = $2
+                        AND submission_hash.site = '{site}'
+                JOIN {table} ON {table}.{id} = submission_hash.site_id",
+                site = site,
+                table = table,
+                id = id_column
+            ),
+            "null::integer",
+        ),
+        HashSource::Frame => (
+            format!(
+                "JOIN submission_frame
+                    ON submission_frame.hash = hashes.found_hash
+                        AND submission_frame.site = '{site}'
+                JOIN {table} ON {table}.{id} = submission_frame.site_id",
+                site = site,
+                table = table,
+                id = id_column
+            ),
+            "submission_frame.frame",
+        ),
+    };
+
+    let mut query = format!(
+        "SELECT {}, {}, {} frame FROM hashes {} {}",
+        columns,
+        tag_columns(site, filter.include_tags, filter.tag_categories),
+        frame_column,
+        hash_join,
+        joins
+    );
+
+    if !conditions.is_empty() {
+        query.push_str(" WHERE ");
+        query.push_str(&conditions.join(" AND "));
+    }
+
+    query
+}
+
+/// Convert each site's rating into a comparable level, which must match the
+/// parsing of `Rating` and `Rating::level`.
+const RATING_LEVEL: &str = "CASE
+        WHEN results.rating IN ('g', 's', 'general') THEN 0
+        WHEN results.rating IN ('m', 'q', 'mature') THEN 1
+        WHEN results.rating IN ('a', 'e', 'adult', 'explicit') THEN 2
+    END";
+
+/// Build the query to look up every submission matching the found hashes.
+///
+/// Animation keyframes are only stored as gradient hashes, so they are only
+/// searched for gradient hash lookups. Sites excluded by the filter are not
+/// queried, the remaining filters are bound as parameters after the hash
+/// search parameters, starting at `first_param`, followed by the cursor and
+/// limit.
+///
+/// Only the closest match of each submission for each searched hash is kept,
+/// which removes duplicates from several keyframes of an animation matching.
+fn build_image_query(kind: HashKind, filter: &SearchFilterOpts, first_param: usize) -> String {
+    let sources: &[HashSource] = if kind == HashKind::Gradient {
+        &[HashSource::Submission, HashSource::Frame]
+    } else {
+        &[HashSource::Kind]
+    };
+
+    let branches: Vec<String> = sources
+        .iter()
+        .flat_map(|source| {
+            [Site::FurAffinity, Site::E621, Site::Weasyl, Site::Twitter]
+                .iter()
+                .filter(|site| filter.includes_site(**site))
+                .map(move |site| site_query(*site, *source, filter))
+        })
+        .collect();
+    let branches = branches.join(" UNION ALL ");
+
+    let hashes = if kind == HashKind::Gradient {
+        "SELECT * FROM jsonb_to_recordset($1::jsonb)
+            AS hashes(searched_hash bigint, found_hash bigint, distance bigint)"
+    } else {
+        HASH_KIND_SEARCH
+    };
+
+    format!(
+        "WITH hashes AS ({hashes})
+        SELECT * FROM (
+            SELECT DISTINCT ON (results.site, results.id, results.searched_hash) *
+            FROM ({branches}) results
+            WHERE
+                (${rating}::smallint IS NULL OR {rating_level} <= ${rating})
+                AND (${after}::timestamptz IS NULL OR results.posted_at >= ${after})
+                AND (${before}::timestamptz IS NULL OR results.posted_at <= ${before})
+                AND (${deleted}::boolean OR NOT results.deleted)
+            ORDER BY results.site, results.id, results.searched_hash, results.distance
+        ) results
+        WHERE
+            ${distance}::bigint IS NULL
+            OR (results.distance, results.site, results.id, results.searched_hash)
+                > (${distance}, ${site}::text, ${id}::bigint, ${searched_hash}::bigint)
+        ORDER BY results.distance, results.site, results.id, results.searched_hash
+        LIMIT ${limit}",
+        hashes = hashes,
+        branches = branches,
+        rating_level = RATING_LEVEL,
+        rating = first_param,
+        after = first_param + 1,
+        before = first_param + 2,
+        deleted = first_param + 3,
+        distance = first_param + 4,
+        site = first_param + 5,
+        id = first_param + 6,
+        searched_hash = first_param + 7,
+        limit = first_param + 8
+    )
+}
+
+/// Get the hashes of a kind stored for a site's submissions, which are
+/// identified by their gradient hashes.
+#[tracing::instrument(skip(executor))]
+pub async fn submission_kind_hashes<'c, E>(
+    executor: E,
+    site: Site,
+    kind: HashKind,
+    hashes: &[i64],
+) -> Result<Vec<i64>, sqlx::Error>
+where
+    E: sqlx::Executor<'c, Database = sqlx::Postgres>,
+{
+    let SiteTable {
+        table,
+        hash_column,
+        id_column,
+        ..
+    } = site_table(site);
+
+    let query = format!(
+        "SELECT DISTINCT submission_hash.hash
+        FROM {table}
+        JOIN submission_hash
+            ON submission_hash.site = '{site}' AND submission_hash.site_id = {table}.{id}
+        WHERE {table}.{hash} = ANY($1) AND submission_hash.kind = $2
+        ORDER BY submission_hash.hash",
+        table = table,
+        site = site,
+        id = id_column,
+        hash = hash_column
+    );
+
+    sqlx::query_scalar(&query)
+        .bind(hashes)
+        .bind(kind.as_str())
+        .fetch_all(executor)
+        .await
+}
+
+/// An error searching for submissions matching hashes.
+#[derive(Debug)]
+pub enum ImageQueryError {
+    Database(sqlx::Error),
+    /// bkapi could not be searched for similar gradient hashes.
+    Bkapi(Box<dyn std::error::Error + Send + Sync>),
+}
+
+impl From<sqlx::Error> for ImageQueryError {
+    fn from(err: sqlx::Error) -> Self {
+        Self::Database(err)
+    }
+}
+
+/// Search for submissions matching any of the hashes.
+///
+/// When paginated, one more result than the limit is returned so callers can
+/// tell if there is another page with `SearchPageOpts::next_cursor`. Otherwise
+/// every result is returned.
 #[tracing::instrument(skip(pool, bkapi))]
 pub async fn image_query(
     pool: Pool,
     bkapi: bkapi_client::BKApiClient,
     hashes: Vec<i64>,
     distance: i64,
+    kind: HashKind,
+    filter: &SearchFilterOpts,
+    page: Option<&SearchPageOpts>,
+) -> Result<Vec<SearchResult>, ImageQueryError> {
+    if matches!(filter.sites, Some(ref sites) if sites.is_empty()) {
+        return Ok(Vec::new());
+    }
+
+    let hashes = if kind == HashKind::Gradient {
+        let found_hashes: Vec<HashSearch> = bkapi
+            .search_many(&hashes, distance as u64)
+            .await
+            .map_err(|err| ImageQueryError::Bkapi(err.into()))?
+            .into_iter()
+            .flat_map(|results| {
+                results
+                    .hashes
+                    .iter()
+                    .map(|hash| HashSearch {
+                        searched_hash: results.hash,
+                        found_hash: hash.hash,
+                        distance: hash.distance,
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+
+        FoundHashes::Gradient(found_hashes)
+    } else {
+        FoundHashes::Kind {
+            kind,
+            hashes,
+            distance,
+        }
+    };
+
+    let timer = IMAGE_QUERY_DURATION.start_timer();
+    let matches = lookup_found_hashes(&pool, hashes, filter, page).await?;
+    timer.stop_and_record();
+
+    Ok(matches)
+}
+
+/// The hashes to look up submissions for in an image search.
+enum FoundHashes {
+    /// Gradient hashes that were already found by bkapi.
+    Gradient(Vec<HashSearch>),
+    /// Hashes of another kind, which are found within distance by the query.
+    Kind {
+        kind: HashKind,
+        hashes: Vec<i64>,
+        distance: i64,
+    },
+}
+
+/// Look up every submission matching the hashes of an image search.
+async fn lookup_found_hashes<'c, E>(
+    executor: E,
+    hashes: FoundHashes,
+    filter: &SearchFilterOpts,
+    page: Option<&SearchPageOpts>,
+) -> Result<Vec<SearchResult>, sqlx::Error>
+where
+    E: sqlx::Executor<'c, Database = sqlx::Postgres>,
+{
+    let query = match hashes {
+        FoundHashes::Gradient(_) => build_image_query(HashKind::Gradient, filter, 2),
+        FoundHashes::Kind { kind, .. } => build_image_query(kind, filter, 4),
+    };
+
+    let query = match hashes {
+        FoundHashes::Gradient(found_hashes) => {
+            sqlx::query(&query).bind(serde_json::to_value(&found_hashes).unwrap())
+        }
+        FoundHashes::Kind {
+            kind,
+            hashes,
+            distance,
+        } => sqlx::query(&query)
+            .bind(hashes)
+            .bind(kind.as_str())
+            .bind(distance),
+    };
+
+    let query = query
+        .bind(filter.max_rating.as_ref().map(Rating::level))
+        .bind(filter.posted_after)
+        .bind(filter.posted_before)
+        .bind(filter.include_deleted);
+
+    let cursor = page.and_then(|page| page.cursor.as_ref());
+    let query = query
+        .bind(cursor.map(|cursor| cursor.distance))
+        .bind(cursor.map(|cursor| cursor.site.to_string()))
+        .bind(cursor.map(|cursor| cursor.site_id))
+        .bind(cursor.map(|cursor| cursor.searched_hash))
+        .bind(page.map(|page| page.limit() as i64 + 1));
+
+    query.map(search_result_from_row).fetch_all(executor).await
+}
+
+/// Build a query to select a site's submissions matching a condition instead
+/// of a hash.
+///
+/// The site's own hash is exposed as the found hash so the same columns can be
+/// selected as an image search. Conditions may reference the site's table and
+/// anything it is joined with. Tags are always included, as is the ID of the
+/// row in the site's table as `page_id`.
+pub fn site_lookup_query(site: Site, condition: &str) -> String {
+    let SiteTable {
+        table,
+        hash_column,
+        id_column,
+        columns,
+        joins,
+    } = site_table(site);
+
+    format!(
+        "SELECT {columns}, {tags}, null::integer frame, {table}.{id_column}::bigint page_id
+        FROM {table}
+        CROSS JOIN LATERAL (
+            SELECT
+                {table}.{hash_column} found_hash,
+                null::bigint searched_hash,
+                null::bigint distance
+        ) hashes
+        {joins}
+        WHERE {condition}",
+        columns = columns,
+        tags = tag_columns(site, true, true),
+        table = table,
+        hash_column = hash_column,
+        id_column = id_column,
+        joins = joins,
+        condition = condition
+    )
+}
+
+/// Build a condition excluding a site's deleted submissions unless the boolean
+/// parameter is set.
+pub fn deleted_condition(site: Site, param: usize) -> String {
+    match site {
+        // Tweets are never marked as deleted.
+        Site::Twitter => format!("(true OR ${}::boolean)", param),
+        _ => format!("(NOT {}.deleted OR ${})", site_table(site).table, param),
+    }
+}
+
+/// Build the query to page through an artist's submissions on a site, from
+/// the most recently indexed.
+fn artist_query(site: Site) -> String {
+    let SiteTable {
+        table, id_column, ..
+    } = site_table(site);
+
+    let condition = match site {
+        Site::FurAffinity => "lower(artist.name) = lower($1)",
+        Site::E621 => "e621.data->'tags'->'artist' ? $1",
+        Site::Weasyl => "lower(weasyl.data->>'owner_login') = lower($1)",
+        Site::Twitter => {
+            "tweet.twitter_user_id IN (SELECT twitter_id FROM twitter_user WHERE lower(data->>'screen_name') = lower($1))"
+        }
+    };
+
+    format!(
+        "{lookup}
+            AND ($3::bigint IS NULL OR {table}.{id_column} < $3)
+        ORDER BY {table}.{id_column} DESC
+        LIMIT $4",
+        lookup = site_lookup_query(
+            site,
+            &format!("({}) AND {}", condition, deleted_condition(site, 2))
+        ),
+        table = table,
+        id_column = id_column
+    )
+}
+
+/// Look up a page of an artist's submissions on a site, returning the cursor
+/// for the next page if there are more.
+#[tracing::instrument(skip(pool))]
+pub async fn artist_submissions(
+    pool: &Pool,
+    site: Site,
+    name: &str,
+    include_deleted: bool,
+    page: &IdPageOpts,
+) -> Result<(Vec<SearchResult>, Option<i64>), sqlx::Error> {
+    use sqlx::Row;
+
+    let name = match site {
+        Site::E621 => e621_artist_tag(name),
+        _ => name.to_string(),
+    };
+
+    let limit = page.limit() as usize;
+
+    let mut rows = sqlx::query(&artist_query(site))
+        .bind(name)
+        .bind(include_deleted)
+        .bind(page.cursor)
+        .bind(limit as i64 + 1)
+        .map(|row: sqlx::postgres::PgRow| {
+            (row.get::<i64, _>("page_id"), search_result_from_row(row))
+        })
+        .fetch_all(pool)
+        .await?;
+
+    let next_cursor = if rows.len() > limit {
+        rows.truncate(limit);
+        rows.last().map(|(page_id, _result)| *page_id)
+    } else {
+        None
+    };
+
+    let results = rows.into_iter().map(|(_page_id, result)| result).collect();
+
+    Ok((results, next_cursor))
+}
+
+/// e621 artists are tags, which are lowercase with underscores instead of
+/// spaces.
+fn e621_artist_tag(name: &str) -> String {
+    name.trim().to_lowercase().replace(' ', "_")
+}
+
+/// Build the query to look up submissions with an identical file. Twitter
+/// does not store file digests.
+fn build_sha256_query() -> String {
+    [
+        (Site::FurAffinity, "submission.file_sha256 = ANY($1)"),
+        (Site::E621, "e621.sha256 = ANY($1)"),
+        (Site::Weasyl, "weasyl.sha256 = ANY($1)"),
+    ]
+    .iter()
+    .map(|(site, condition)| site_lookup_query(*site, condition))
+    .collect::<Vec<_>>()
+    .join(" UNION ALL ")
+}
+
+/// Look up every submission with exactly a hash, including animations with a
+/// keyframe with the hash.
+#[tracing::instrument(skip(executor))]
+pub async fn hash_lookup<'c, E>(executor: E, hash: i64) -> Result<Vec<SearchResult>, sqlx::Error>
+where
+    E: sqlx::Executor<'c, Database = sqlx::Postgres>,
+{
+    let hashes = FoundHashes::Gradient(vec![HashSearch {
+        searched_hash: hash,
+        found_hash: hash,
+        distance: 0,
+    }]);
+
+    let filter = SearchFilterOpts {
+        include_deleted: true,
+        include_tags: true,
+        tag_categories: true,
+        ..Default::default()
+    };
+
+    lookup_found_hashes(executor, hashes, &filter, None).await
+}
+
+/// Look up every submission with a file matching one of the SHA-256 digests.
+#[tracing::instrument(skip(pool, digests), fields(digests = digests.len()))]
+pub async fn sha256_query(
+    pool: &Pool,
+    digests: Vec<Vec<u8>>,
 ) -> Result<Vec<SearchResult>, sqlx::Error> {
-    let found_hashes: Vec<HashSearch> = bkapi
-        .search_many(&hashes, distance as u64)
+    let query = build_sha256_query();
+
+    let timer = SHA256_QUERY_DURATION.start_timer();
+    let matches = sqlx::query(&query)
+        .bind(digests)
+        .map(search_result_from_row)
+        .fetch_all(pool)
+        .await?;
+    timer.stop_and_record();
+
+    Ok(matches)
+}
+
+/// Convert a row from one of the site queries into a search result.
+pub fn search_result_from_row(row: sqlx::postgres::PgRow) -> SearchResult {
+    use sqlx::Row;
+    use std::convert::TryFrom;
+
+    let site_info = match row.get::<Option<&str>, _>("site") {
+        Some("FurAffinity") => SiteInfo::FurAffinity {
+            file_id: row.get::<Option<i32>, _>("file_id").unwrap_or(-1),
+        },
+        Some("e621") => SiteInfo::E621 {
+            sources: row.get("sources"),
+        },
+        Some("Twitter") => SiteInfo::Twitter,
+        Some("Weasyl") => SiteInfo::Weasyl,
+        _ => panic!("Got unknown site"),
+    };
+
+    let id = row.get::<Option<i64>, _>("id").unwrap_or_default();
+
+    SearchResult {
+        site_id: id,
+        site_info: Some(site_info),
+        rating: row
+            .get::<Option<String>, _>("rating")
+            .and_then(|rating| rating.parse().ok()),
+        site_id_str: id.to_string(),
+        url: row.get::<Option<String>, _>("url").unwrap_or_default(),
+        posted_at: row.get("posted_at"),
+        tags: row.get("tags"),
+        tag_categories: row
+            .get::<Option<serde_json::Value>, _>("tag_categories")
+            .and_then(|categories| serde_json::from_value(categories).ok()),
+        sha256: row.get::<Option<Vec<u8>>, _>("sha256").map(hex::encode),
+        hash: row.get("hash"),
+        distance: row
+            .get::<Option<i64>, _>("distance")
+            .and_then(|distance| u64::try_from(distance).ok()),
+        artists: row.get("artists"),
+        filename: row.get::<Option<String>, _>("filename").unwrap_or_default(),
+        searched_hash: row.get("searched_hash"),
+        deleted: row.get("deleted"),
+        frame: row
+            .get::<Option<i32>, _>("frame")
+            .and_then(|frame| u32::try_from(frame).ok()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SITES: [Site; 4] = [Site::FurAffinity, Site::E621, Site::Weasyl, Site::Twitter];
+
+    async fn test_pool() -> Pool {
+        sqlx::postgres::PgPoolOptions::new()
+            .max_connections(1)
+            .connect(&std::env::var("DATABASE_URL").expect("Missing DATABASE_URL"))
+            .await
+            .expect("Unable to connect to database")
+    }
+
+    /// A hash unique to each site, source, and kind, far from any real hash.
+    fn fixture_hash(site: Site, source: HashSource, kind: HashKind) -> i64 {
+        let site = SITES.iter().position(|other| *other == site).unwrap() as i64;
+        let kind = HashKind::ALL
+            .iter()
+            .position(|other| *other == kind)
+            .unwrap() as i64;
+        let source = match source {
+            HashSource::Submission => 0,
+            HashSource::Kind => 1,
+            HashSource::Frame => 2,
+        };
+
+        0x7a5a_0000_0000_0000 | site << 16 | kind << 8 | source
+    }
+
+    /// The ID of the row each site's fixture is stored with, and the ID it is
+    /// returned with in search results.
+    fn fixture_ids(site: Site) -> (i64, i64) {
+        match site {
+            Site::FurAffinity => (900_000_001, 900_000_001),
+            Site::E621 => (900_000_002, 900_000_002),
+            Site::Weasyl => (900_000_003, 900_000_003),
+            Site::Twitter => (900_000_004, 900_000_005),
+        }
+    }
+
+    /// Insert a submission for each site, with every kind of hash and a
+    /// keyframe.
+    async fn insert_fixtures(tx: &mut sqlx::Transaction<'_, sqlx::Postgres>) {
+        sqlx::query("INSERT INTO artist (id, name) VALUES ($1, 'fixture-artist')")
+            .bind(fixture_ids(Site::FurAffinity).0 as i32)
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+
+        sqlx::query(
+            "INSERT INTO submission (id, artist_id, hash_int, url, filename, rating, posted_at, file_id)
+            VALUES ($1, $1, $2, 'https://example.com/fa.png', 'fa.png', 'g', '2020-01-01T00:00:00Z', 1)",
+        )
+        .bind(fixture_ids(Site::FurAffinity).0 as i32)
+        .bind(fixture_hash(Site::FurAffinity, HashSource::Submission, HashKind::Gradient))
+        .execute(&mut *tx)
         .await
-        .unwrap()
-        .into_iter()
-        .flat_map(|results| {
-            results
-                .hashes
+        .unwrap();
+
+        sqlx::query("INSERT INTO e621 (id, hash, data) VALUES ($1, $2, $3)")
+            .bind(fixture_ids(Site::E621).0 as i32)
+            .bind(fixture_hash(
+                Site::E621,
+                HashSource::Submission,
+                HashKind::Gradient,
+            ))
+            .bind(serde_json::json!({
+                "file": {"url": "https://example.com/e621.png", "md5": "fixture", "ext": "png"},
+                "tags": {"artist": ["fixture-artist"], "general": ["fox"]},
+                "sources": [],
+                "rating": "s",
+                "created_at": "2020-01-01T00:00:00Z",
+            }))
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+
+        sqlx::query("INSERT INTO weasyl (id, hash, data) VALUES ($1, $2, $3)")
+            .bind(fixture_ids(Site::Weasyl).0 as i32)
+            .bind(fixture_hash(
+                Site::Weasyl,
+                HashSource::Submission,
+                HashKind::Gradient,
+            ))
+            .bind(serde_json::json!({
+                "link": "https://example.com/weasyl",
+                "owner_login": "fixture-artist",
+                "rating": "general",
+                "posted_at": "2020-01-01T00:00:00Z",
+            }))
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+
+        let (media_id, tweet_id) = fixture_ids(Site::Twitter);
+        sqlx::query("INSERT INTO twitter_user (twitter_id) VALUES ($1)")
+            .bind(tweet_id)
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+        sqlx::query("INSERT INTO tweet (id, twitter_user_id, data) VALUES ($1, $1, $2)")
+            .bind(tweet_id)
+            .bind(serde_json::json!({
+                "user": {"screen_name": "fixture-artist"},
+                "possibly_sensitive": false,
+                "created_at": "Wed Jan 01 00:00:00 +0000 2020",
+            }))
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+        sqlx::query("INSERT INTO tweet_media (media_id, tweet_id, hash, url) VALUES ($1, $2, $3, 'https://example.com/tweet.png')")
+            .bind(media_id)
+            .bind(tweet_id)
+            .bind(fixture_hash(Site::Twitter, HashSource::Submission, HashKind::Gradient))
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+
+        for site in SITES {
+            let (site_id, _) = fixture_ids(site);
+
+            for kind in HashKind::ALL
+                .iter()
+                .filter(|kind| **kind != HashKind::Gradient)
+            {
+                sqlx::query(
+                    "INSERT INTO submission_hash (site, site_id, kind, hash) VALUES ($1, $2, $3, $4)",
+                )
+                .bind(site.to_string())
+                .bind(site_id)
+                .bind(kind.as_str())
+                .bind(fixture_hash(site, HashSource::Kind, *kind))
+                .execute(&mut *tx)
+                .await
+                .unwrap();
+            }
+
+            sqlx::query(
+                "INSERT INTO submission_frame (site, site_id, frame, hash) VALUES ($1, $2, 3, $3)",
+            )
+            .bind(site.to_string())
+            .bind(site_id)
+            .bind(fixture_hash(site, HashSource::Frame, HashKind::Gradient))
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+        }
+    }
+
+    /// Filters that include every fixture while binding every parameter.
+    fn every_filter() -> (SearchFilterOpts, SearchPageOpts) {
+        let filter = SearchFilterOpts {
+            sites: None,
+            max_rating: Some(fuzzysearch_common::types::Rating::Adult),
+            posted_after: Some("2019-01-01T00:00:00Z".parse().unwrap()),
+            posted_before: Some("2021-01-01T00:00:00Z".parse().unwrap()),
+            include_deleted: true,
+            include_tags: true,
+            tag_categories: true,
+        };
+
+        let page = SearchPageOpts {
+            limit: Some(10),
+            cursor: Some(SearchCursor {
+                distance: -1,
+                site: Site::E621,
+                site_id: 0,
+                searched_hash: 0,
+            }),
+        };
+
+        (filter, page)
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a migrated database at DATABASE_URL"]
+    async fn image_query_finds_every_kind_site_and_source() {
+        let pool = test_pool().await;
+        let mut tx = pool.begin().await.unwrap();
+        insert_fixtures(&mut tx).await;
+
+        for kind in HashKind::ALL {
+            let sources: &[HashSource] = if kind == HashKind::Gradient {
+                &[HashSource::Submission, HashSource::Frame]
+            } else {
+                &[HashSource::Kind]
+            };
+
+            for source in sources {
+                for site in SITES {
+                    let hash = fixture_hash(site, *source, kind);
+                    let hashes = || {
+                        if kind == HashKind::Gradient {
+                            FoundHashes::Gradient(vec![HashSearch {
+                                searched_hash: hash,
+                                found_hash: hash,
+                                distance: 0,
+                            }])
+                        } else {
+                            FoundHashes::Kind {
+                                kind,
+                                hashes: vec![hash],
+                                distance: 0,
+                            }
+                        }
+                    };
+
+                    let site_filter = SearchFilterOpts {
+                        sites: Some(vec![site]),
+                        ..Default::default()
+                    };
+                    let (filter, page) = every_filter();
+
+                    for (filter, page) in [(&site_filter, None), (&filter, Some(&page))] {
+                        let results = lookup_found_hashes(&mut tx, hashes(), filter, page)
+                            .await
+                            .unwrap_or_else(|err| {
+                                panic!("{:?} {:?} {:?} failed: {:?}", kind, source, site, err)
+                            });
+
+                        let (_, expected_id) = fixture_ids(site);
+                        let expected_frame = if *source == HashSource::Frame {
+                            Some(3)
+                        } else {
+                            None
+                        };
+
+                        assert!(
+                            results.iter().any(|result| {
+                                result.site_id == expected_id
+                                    && result.frame == expected_frame
+                                    && result.hash == Some(hash)
+                                    && result.site_info.as_ref().map(|info| info.site())
+                                        == Some(site)
+                            }),
+                            "{:?} {:?} {:?} was not found: {:?}",
+                            kind,
+                            source,
+                            site,
+                            results
+                        );
+                    }
+                }
+            }
+        }
+
+        tx.rollback().await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a migrated database at DATABASE_URL"]
+    async fn image_query_excludes_filtered_submissions() {
+        let pool = test_pool().await;
+        let mut tx = pool.begin().await.unwrap();
+        insert_fixtures(&mut tx).await;
+
+        sqlx::query("UPDATE e621 SET deleted = true WHERE id = $1")
+            .bind(fixture_ids(Site::E621).0 as i32)
+            .execute(&mut tx)
+            .await
+            .unwrap();
+
+        for kind in HashKind::ALL {
+            let hashes = SITES
                 .iter()
-                .map(|hash| HashSearch {
-                    searched_hash: results.hash,
-                    found_hash: hash.hash,
-                    distance: hash.distance,
+                .map(|site| {
+                    let source = if kind == HashKind::Gradient {
+                        HashSource::Submission
+                    } else {
+                        HashSource::Kind
+                    };
+
+                    fixture_hash(*site, source, kind)
                 })
-                .collect::<Vec<_>>()
-        })
-        .collect();
+                .collect::<Vec<_>>();
 
-    let timer = IMAGE_QUERY_DURATION.start_timer();
-    let matches = sqlx::query!(
-        r#"WITH hashes AS (
-            SELECT * FROM jsonb_to_recordset($1::jsonb)
-                AS hashes(searched_hash bigint, found_hash bigint, distance bigint)
+            let hashes = if kind == HashKind::Gradient {
+                FoundHashes::Gradient(
+                    hashes
+                        .into_iter()
+                        .map(|hash| HashSearch {
+                            searched_hash: hash,
+                            found_hash: hash,
+                            distance: 0,
+                        })
+                        .collect(),
+                )
+            } else {
+                FoundHashes::Kind {
+                    kind,
+                    hashes,
+                    distance: 0,
+                }
+            };
+
+            let filter = SearchFilterOpts {
+                sites: Some(vec![Site::E621, Site::Weasyl]),
+                posted_before: Some("2019-01-01T00:00:00Z".parse().unwrap()),
+                ..Default::default()
+            };
+
+            let results = lookup_found_hashes(&mut tx, hashes, &filter, None)
+                .await
+                .unwrap();
+            assert!(results.is_empty(), "{:?} found {:?}", kind, results);
+        }
+
+        tx.rollback().await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a migrated database at DATABASE_URL"]
+    async fn hash_lookup_finds_submissions_and_frames() {
+        let pool = test_pool().await;
+        let mut tx = pool.begin().await.unwrap();
+        insert_fixtures(&mut tx).await;
+
+        for source in [HashSource::Submission, HashSource::Frame] {
+            for site in SITES {
+                let hash = fixture_hash(site, source, HashKind::Gradient);
+                let results = hash_lookup(&mut tx, hash).await.unwrap();
+
+                let (_, expected_id) = fixture_ids(site);
+                let expected_frame = if source == HashSource::Frame {
+                    Some(3)
+                } else {
+                    None
+                };
+
+                assert!(
+                    matches!(
+                        results.as_slice(),
+                        [result] if result.site_id == expected_id
+                            && result.frame == expected_frame
+                            && result.hash == Some(hash)
+                    ),
+                    "{:?} {:?} was not found: {:?}",
+                    source,
+                    site,
+                    results
+                );
+            }
+        }
+
+        tx.rollback().await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a migrated database at DATABASE_URL"]
+    async fn registering_again_keeps_unverified_password() {
+        let pool = test_pool().await;
+        let mut tx = pool.begin().await.unwrap();
+
+        let email = "fixture-account@example.com";
+        let first = create_account(&mut tx, email, "first-password", "first-verifier")
+            .await
+            .unwrap();
+        let second = create_account(&mut tx, email, "second-password", "second-verifier")
+            .await
+            .unwrap();
+        assert!(first.is_some());
+        assert_eq!(first, second);
+
+        let (password, email_verifier): (String, Option<String>) =
+            sqlx::query_as("SELECT password, email_verifier FROM account WHERE id = $1")
+                .bind(first)
+                .fetch_one(&mut tx)
+                .await
+                .unwrap();
+        assert_eq!(password, "first-password");
+        assert_eq!(email_verifier.as_deref(), Some("second-verifier"));
+
+        tx.rollback().await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a migrated database at DATABASE_URL"]
+    async fn usage_includes_rolled_up_days() {
+        let pool = test_pool().await;
+        let mut tx = pool.begin().await.unwrap();
+
+        let key_id: i32 = sqlx::query_scalar(
+            "WITH account AS (
+                INSERT INTO account (email, password) VALUES ('fixture-usage@example.com', '')
+                RETURNING id
+            )
+            INSERT INTO api_key (user_id, key) SELECT id, 'fixture-usage-key' FROM account
+            RETURNING id",
+        )
+        .fetch_one(&mut tx)
+        .await
+        .unwrap();
+
+        // 2000-01-02, far enough in the past for nothing else to be in it.
+        let day = 946_771_200;
+        for (time_window, count) in [(day + 60, 2), (day + 60 * 60, 3), (day + 86_400 + 60, 5)] {
+            sqlx::query(
+                "INSERT INTO rate_limit (api_key_id, time_window, group_name, count)
+                VALUES ($1, $2, 'image', $3)",
+            )
+            .bind(key_id)
+            .bind(time_window)
+            .bind(count as i16)
+            .execute(&mut tx)
+            .await
+            .unwrap();
+        }
+
+        sqlx::query(
+            "INSERT INTO rate_limit_daily (api_key_id, day, group_name, count)
+            VALUES ($1, '2000-01-02', 'image', 1)",
         )
-        SELECT
-            'FurAffinity' site,
-            submission.id,
-            submission.hash_int hash,
-            submission.url,
-            submission.filename,
-            ARRAY(SELECT artist.name) artists,
-            submission.file_id,
-            null sources,
-            submission.rating,
-            submission.posted_at,
-            hashes.searched_hash,
-            hashes.distance,
-            submission.file_sha256 sha256
-        FROM hashes
-        JOIN submission ON hashes.found_hash = submission.hash_int
-        JOIN artist ON submission.artist_id = artist.id
-        WHERE hash_int IN (SELECT hashes.found_hash)
-        UNION ALL
-        SELECT
-            'e621' site,
-            e621.id,
-            e621.hash,
-            e621.data->'file'->>'url' url,
-            (e621.data->'file'->>'md5') || '.' || (e621.data->'file'->>'ext') filename,
-            ARRAY(SELECT jsonb_array_elements_text(e621.data->'tags'->'artist')) artists,
-            null file_id,
-            ARRAY(SELECT jsonb_array_elements_text(e621.data->'sources')) sources,
-            e621.data->>'rating' rating,
-            to_timestamp(data->>'created_at', 'YYYY-MM-DD"T"HH24:MI:SS"Z"') posted_at,
-            hashes.searched_hash,
-            hashes.distance,
-            e621.sha256
-        FROM hashes
-        JOIN e621 ON hashes.found_hash = e621.hash
-        WHERE e621.hash IN (SELECT hashes.found_hash)
-        UNION ALL
-        SELECT
-            'Weasyl' site,
-            weasyl.id,
-            weasyl.hash,
-            weasyl.data->>'link' url,
-            null filename,
-            ARRAY(SELECT weasyl.data->>'owner_login') artists,
-            null file_id,
-            null sources,
-            weasyl.data->>'rating' rating,
-            to_timestamp(data->>'posted_at', 'YYYY-MM-DD"T"HH24:MI:SS"Z"') posted_at,
-            hashes.searched_hash,
-            hashes.distance,
-            weasyl.sha256
-        FROM hashes
-        JOIN weasyl ON hashes.found_hash = weasyl.hash
-        WHERE weasyl.hash IN (SELECT hashes.found_hash)
-        UNION ALL
-        SELECT
-            'Twitter' site,
-            tweet.id,
-            tweet_media.hash,
-            tweet_media.url,
-            null filename,
-            ARRAY(SELECT tweet.data->'user'->>'screen_name') artists,
-            null file_id,
-            null sources,
-            CASE
-                WHEN (tweet.data->'possibly_sensitive')::boolean IS true THEN 'adult'
-                WHEN (tweet.data->'possibly_sensitive')::boolean IS false THEN 'general'
-            END rating,
-            to_timestamp(tweet.data->>'created_at', 'DY Mon DD HH24:MI:SS +0000 YYYY') posted_at,
-            hashes.searched_hash,
-            hashes.distance,
-            null sha256
-        FROM hashes
-        JOIN tweet_media ON hashes.found_hash = tweet_media.hash
-        JOIN tweet ON tweet_media.tweet_id = tweet.id
-        WHERE tweet_media.hash IN (SELECT hashes.found_hash)"#,
-        serde_json::to_value(&found_hashes).unwrap()
-    )
-    .map(|row| {
-        use std::convert::TryFrom;
-
-        let site_info = match row.site.as_deref() {
-            Some("FurAffinity") => SiteInfo::FurAffinity {
-                file_id: row.file_id.unwrap_or(-1),
-            },
-            Some("e621") => SiteInfo::E621 {
-                sources: row.sources,
-            },
-            Some("Twitter") => SiteInfo::Twitter,
-            Some("Weasyl") => SiteInfo::Weasyl,
-            _ => panic!("Got unknown site"),
+        .bind(key_id)
+        .execute(&mut tx)
+        .await
+        .unwrap();
+
+        let updated = rollup_rate_limits(&mut tx, day + 86_400).await.unwrap();
+        assert_eq!(updated, 1);
+
+        let time = |timestamp| chrono::TimeZone::timestamp_opt(&chrono::Utc, timestamp, 0).unwrap();
+        let since = time(day - 86_400);
+        let counts = |usage: Vec<GroupUsage>| {
+            usage
+                .into_iter()
+                .map(|usage| (usage.group, usage.time, usage.count))
+                .collect::<Vec<_>>()
         };
 
-        SearchResult {
-            site_id: row.id.unwrap_or_default(),
-            site_info: Some(site_info),
-            rating: row.rating.and_then(|rating| rating.parse().ok()),
-            site_id_str: row.id.unwrap_or_default().to_string(),
-            url: row.url.unwrap_or_default(),
-            posted_at: row.posted_at,
-            tags: None,
-            sha256: row.sha256.map(hex::encode),
-            hash: row.hash,
-            distance: row
-                .distance
-                .and_then(|distance| u64::try_from(distance).ok()),
-            artists: row.artists,
-            filename: row.filename.unwrap_or_default(),
-            searched_hash: row.searched_hash,
-        }
-    })
-    .fetch_all(&pool)
-    .await?;
-    timer.stop_and_record();
+        // Rolled up windows are added to the existing daily total, and the
+        // remaining windows are counted alongside them.
+        let usage = api_key_usage(&mut tx, key_id, UsageBucket::Day, since)
+            .await
+            .unwrap();
+        assert_eq!(
+            counts(usage),
+            vec![
+                ("image".to_string(), time(day), 6),
+                ("image".to_string(), time(day + 86_400), 5),
+            ]
+        );
 
-    Ok(matches)
+        // Daily totals are only included when aggregating by day.
+        let usage = api_key_usage(&mut tx, key_id, UsageBucket::Hour, since)
+            .await
+            .unwrap();
+        assert_eq!(
+            counts(usage),
+            vec![("image".to_string(), time(day + 86_400), 5)]
+        );
+
+        tx.rollback().await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a migrated database at DATABASE_URL"]
+    async fn submission_kind_hashes_finds_each_kind() {
+        let pool = test_pool().await;
+        let mut tx = pool.begin().await.unwrap();
+        insert_fixtures(&mut tx).await;
+
+        for site in SITES {
+            let hash = fixture_hash(site, HashSource::Submission, HashKind::Gradient);
+
+            for kind in HashKind::ALL
+                .iter()
+                .filter(|kind| **kind != HashKind::Gradient)
+            {
+                let hashes = submission_kind_hashes(&mut tx, site, *kind, &[hash])
+                    .await
+                    .unwrap();
+                assert_eq!(
+                    hashes,
+                    vec![fixture_hash(site, HashSource::Kind, *kind)],
+                    "{:?} {:?}",
+                    site,
+                    kind
+                );
+            }
+
+            // Hashes of other sites' submissions are not included.
+            let other = SITES.iter().find(|other| **other != site).unwrap();
+            let hash = fixture_hash(*other, HashSource::Submission, HashKind::Gradient);
+            let hashes = submission_kind_hashes(&mut tx, site, HashKind::BlockMean, &[hash])
+                .await
+                .unwrap();
+            assert!(hashes.is_empty(), "{:?} found {:?}", site, hashes);
+        }
+
+        tx.rollback().await.unwrap();
+    }
 }
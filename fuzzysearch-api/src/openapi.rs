@@ -0,0 +1,286 @@
+use utoipa::{
+    openapi::security::{ApiKey, ApiKeyValue, Http, HttpAuthScheme, SecurityScheme},
+    Modify, OpenApi, ToSchema,
+};
+
+use crate::handlers;
+use crate::types::*;
+use fuzzysearch_common::types::{HashKind, Rating, SearchResult, Site, SiteInfo};
+
+/// The OpenAPI document for the search and account APIs, generated from the
+/// same types the filters and handlers use.
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "FuzzySearch API"),
+    paths(
+        handlers::search_image,
+        handlers::search_image_batch,
+        handlers::search_video,
+        handlers::search_hashes,
+        handlers::search_sha256,
+        handlers::search_sha256_file,
+        handlers::search_file,
+        handlers::check_handle,
+        handlers::search_image_by_url,
+        handlers::search_duplicates,
+        handlers::subscribe_hashes,
+        handlers::artist_submissions,
+        handlers::related_artists,
+        handlers::api_key_usage,
+        handlers::register_account,
+        handlers::verify_account,
+        handlers::get_account,
+        handlers::list_api_keys,
+        handlers::create_api_key,
+        handlers::rename_api_key,
+        handlers::rotate_api_key,
+        handlers::revoke_api_key,
+        handlers::update_api_key_limits,
+        handlers::list_hash_watches,
+        handlers::watch_hash,
+        handlers::unwatch_hash,
+        handlers::list_webhooks,
+        handlers::create_webhook,
+        handlers::update_webhook_filters,
+        handlers::rotate_webhook_secret,
+        handlers::enable_webhook,
+        handlers::list_webhook_deliveries,
+        handlers::redeliver_webhook,
+        handlers::delete_webhook,
+    ),
+    components(schemas(
+        SearchResult,
+        SiteInfo,
+        Site,
+        Rating,
+        HashKind,
+        ImageSearchType,
+        ImageSimilarity,
+        BatchImageSimilarity,
+        FrameSimilarity,
+        ErrorMessage,
+        UsageBucket,
+        GroupUsage,
+        GroupRemaining,
+        KeyUsage,
+        HandleInfo,
+        TwitterBackfill,
+        RelatedArtist,
+        DuplicateCluster,
+        DuplicateMatch,
+        ImageForm,
+        BatchImageForm,
+        VideoForm,
+        FileForm,
+        AccountInfo,
+        RegisterAccount,
+        VerifyAccount,
+        ApiKeyInfo,
+        ApiKeyName,
+        ApiKeyLimits,
+        HashWatch,
+        NewHashWatch,
+        WebhookInfo,
+        WebhookFilters,
+        NewWebhook,
+        WebhookDelivery,
+    )),
+    modifiers(&SecuritySchemes),
+)]
+pub struct ApiDoc;
+
+/// Adds the `x-api-key` header used to authenticate searches and the HTTP
+/// basic credentials used to authenticate accounts.
+struct SecuritySchemes;
+
+impl Modify for SecuritySchemes {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        if let Some(components) = openapi.components.as_mut() {
+            components.add_security_scheme(
+                "api_key",
+                SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::new("x-api-key"))),
+            );
+            components.add_security_scheme(
+                "basic_auth",
+                SecurityScheme::Http(Http::new(HttpAuthScheme::Basic)),
+            );
+        }
+    }
+}
+
+// The multipart forms accepted by the API, which are read part by part
+// instead of being deserialized so only exist to be documented.
+
+/// An image to search.
+#[allow(dead_code)]
+#[derive(ToSchema)]
+pub struct ImageForm {
+    #[schema(value_type = String, format = Binary)]
+    image: Vec<u8>,
+}
+
+/// Images to search, each in a part named `image`.
+#[allow(dead_code)]
+#[derive(ToSchema)]
+pub struct BatchImageForm {
+    #[schema(value_type = Vec<String>, format = Binary)]
+    image: Vec<Vec<u8>>,
+}
+
+/// An animated GIF or APNG to search by its keyframes.
+#[allow(dead_code)]
+#[derive(ToSchema)]
+pub struct VideoForm {
+    #[schema(value_type = String, format = Binary)]
+    video: Vec<u8>,
+}
+
+/// A file to look up by its SHA-256 digest.
+#[allow(dead_code)]
+#[derive(ToSchema)]
+pub struct FileForm {
+    #[schema(value_type = String, format = Binary)]
+    file: Vec<u8>,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use utoipa::openapi::path::{Parameter, ParameterIn, PathItemType};
+    use warp::{
+        http::{Method, StatusCode},
+        Filter,
+    };
+
+    use super::*;
+    use crate::filters::ROUTES;
+
+    fn path_item_type(method: &Method) -> PathItemType {
+        match *method {
+            Method::GET => PathItemType::Get,
+            Method::POST => PathItemType::Post,
+            Method::DELETE => PathItemType::Delete,
+            _ => unreachable!("no routes use other methods"),
+        }
+    }
+
+    fn names<'a>(
+        params: impl IntoIterator<Item = &'a Parameter>,
+        location: ParameterIn,
+    ) -> BTreeSet<String> {
+        params
+            .into_iter()
+            .filter(|param| param.parameter_in == location)
+            .map(|param| param.name.clone())
+            .collect()
+    }
+
+    #[test]
+    fn documents_every_route() {
+        let doc = ApiDoc::openapi();
+
+        for route in ROUTES {
+            let name = format!("{} {}", route.method, route.path);
+
+            let operation = doc
+                .paths
+                .paths
+                .get(route.path)
+                .and_then(|item| item.operations.get(&path_item_type(&route.method)))
+                .unwrap_or_else(|| panic!("{} is not documented", name));
+            let documented = operation.parameters.iter().flatten();
+
+            let expected: BTreeSet<String> = route
+                .params
+                .iter()
+                .flat_map(|params| params())
+                .map(|param| param.name)
+                .collect();
+            assert_eq!(
+                names(documented.clone(), ParameterIn::Query),
+                expected,
+                "{} has different query parameters",
+                name
+            );
+
+            let expected: BTreeSet<String> = route
+                .path
+                .split('/')
+                .filter_map(|segment| segment.strip_prefix('{')?.strip_suffix('}'))
+                .map(str::to_string)
+                .collect();
+            assert_eq!(
+                names(documented, ParameterIn::Path),
+                expected,
+                "{} has different path parameters",
+                name
+            );
+        }
+
+        let documented = doc
+            .paths
+            .paths
+            .values()
+            .map(|item| item.operations.len())
+            .sum::<usize>();
+        assert_eq!(
+            documented,
+            ROUTES.len(),
+            "documented operations are missing from the routes"
+        );
+    }
+
+    #[tokio::test]
+    async fn routes_are_served() {
+        // Requests are made without credentials, so they are rejected before
+        // needing the database. Unhandled rejections are only not found or
+        // method not allowed if no route matched.
+        let db = sqlx::postgres::PgPoolOptions::new()
+            .connect_timeout(std::time::Duration::from_secs(1))
+            .connect_lazy("postgres://127.0.0.1:1/fuzzysearch")
+            .unwrap();
+        let endpoints = crate::Endpoints {
+            hash_input: "http://127.0.0.1:1".to_string(),
+            hash_input_video: None,
+            bkapi: "http://127.0.0.1:1".to_string(),
+        };
+
+        let api = crate::filters::search(
+            db.clone(),
+            crate::limiter::from_config("postgres", db.clone()),
+            bkapi_client::BKApiClient::new(&endpoints.bkapi),
+            endpoints,
+            crate::subscriptions::listen(db.clone()),
+        )
+        .or(crate::filters::accounts(db, None, None));
+
+        for route in ROUTES {
+            let uri = route
+                .path
+                .split('/')
+                .map(|segment| {
+                    if segment.starts_with('{') {
+                        "1"
+                    } else {
+                        segment
+                    }
+                })
+                .collect::<Vec<_>>()
+                .join("/");
+
+            let resp = warp::test::request()
+                .method(route.method.as_str())
+                .path(&uri)
+                .reply(&api)
+                .await;
+
+            assert!(
+                ![StatusCode::NOT_FOUND, StatusCode::METHOD_NOT_ALLOWED].contains(&resp.status()),
+                "{} {} is not served",
+                route.method,
+                route.path
+            );
+        }
+    }
+}
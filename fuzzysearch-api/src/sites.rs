@@ -0,0 +1,246 @@
+use reqwest::Url;
+
+use fuzzysearch_common::types::Site;
+
+use crate::types::FileSearchOpts;
+
+/// Recognize a URL to a submission or its media on a site that is indexed,
+/// returning how to look it up.
+pub fn known_url_opts(url: &str) -> Option<FileSearchOpts> {
+    let parsed = Url::parse(url).ok()?;
+
+    if !matches!(parsed.scheme(), "http" | "https") {
+        return None;
+    }
+
+    let host = parsed.host_str()?.to_ascii_lowercase();
+    let host = host.strip_prefix("www.").unwrap_or(&host);
+    let segments: Vec<&str> = parsed
+        .path_segments()?
+        .filter(|segment| !segment.is_empty())
+        .collect();
+
+    match host {
+        "furaffinity.net" | "sfw.furaffinity.net" => furaffinity_post(&segments),
+        "d.furaffinity.net" | "d.facdn.net" | "d2.facdn.net" => furaffinity_media(&segments),
+        "e621.net" | "e926.net" => e621_post(&segments),
+        "static1.e621.net" | "static1.e926.net" => Some(file_url(Site::E621, url)),
+        "weasyl.com" => weasyl_post(&segments),
+        "cdn.weasyl.com" => Some(file_url(Site::Weasyl, url)),
+        "twitter.com" | "mobile.twitter.com" | "x.com" => twitter_post(&segments),
+        "pbs.twimg.com" => twitter_media(&parsed, &segments),
+        _ => None,
+    }
+}
+
+fn site_id(site: Site, id: &str) -> Option<FileSearchOpts> {
+    Some(FileSearchOpts {
+        site: Some(site),
+        site_id: Some(id.parse().ok()?),
+        ..Default::default()
+    })
+}
+
+fn file_url(site: Site, url: &str) -> FileSearchOpts {
+    FileSearchOpts {
+        site: Some(site),
+        url: Some(url.to_string()),
+        ..Default::default()
+    }
+}
+
+/// Submission pages, like `https://www.furaffinity.net/view/<id>/`.
+fn furaffinity_post(segments: &[&str]) -> Option<FileSearchOpts> {
+    match segments {
+        ["view", id] | ["full", id] => site_id(Site::FurAffinity, id),
+        _ => None,
+    }
+}
+
+/// Files, like `https://d.furaffinity.net/art/<artist>/<time>/<filename>`.
+fn furaffinity_media(segments: &[&str]) -> Option<FileSearchOpts> {
+    match segments {
+        ["art", _artist, .., filename] => Some(FileSearchOpts {
+            site: Some(Site::FurAffinity),
+            name: Some(filename.to_string()),
+            ..Default::default()
+        }),
+        _ => None,
+    }
+}
+
+/// Posts, like `https://e621.net/posts/<id>` or the older
+/// `https://e621.net/post/show/<id>`.
+fn e621_post(segments: &[&str]) -> Option<FileSearchOpts> {
+    match segments {
+        ["posts", id] | ["post", "show", id, ..] => site_id(Site::E621, id),
+        _ => None,
+    }
+}
+
+/// Submissions, like `https://www.weasyl.com/~<user>/submissions/<id>/<title>`
+/// or `https://www.weasyl.com/submission/<id>`.
+fn weasyl_post(segments: &[&str]) -> Option<FileSearchOpts> {
+    match segments {
+        [user, "submissions", id, ..] if user.starts_with('~') => site_id(Site::Weasyl, id),
+        ["submission", id, ..] => site_id(Site::Weasyl, id),
+        _ => None,
+    }
+}
+
+/// Tweets, like `https://twitter.com/<user>/status/<id>`.
+fn twitter_post(segments: &[&str]) -> Option<FileSearchOpts> {
+    match segments {
+        [_user, "status", id, ..] => site_id(Site::Twitter, id),
+        _ => None,
+    }
+}
+
+/// Media, which is stored like `https://pbs.twimg.com/media/<name>.<ext>:large`
+/// but may be linked with another size or with the format in the query, like
+/// `https://pbs.twimg.com/media/<name>?format=<ext>&name=small`.
+fn twitter_media(url: &Url, segments: &[&str]) -> Option<FileSearchOpts> {
+    let filename = match segments {
+        ["media", filename] => filename.split(':').next()?,
+        _ => return None,
+    };
+
+    let filename = if filename.contains('.') {
+        filename.to_string()
+    } else {
+        let (_, format) = url.query_pairs().find(|(key, _value)| key == "format")?;
+        format!("{}.{}", filename, format)
+    };
+
+    Some(file_url(
+        Site::Twitter,
+        &format!("https://pbs.twimg.com/media/{}:large", filename),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// How a URL is expected to be looked up.
+    #[derive(Debug, PartialEq)]
+    enum Lookup {
+        SiteId(Site, i64),
+        Name(Site, String),
+        Url(Site, String),
+    }
+
+    fn lookup(opts: FileSearchOpts) -> Lookup {
+        let site = opts.site.expect("site was not set");
+
+        match (opts.site_id, opts.name, opts.url) {
+            (Some(site_id), None, None) => Lookup::SiteId(site, site_id),
+            (None, Some(name), None) => Lookup::Name(site, name),
+            (None, None, Some(url)) => Lookup::Url(site, url),
+            other => panic!("unexpected lookup: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn recognizes_known_urls() {
+        use Lookup::*;
+
+        let e621_file = "https://static1.e621.net/data/ab/cd/abcdef0123456789abcdef0123456789.png";
+
+        for (url, expected) in [
+            (
+                "https://www.furaffinity.net/view/12345/",
+                SiteId(Site::FurAffinity, 12345),
+            ),
+            (
+                "https://furaffinity.net/full/12345",
+                SiteId(Site::FurAffinity, 12345),
+            ),
+            (
+                "http://sfw.furaffinity.net/view/12345",
+                SiteId(Site::FurAffinity, 12345),
+            ),
+            (
+                "https://d.furaffinity.net/art/artist/1600000000/1600000000.artist_file.png",
+                Name(Site::FurAffinity, "1600000000.artist_file.png".into()),
+            ),
+            ("https://e621.net/posts/54321", SiteId(Site::E621, 54321)),
+            (
+                "https://e926.net/posts/54321?q=tag",
+                SiteId(Site::E621, 54321),
+            ),
+            (
+                "https://e621.net/post/show/54321/some-tags",
+                SiteId(Site::E621, 54321),
+            ),
+            (e621_file, Url(Site::E621, e621_file.into())),
+            (
+                "https://www.weasyl.com/submission/2468",
+                SiteId(Site::Weasyl, 2468),
+            ),
+            (
+                "https://www.weasyl.com/~user/submissions/2468/title",
+                SiteId(Site::Weasyl, 2468),
+            ),
+            (
+                "https://twitter.com/user/status/1234567890123",
+                SiteId(Site::Twitter, 1234567890123),
+            ),
+            (
+                "https://x.com/user/status/1234567890123/photo/1",
+                SiteId(Site::Twitter, 1234567890123),
+            ),
+            (
+                "https://pbs.twimg.com/media/AbCdEf.jpg:small",
+                Url(
+                    Site::Twitter,
+                    "https://pbs.twimg.com/media/AbCdEf.jpg:large".into(),
+                ),
+            ),
+            (
+                "https://pbs.twimg.com/media/AbCdEf?format=png&name=small",
+                Url(
+                    Site::Twitter,
+                    "https://pbs.twimg.com/media/AbCdEf.png:large".into(),
+                ),
+            ),
+        ] {
+            let opts = known_url_opts(url).unwrap_or_else(|| panic!("{} was not recognized", url));
+            assert_eq!(lookup(opts), expected, "{}", url);
+        }
+    }
+
+    #[test]
+    fn ignores_unknown_urls() {
+        for url in [
+            // Lookalike hosts.
+            "https://furaffinity.net.example.com/view/12345/",
+            "https://notfuraffinity.net/view/12345/",
+            "https://e621.net.example.com/posts/54321",
+            "https://fakee621.net/posts/54321",
+            "https://weasyl.com.example.com/submission/2468",
+            "https://nottwitter.com/user/status/1234567890123",
+            // IDs that are not numbers.
+            "https://www.furaffinity.net/view/abc/",
+            "https://e621.net/posts/abc",
+            "https://www.weasyl.com/submission/abc",
+            "https://www.weasyl.com/~user/submissions/abc/title",
+            "https://twitter.com/user/status/abc",
+            // Other pages on known sites.
+            "https://www.furaffinity.net/user/artist/",
+            "https://e621.net/posts",
+            "https://www.weasyl.com/user/submissions/2468/title",
+            "https://twitter.com/user",
+            "https://pbs.twimg.com/media/AbCdEf",
+            // Other schemes.
+            "ftp://www.furaffinity.net/view/12345/",
+            "not a url",
+        ] {
+            assert!(
+                known_url_opts(url).is_none(),
+                "{} should not be recognized",
+                url
+            );
+        }
+    }
+}
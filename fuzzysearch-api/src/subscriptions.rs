@@ -0,0 +1,182 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::broadcast;
+
+use crate::Pool;
+use fuzzysearch_common::types::SearchResult;
+
+/// The channel notified by the database each time a hash is added.
+const HASH_ADDED_CHANNEL: &str = "fuzzysearch_hash_added";
+
+/// How many added hashes may be buffered before slow subscribers start
+/// missing them.
+const HASH_ADDED_CAPACITY: usize = 1024;
+
+/// How long to wait before listening again after losing the connection.
+const RECONNECT_DELAY: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// Sends every hash added to the database, with the submissions it belongs to,
+/// and counts the subscriptions each API key has open.
+#[derive(Clone)]
+pub struct HashAddedSender {
+    tx: broadcast::Sender<Arc<HashAdded>>,
+    open: Arc<Mutex<HashMap<String, usize>>>,
+}
+
+impl HashAddedSender {
+    /// Subscribe to added hashes for an API key, unless it already has `max`
+    /// subscriptions open. The subscription is open until the receiver is
+    /// dropped.
+    pub fn subscribe(&self, api_key: &str, max: usize) -> Option<HashAddedReceiver> {
+        let mut open = self.open.lock().unwrap();
+
+        let count = open.entry(api_key.to_string()).or_default();
+        if *count >= max {
+            return None;
+        }
+        *count += 1;
+
+        Some(HashAddedReceiver {
+            rx: self.tx.subscribe(),
+            api_key: api_key.to_string(),
+            open: self.open.clone(),
+        })
+    }
+}
+
+/// Receives added hashes for one open subscription.
+pub struct HashAddedReceiver {
+    rx: broadcast::Receiver<Arc<HashAdded>>,
+    api_key: String,
+    open: Arc<Mutex<HashMap<String, usize>>>,
+}
+
+impl HashAddedReceiver {
+    pub async fn recv(&mut self) -> Result<Arc<HashAdded>, broadcast::error::RecvError> {
+        self.rx.recv().await
+    }
+}
+
+impl Drop for HashAddedReceiver {
+    fn drop(&mut self) {
+        let mut open = self.open.lock().unwrap();
+
+        if let Some(count) = open.get_mut(&self.api_key) {
+            *count -= 1;
+
+            if *count == 0 {
+                open.remove(&self.api_key);
+            }
+        }
+    }
+}
+
+/// A hash that was added to the database.
+#[derive(Debug)]
+pub struct HashAdded {
+    pub hash: i64,
+    /// Every submission with the hash.
+    pub results: Vec<SearchResult>,
+}
+
+#[derive(serde::Deserialize)]
+struct HashAddedPayload {
+    hash: i64,
+}
+
+/// Start listening for added hashes, returning the sender that subscribers
+/// can receive them from.
+pub fn listen(db: Pool) -> HashAddedSender {
+    let (tx, _rx) = broadcast::channel(HASH_ADDED_CAPACITY);
+
+    tokio::spawn(listen_hash_added(db, tx.clone()));
+
+    HashAddedSender {
+        tx,
+        open: Default::default(),
+    }
+}
+
+async fn listen_hash_added(db: Pool, tx: broadcast::Sender<Arc<HashAdded>>) {
+    loop {
+        if let Err(err) = forward_hash_added(&db, &tx).await {
+            tracing::error!("could not listen for added hashes: {:?}", err);
+        }
+
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+async fn forward_hash_added(
+    db: &Pool,
+    tx: &broadcast::Sender<Arc<HashAdded>>,
+) -> Result<(), sqlx::Error> {
+    let mut listener = sqlx::postgres::PgListener::connect_with(db).await?;
+    listener.listen(HASH_ADDED_CHANNEL).await?;
+
+    tracing::info!("listening for added hashes");
+
+    loop {
+        let notification = listener.recv().await?;
+
+        // Looking up submissions is wasted if nobody is subscribed.
+        if tx.receiver_count() == 0 {
+            continue;
+        }
+
+        let payload: HashAddedPayload = match serde_json::from_str(notification.payload()) {
+            Ok(payload) => payload,
+            Err(err) => {
+                tracing::warn!("got invalid added hash payload: {:?}", err);
+                continue;
+            }
+        };
+
+        // A failed lookup only loses this hash, so keep listening.
+        let results = match crate::models::hash_lookup(db, payload.hash).await {
+            Ok(results) => results,
+            Err(err) => {
+                tracing::error!(
+                    hash = payload.hash,
+                    "could not look up added hash: {:?}",
+                    err
+                );
+                continue;
+            }
+        };
+        tracing::trace!(hash = payload.hash, results = results.len(), "hash added");
+
+        // Sending only fails if every subscriber has since gone away.
+        let _ = tx.send(Arc::new(HashAdded {
+            hash: payload.hash,
+            results,
+        }));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limits_open_subscriptions() {
+        let (tx, _rx) = broadcast::channel(1);
+        let sender = HashAddedSender {
+            tx,
+            open: Default::default(),
+        };
+
+        let first = sender.subscribe("key", 2).unwrap();
+        let second = sender.subscribe("key", 2).unwrap();
+        assert!(sender.subscribe("key", 2).is_none());
+        assert!(sender.subscribe("other-key", 2).is_some());
+
+        drop(first);
+        let third = sender.subscribe("key", 2).unwrap();
+
+        drop(second);
+        drop(third);
+        assert!(sender.open.lock().unwrap().is_empty());
+    }
+}
@@ -1,12 +1,13 @@
 use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
 
-use fuzzysearch_common::types::SearchResult;
+use fuzzysearch_common::types::{HashKind, Rating, SearchResult, Site};
 
 /// An API key representation from the database.alloc
 ///
 /// May contain information about the owner, always has rate limit information.
 /// Limits are the number of requests allowed per minute.
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct ApiKey {
     pub id: i32,
     pub name: Option<String>,
@@ -14,32 +15,430 @@ pub struct ApiKey {
     pub name_limit: i16,
     pub image_limit: i16,
     pub hash_limit: i16,
+    pub sha256_limit: i16,
+}
+
+/// The name of every rate limit group.
+pub const RATE_LIMIT_GROUPS: &[&str] = &["file", "image", "hash", "sha256"];
+
+impl ApiKey {
+    /// The limit for a rate limit group.
+    pub fn group_limit(&self, group: &str) -> Option<i16> {
+        match group {
+            "file" => Some(self.name_limit),
+            "image" => Some(self.image_limit),
+            "hash" => Some(self.hash_limit),
+            "sha256" => Some(self.sha256_limit),
+            _ => None,
+        }
+    }
+}
+
+/// An account, which owns API keys.
+#[derive(Debug)]
+pub struct Account {
+    pub id: i32,
+    pub email: String,
+    /// The account's password, as an encoded Argon2 hash.
+    pub password: String,
+    /// The code needed to verify the account's email address, cleared once
+    /// it has been verified.
+    pub email_verifier: Option<String>,
+    pub is_admin: bool,
+}
+
+/// The details of an account that are shown to its owner.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct AccountInfo {
+    pub id: i32,
+    pub email: String,
+    pub verified: bool,
+    pub is_admin: bool,
+}
+
+impl From<Account> for AccountInfo {
+    fn from(account: Account) -> Self {
+        Self {
+            id: account.id,
+            email: account.email,
+            verified: account.email_verifier.is_none(),
+            is_admin: account.is_admin,
+        }
+    }
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct RegisterAccount {
+    pub email: String,
+    pub password: String,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct VerifyAccount {
+    pub email: String,
+    pub verifier: String,
+}
+
+/// An API key, as shown to its owner.
+///
+/// The key itself is only included when it was just created or rotated.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ApiKeyInfo {
+    pub id: i32,
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+    pub name_limit: i16,
+    pub image_limit: i16,
+    pub hash_limit: i16,
+    pub sha256_limit: i16,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    pub revoked_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct ApiKeyName {
+    pub name: Option<String>,
+}
+
+/// A hash an account is notified about similar submissions to.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct HashWatch {
+    pub id: i32,
+    pub hash: i64,
+    pub distance: i16,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct NewHashWatch {
+    pub hash: i64,
+    /// The largest distance to be notified about, defaulting to 3 and at
+    /// most 10.
+    pub distance: Option<i16>,
+}
+
+/// A webhook from the database.
+#[derive(Debug)]
+pub struct Webhook {
+    pub id: i32,
+    pub endpoint: String,
+    pub sites: Option<Vec<String>>,
+    pub artists: Option<Vec<String>>,
+    pub max_rating: Option<i16>,
+    pub require_hash: bool,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    /// The secret used to sign payloads, only selected when it was just
+    /// created or rotated.
+    pub secret: Option<String>,
+    /// How many attempts to send a payload have failed in a row.
+    pub consecutive_failures: i32,
+    /// When the webhook was disabled after too many failures.
+    pub disabled_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+/// A webhook, as shown to its owner.
+///
+/// The secret is only included when it was just created or rotated.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct WebhookInfo {
+    pub id: i32,
+    pub endpoint: String,
+    #[serde(flatten)]
+    pub filters: WebhookFilters,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub secret: Option<String>,
+    pub consecutive_failures: i32,
+    pub disabled_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+impl From<Webhook> for WebhookInfo {
+    fn from(webhook: Webhook) -> Self {
+        Self {
+            id: webhook.id,
+            endpoint: webhook.endpoint,
+            filters: WebhookFilters {
+                sites: webhook
+                    .sites
+                    .map(|sites| sites.iter().filter_map(|site| site.parse().ok()).collect()),
+                artists: webhook.artists,
+                max_rating: webhook.max_rating.and_then(Rating::from_level),
+                require_hash: webhook.require_hash,
+            },
+            created_at: webhook.created_at,
+            secret: webhook.secret,
+            consecutive_failures: webhook.consecutive_failures,
+            disabled_at: webhook.disabled_at,
+        }
+    }
+}
+
+/// Which new submissions are sent to a webhook. Submissions must match every
+/// filter that is set.
+#[derive(Debug, Default, Deserialize, Serialize, ToSchema)]
+pub struct WebhookFilters {
+    /// The sites to send submissions from.
+    pub sites: Option<Vec<Site>>,
+    /// The artists to send submissions from, ignoring case.
+    pub artists: Option<Vec<String>>,
+    /// The most explicit rating to send. Submissions without a known rating
+    /// are not sent when set.
+    pub max_rating: Option<Rating>,
+    /// If submissions must have been hashed.
+    #[serde(default)]
+    pub require_hash: bool,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct NewWebhook {
+    pub endpoint: String,
+    #[serde(flatten)]
+    pub filters: WebhookFilters,
+}
+
+/// An attempt to send a payload to a webhook.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct WebhookDelivery {
+    pub id: i64,
+    /// The ID sent with the payload, which is the same for every attempt of a
+    /// delivery.
+    pub delivery_id: String,
+    pub attempt: i32,
+    #[schema(value_type = Object)]
+    pub payload: serde_json::Value,
+    /// The status code of the response, if one was received.
+    pub status_code: Option<i16>,
+    pub latency_ms: i32,
+    /// Why the attempt failed.
+    pub error: Option<String>,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+/// New limits for an API key, leaving any that are not set unchanged.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct ApiKeyLimits {
+    pub name_limit: Option<i16>,
+    pub image_limit: Option<i16>,
+    pub hash_limit: Option<i16>,
+    pub sha256_limit: Option<i16>,
+}
+
+/// The status of an API key's rate limit for a group.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RateLimitStatus {
+    /// The API key rate limit group, if the status is for one.
+    pub group: Option<&'static str>,
+    /// The number of requests that may still be made.
+    pub remaining: i16,
+    pub limit: i16,
+    /// The number of seconds until the limit resets.
+    pub reset: u64,
 }
 
 /// The status of an API key's rate limit.
 #[derive(Debug, PartialEq)]
 pub enum RateLimit {
-    /// This key is limited, we should deny the request.
-    Limited,
-    /// This key is available, contains the number of requests made.
-    Available((i16, i16)),
+    /// This key is limited, we should deny the request. The reset is when
+    /// this request would be allowed.
+    Limited(RateLimitStatus),
+    /// This key is available.
+    Available(RateLimitStatus),
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
 pub struct FileSearchOpts {
+    /// The site to search, defaulting to FurAffinity.
+    #[serde(default, deserialize_with = "deserialize_site")]
+    #[param(value_type = Option<Site>)]
+    pub site: Option<Site>,
     pub id: Option<i32>,
     pub name: Option<String>,
     pub url: Option<String>,
-    pub site_id: Option<i32>,
+    pub site_id: Option<i64>,
+    /// The MD5 of the file, only used for e621.
+    pub md5: Option<String>,
+    /// If submissions that have been deleted from their site should be
+    /// included.
+    #[serde(default)]
+    pub include_deleted: bool,
 }
 
-#[derive(Debug, Deserialize)]
+fn deserialize_site<'de, D>(deserializer: D) -> Result<Option<Site>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let site: Option<String> = Option::deserialize(deserializer)?;
+
+    site.map(|site| site.parse().map_err(serde::de::Error::custom))
+        .transpose()
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
 pub struct ImageSearchOpts {
     #[serde(rename = "type")]
     pub search_type: Option<ImageSearchType>,
+    pub kind: Option<HashKind>,
+}
+
+/// Filters for which results should be included in an image search, and what
+/// should be included with each result.
+#[derive(Clone, Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct SearchFilterOpts {
+    /// A comma separated list of sites to search, defaulting to every site.
+    #[serde(default, deserialize_with = "deserialize_sites")]
+    #[param(value_type = Option<String>)]
+    pub sites: Option<Vec<Site>>,
+    /// The most explicit rating to include. Results without a known rating
+    /// are excluded when set.
+    pub max_rating: Option<Rating>,
+    pub posted_after: Option<chrono::DateTime<chrono::Utc>>,
+    pub posted_before: Option<chrono::DateTime<chrono::Utc>>,
+    /// If submissions that have been deleted from their site should be
+    /// included.
+    #[serde(default)]
+    pub include_deleted: bool,
+    /// If tags should be included with each result, when the site has them.
+    #[serde(default)]
+    pub include_tags: bool,
+    /// If tags should also be included grouped by their category, when the
+    /// site has them. Requires `include_tags`.
+    #[serde(default)]
+    pub tag_categories: bool,
+}
+
+impl SearchFilterOpts {
+    /// If a site should be searched.
+    pub fn includes_site(&self, site: Site) -> bool {
+        self.sites
+            .as_ref()
+            .map(|sites| sites.contains(&site))
+            .unwrap_or(true)
+    }
+}
+
+fn deserialize_sites<'de, D>(deserializer: D) -> Result<Option<Vec<Site>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let sites: Option<String> = Option::deserialize(deserializer)?;
+
+    sites
+        .map(|sites| {
+            sites
+                .split(',')
+                .map(|site| site.trim().parse().map_err(serde::de::Error::custom))
+                .collect()
+        })
+        .transpose()
+}
+
+/// The most results that may be returned from a single page of an image
+/// search, also used when no limit is requested.
+pub const MAX_RESULT_LIMIT: u16 = 100;
+
+/// Pagination for image searches.
+///
+/// Results are ordered by distance, then by site and ID. The cursor is opaque
+/// to clients and comes from the `x-next-cursor` header of the previous page.
+#[derive(Clone, Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct SearchPageOpts {
+    pub limit: Option<u16>,
+    #[serde(default, deserialize_with = "deserialize_cursor")]
+    #[param(value_type = Option<String>)]
+    pub cursor: Option<SearchCursor>,
+}
+
+impl SearchPageOpts {
+    /// The number of results to return, capped at the maximum.
+    pub fn limit(&self) -> u16 {
+        self.limit
+            .unwrap_or(MAX_RESULT_LIMIT)
+            .clamp(1, MAX_RESULT_LIMIT)
+    }
+
+    /// Remove the extra result fetched beyond the limit, returning the cursor
+    /// for the next page if there was one.
+    pub fn next_cursor(&self, results: &mut Vec<SearchResult>) -> Option<SearchCursor> {
+        let limit = self.limit() as usize;
+
+        if results.len() <= limit {
+            return None;
+        }
+
+        results.truncate(limit);
+        results.last().and_then(SearchCursor::from_result)
+    }
+}
+
+/// The position of the last result of a page of an image search.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SearchCursor {
+    pub distance: i64,
+    pub site: Site,
+    pub site_id: i64,
+    pub searched_hash: i64,
+}
+
+impl SearchCursor {
+    fn from_result(result: &SearchResult) -> Option<Self> {
+        Some(Self {
+            distance: result.distance? as i64,
+            site: result.site_info.as_ref()?.site(),
+            site_id: result.site_id,
+            searched_hash: result.searched_hash?,
+        })
+    }
 }
 
-#[derive(Debug, Deserialize, PartialEq)]
+impl std::fmt::Display for SearchCursor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}.{}.{}.{}",
+            self.distance, self.site, self.site_id, self.searched_hash
+        )
+    }
+}
+
+impl std::str::FromStr for SearchCursor {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split('.');
+        let mut next = || parts.next().ok_or("incomplete cursor");
+
+        let cursor = Self {
+            distance: next()?.parse().map_err(|_| "invalid cursor distance")?,
+            site: next()?.parse()?,
+            site_id: next()?.parse().map_err(|_| "invalid cursor site id")?,
+            searched_hash: next()?.parse().map_err(|_| "invalid cursor hash")?,
+        };
+
+        if parts.next().is_some() {
+            return Err("invalid cursor");
+        }
+
+        Ok(cursor)
+    }
+}
+
+fn deserialize_cursor<'de, D>(deserializer: D) -> Result<Option<SearchCursor>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let cursor: Option<String> = Option::deserialize(deserializer)?;
+
+    cursor
+        .map(|cursor| cursor.parse().map_err(serde::de::Error::custom))
+        .transpose()
+}
+
+#[derive(Debug, Deserialize, PartialEq, ToSchema)]
 #[serde(rename_all = "lowercase")]
 pub enum ImageSearchType {
     Close,
@@ -47,30 +446,340 @@ pub enum ImageSearchType {
     Force,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, ToSchema)]
 pub struct ImageSimilarity {
     pub hash: i64,
     pub matches: Vec<SearchResult>,
 }
 
-#[derive(Serialize)]
+/// The results for a single image within a batch search.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct BatchImageSimilarity {
+    /// The position of the image part within the submitted form.
+    pub index: usize,
+    /// The filename provided with the image part, if any.
+    pub filename: Option<String>,
+    /// The hash of the image, if it could be hashed.
+    pub hash: Option<i64>,
+    pub matches: Vec<SearchResult>,
+    /// Why the image could not be searched, if it could not be hashed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<ErrorMessage>,
+}
+
+/// The results for a single keyframe of a searched animation.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct FrameSimilarity {
+    /// The index of the frame within the animation.
+    pub frame: u32,
+    pub hash: i64,
+    pub matches: Vec<SearchResult>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
 pub struct ErrorMessage {
     pub code: u16,
     pub message: String,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
 pub struct HashSearchOpts {
     pub hashes: String,
     pub distance: Option<i64>,
+    pub kind: Option<HashKind>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct Sha256SearchOpts {
+    /// Comma separated, hex encoded SHA-256 digests.
+    pub sha256: String,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
 pub struct HandleOpts {
+    /// The site the handle is from.
+    #[serde(default, deserialize_with = "deserialize_site")]
+    #[param(value_type = Option<Site>)]
+    pub site: Option<Site>,
+    pub handle: Option<String>,
+    /// A Twitter handle, only returning if it has been indexed. Superseded by
+    /// `site` and `handle`.
     pub twitter: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
+/// What has been indexed for an artist's handle on a site.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct HandleInfo {
+    pub site: Site,
+    pub handle: String,
+    /// If the handle is known to the site's index.
+    pub indexed: bool,
+    pub submissions: i64,
+    pub first_posted_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub last_posted_at: Option<chrono::DateTime<chrono::Utc>>,
+    /// How much of the account's history has been loaded, only for Twitter.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub backfill: Option<TwitterBackfill>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct TwitterBackfill {
+    /// If all of the account's previous tweets have been loaded.
+    pub completed: bool,
+    /// The oldest tweet that has been loaded.
+    pub min_id: Option<i64>,
+}
+
+/// The size of the time buckets API key usage is aggregated into.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum UsageBucket {
+    Minute,
+    Hour,
+    Day,
+}
+
+impl UsageBucket {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Minute => "minute",
+            Self::Hour => "hour",
+            Self::Day => "day",
+        }
+    }
+
+    /// How far back usage is returned when no start is requested.
+    pub fn default_range(&self) -> chrono::Duration {
+        match self {
+            Self::Minute => chrono::Duration::hours(1),
+            Self::Hour => chrono::Duration::days(1),
+            Self::Day => chrono::Duration::days(30),
+        }
+    }
+
+    /// How long usage is kept in this bucket size, if it is not kept forever.
+    pub fn retention(&self) -> Option<chrono::Duration> {
+        match self {
+            Self::Minute | Self::Hour => Some(chrono::Duration::days(
+                crate::utils::RATE_LIMIT_RETENTION_DAYS,
+            )),
+            Self::Day => None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct UsageOpts {
+    /// The size of each bucket, defaulting to hour.
+    pub bucket: Option<UsageBucket>,
+    /// The earliest time to return usage for. Minute and hour buckets are
+    /// only kept for 7 days, so earlier times are moved forward to the oldest
+    /// available usage.
+    pub since: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+/// The number of requests made in a rate limit group during a time bucket.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct GroupUsage {
+    pub group: String,
+    pub time: chrono::DateTime<chrono::Utc>,
+    pub count: i64,
+}
+
+/// The requests remaining for a rate limit group in the current window.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct GroupRemaining {
+    pub group: &'static str,
+    pub limit: i16,
+    pub remaining: i16,
+    pub resets_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct KeyUsage {
+    pub bucket: UsageBucket,
+    pub since: chrono::DateTime<chrono::Utc>,
+    pub usage: Vec<GroupUsage>,
+    pub remaining: Vec<GroupRemaining>,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
 pub struct UrlSearchOpts {
     pub url: String,
 }
+
+/// An artist to look up on a site.
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ArtistOpts {
+    #[serde(default, deserialize_with = "deserialize_site")]
+    #[param(value_type = Option<Site>)]
+    pub site: Option<Site>,
+    /// The artist's name or handle on the site.
+    pub name: String,
+    /// If submissions that have been deleted from their site should be
+    /// included.
+    #[serde(default)]
+    pub include_deleted: bool,
+}
+
+/// The kind of hash to compare images with.
+#[derive(Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct HashKindOpts {
+    /// Defaults to gradient.
+    pub kind: Option<HashKind>,
+}
+
+/// Pagination for results ordered from the most recently added, such as an
+/// artist's submissions or a webhook's deliveries.
+#[derive(Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct IdPageOpts {
+    pub limit: Option<u16>,
+    /// The cursor from the `x-next-cursor` header of the previous page.
+    pub cursor: Option<i64>,
+}
+
+impl IdPageOpts {
+    /// The number of results to return, capped at the maximum.
+    pub fn limit(&self) -> u16 {
+        self.limit
+            .unwrap_or(MAX_RESULT_LIMIT)
+            .clamp(1, MAX_RESULT_LIMIT)
+    }
+}
+
+/// An artist on another site who uploaded some of the same images.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct RelatedArtist {
+    pub site: Site,
+    pub name: String,
+    /// How many of the searched artist's images were also uploaded by this
+    /// artist.
+    pub shared: usize,
+    /// The smallest distance between any of the shared images.
+    pub distance: u64,
+}
+
+/// The default and largest distance used to find copies of a submission.
+pub const DEFAULT_DUPLICATE_DISTANCE: i64 = 3;
+pub const MAX_DUPLICATE_DISTANCE: i64 = 10;
+
+/// A submission to find copies of.
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct DuplicateOpts {
+    #[serde(default, deserialize_with = "deserialize_site")]
+    #[param(value_type = Option<Site>)]
+    pub site: Option<Site>,
+    pub site_id: i64,
+    /// The largest distance between copies, defaulting to 3 and capped at 10.
+    pub distance: Option<i64>,
+    /// The kind of hash to compare copies with, defaulting to gradient.
+    pub kind: Option<HashKind>,
+    /// If copies that have been deleted from their site should be included.
+    #[serde(default)]
+    pub include_deleted: bool,
+}
+
+/// Every copy of a submission that has been found.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct DuplicateCluster {
+    /// The stored hashes of the submission, of the kind that was compared.
+    pub hashes: Vec<i64>,
+    /// If the submission was posted before every copy, making it the likely
+    /// original source.
+    pub is_original: bool,
+    /// Copies of the submission, from the earliest posted.
+    pub matches: Vec<DuplicateMatch>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct DuplicateMatch {
+    #[serde(flatten)]
+    pub result: SearchResult,
+    /// If this copy was posted first, making it the likely original source.
+    pub likely_original: bool,
+}
+
+/// The most hashes that may be watched by a single subscription.
+pub const MAX_SUBSCRIPTION_HASHES: usize = 100;
+
+/// The largest distance a subscription may watch hashes within.
+pub const MAX_SUBSCRIPTION_DISTANCE: u32 = 10;
+
+/// The most subscriptions an API key may have open at once.
+pub const MAX_SUBSCRIPTIONS_PER_KEY: usize = 5;
+
+/// Hashes to be notified about when similar images are added.
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct SubscribeOpts {
+    /// Comma separated hashes to watch.
+    pub hashes: String,
+    /// The largest distance from a watched hash to be notified about,
+    /// defaulting to 3.
+    pub distance: Option<u32>,
+}
+
+#[cfg(test)]
+mod tests {
+    use fuzzysearch_common::types::Site;
+
+    use super::SearchCursor;
+
+    #[test]
+    fn search_cursor_round_trips() {
+        for cursor in [
+            SearchCursor {
+                distance: 0,
+                site: Site::FurAffinity,
+                site_id: 1,
+                searched_hash: 0,
+            },
+            SearchCursor {
+                distance: 10,
+                site: Site::E621,
+                site_id: i64::MAX,
+                searched_hash: i64::MIN,
+            },
+            SearchCursor {
+                distance: 3,
+                site: Site::Twitter,
+                site_id: 1234567890123456789,
+                searched_hash: -42,
+            },
+        ] {
+            let encoded = cursor.to_string();
+            assert_eq!(encoded.parse::<SearchCursor>(), Ok(cursor), "{}", encoded);
+        }
+    }
+
+    #[test]
+    fn search_cursor_rejects_malformed() {
+        for cursor in [
+            "",
+            "3",
+            "3.e621.1",
+            "3.e621.1.2.5",
+            "x.e621.1.2",
+            "3.unknown.1.2",
+            "3.e621.x.2",
+            "3.e621.1.x",
+            "3.e621.1.",
+            "3..1.2",
+        ] {
+            assert!(
+                cursor.parse::<SearchCursor>().is_err(),
+                "{:?} was accepted",
+                cursor
+            );
+        }
+    }
+}
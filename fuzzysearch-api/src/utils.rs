@@ -13,41 +13,36 @@ lazy_static! {
 
 #[macro_export]
 macro_rules! rate_limit {
-    ($api_key:expr, $db:expr, $limit:tt, $group:expr) => {
-        rate_limit!($api_key, $db, $limit, $group, 1)
+    ($api_key:expr, $limiter:expr, $limit:tt, $group:expr) => {
+        rate_limit!($api_key, $limiter, $limit, $group, 1)
     };
 
-    ($api_key:expr, $db:expr, $limit:tt, $group:expr, $incr_by:expr) => {{
-        let api_key = match crate::models::lookup_api_key($api_key, $db).await {
+    ($api_key:expr, $limiter:expr, $limit:tt, $group:expr, $incr_by:expr) => {{
+        let api_key = match $limiter.lookup_api_key($api_key).await {
             Some(api_key) => api_key,
             None => return Ok(Box::new(Error::ApiKey)),
         };
 
-        let rate_limit = match crate::utils::update_rate_limit(
-            $db,
-            api_key.id,
-            api_key.$limit,
-            $group,
-            $incr_by,
-        )
-        .await
+        let rate_limit = match $limiter
+            .update_rate_limit(&api_key, api_key.$limit, $group, $incr_by)
+            .await
         {
             Ok(rate_limit) => rate_limit,
             Err(err) => return Ok(Box::new(Error::Postgres(err))),
         };
 
         match rate_limit {
-            crate::types::RateLimit::Limited => {
+            crate::types::RateLimit::Limited(status) => {
                 crate::utils::RATE_LIMIT_STATUS
                     .with_label_values(&["limited"])
                     .inc();
-                return Ok(Box::new(Error::RateLimit));
+                return Ok(Box::new(Error::RateLimit(status)));
             }
-            crate::types::RateLimit::Available(count) => {
+            crate::types::RateLimit::Available(status) => {
                 crate::utils::RATE_LIMIT_STATUS
                     .with_label_values(&["allowed"])
                     .inc();
-                count
+                status
             }
         }
     }};
@@ -78,8 +73,7 @@ pub async fn update_rate_limit(
     incr_by: i16,
 ) -> Result<RateLimit, sqlx::Error> {
     let now = chrono::Utc::now();
-    let timestamp = now.timestamp();
-    let time_window = timestamp - (timestamp % 60);
+    let time_window = rate_limit_window(now);
 
     let count: i16 = sqlx::query_scalar!(
         "INSERT INTO
@@ -97,12 +91,213 @@ pub async fn update_rate_limit(
     .fetch_one(db)
     .await?;
 
+    let status = RateLimitStatus {
+        group: Some(group_name),
+        remaining: key_group_limit.saturating_sub(count).max(0),
+        limit: key_group_limit,
+        reset: (time_window + 60 - now.timestamp()) as u64,
+    };
+
     if count > key_group_limit {
-        Ok(RateLimit::Limited)
+        Ok(RateLimit::Limited(status))
+    } else {
+        Ok(RateLimit::Available(status))
+    }
+}
+
+/// The start of the rate limit window containing a time, as a Unix
+/// timestamp. Each window is one minute.
+pub fn rate_limit_window(time: chrono::DateTime<chrono::Utc>) -> i64 {
+    let timestamp = time.timestamp();
+
+    timestamp - (timestamp % 60)
+}
+
+/// The start of the window attempts at account actions are counted in, as a
+/// Unix timestamp. Each window is one hour.
+pub fn account_attempt_window(time: chrono::DateTime<chrono::Utc>) -> i64 {
+    let timestamp = time.timestamp();
+
+    timestamp - (timestamp % (60 * 60))
+}
+
+fn account_attempt_status(count: i16, limit: i16, time_window: i64, now: i64) -> RateLimit {
+    let status = RateLimitStatus {
+        group: None,
+        remaining: limit.saturating_sub(count).max(0),
+        limit,
+        reset: (time_window + 60 * 60 - now) as u64,
+    };
+
+    if count > limit {
+        RateLimit::Limited(status)
     } else {
-        Ok(RateLimit::Available((
-            key_group_limit - count,
-            key_group_limit,
-        )))
+        RateLimit::Available(status)
+    }
+}
+
+/// Count an attempt at an account action, limited to `limit` attempts with
+/// the same key each hour.
+pub async fn update_account_attempts(
+    db: &sqlx::PgPool,
+    key: &str,
+    limit: i16,
+) -> Result<RateLimit, sqlx::Error> {
+    let now = chrono::Utc::now();
+    let time_window = account_attempt_window(now);
+
+    let count = crate::models::record_account_attempt(db, key, time_window).await?;
+
+    Ok(account_attempt_status(
+        count,
+        limit,
+        time_window,
+        now.timestamp(),
+    ))
+}
+
+/// Check if another attempt at an account action may be made, without
+/// counting it.
+pub async fn check_account_attempts(
+    db: &sqlx::PgPool,
+    key: &str,
+    limit: i16,
+) -> Result<RateLimit, sqlx::Error> {
+    let now = chrono::Utc::now();
+    let time_window = account_attempt_window(now);
+
+    let count = crate::models::account_attempts(db, key, time_window).await?;
+
+    Ok(account_attempt_status(
+        count.saturating_add(1),
+        limit,
+        time_window,
+        now.timestamp(),
+    ))
+}
+
+/// The number of days rate limit windows are kept before being rolled into
+/// daily totals.
+pub const RATE_LIMIT_RETENTION_DAYS: i64 = 7;
+
+/// Periodically roll old rate limit windows into daily totals, and delete
+/// old account attempts.
+pub async fn rollup_rate_limits(db: sqlx::PgPool) {
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(60 * 60));
+
+    loop {
+        interval.tick().await;
+
+        let before =
+            (chrono::Utc::now() - chrono::Duration::days(RATE_LIMIT_RETENTION_DAYS)).timestamp();
+        let before = before - (before % (60 * 60 * 24));
+
+        match crate::models::rollup_rate_limits(&db, before).await {
+            Ok(updated) => tracing::info!(updated, "rolled up rate limits"),
+            Err(err) => tracing::error!("could not roll up rate limits: {:?}", err),
+        }
+
+        let before = account_attempt_window(chrono::Utc::now() - chrono::Duration::days(1));
+
+        match crate::models::prune_account_attempts(&db, before).await {
+            Ok(deleted) => tracing::info!(deleted, "pruned account attempts"),
+            Err(err) => tracing::error!("could not prune account attempts: {:?}", err),
+        }
+    }
+}
+
+/// The number of days attempts to send to webhooks are kept.
+const WEBHOOK_DELIVERY_RETENTION_DAYS: i64 = 30;
+
+/// Periodically delete old attempts to send to webhooks.
+pub async fn prune_webhook_deliveries(db: sqlx::PgPool) {
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(60 * 60));
+
+    loop {
+        interval.tick().await;
+
+        let before = chrono::Utc::now() - chrono::Duration::days(WEBHOOK_DELIVERY_RETENTION_DAYS);
+
+        match crate::models::prune_webhook_deliveries(&db, before).await {
+            Ok(deleted) => tracing::info!(deleted, "pruned webhook deliveries"),
+            Err(err) => tracing::error!("could not prune webhook deliveries: {:?}", err),
+        }
+    }
+}
+
+/// Sends emails to account owners.
+#[derive(Clone)]
+pub struct Mailer {
+    transport: lettre::AsyncSmtpTransport<lettre::Tokio1Executor>,
+    from: lettre::message::Mailbox,
+}
+
+impl Mailer {
+    pub fn new(url: &str, from: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let transport =
+            lettre::AsyncSmtpTransport::<lettre::Tokio1Executor>::from_url(url)?.build();
+        let from = from.parse()?;
+
+        Ok(Self { transport, from })
     }
+
+    /// Send the code needed to verify a newly registered account's email
+    /// address.
+    #[tracing::instrument(skip(self, email_verifier))]
+    pub async fn send_verification(
+        &self,
+        to: lettre::Address,
+        email_verifier: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        use lettre::AsyncTransport;
+
+        let message = lettre::Message::builder()
+            .from(self.from.clone())
+            .to(lettre::message::Mailbox::new(None, to))
+            .subject("Verify your FuzzySearch account")
+            .body(format!(
+                "Your FuzzySearch account verification code is:\n\n{}\n",
+                email_verifier
+            ))?;
+
+        self.transport.send(message).await?;
+
+        Ok(())
+    }
+}
+
+/// Generate a random alphanumeric token, used for API keys and email
+/// verification codes.
+pub fn generate_token() -> String {
+    use rand::Rng;
+
+    rand::thread_rng()
+        .sample_iter(&rand::distributions::Alphanumeric)
+        .take(32)
+        .map(char::from)
+        .collect()
+}
+
+/// Hash a password with Argon2, returning the encoded hash.
+pub fn hash_password(password: &str) -> Result<String, argon2::password_hash::Error> {
+    use argon2::password_hash::{rand_core::OsRng, PasswordHasher, SaltString};
+
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = argon2::Argon2::default().hash_password(password.as_bytes(), &salt)?;
+
+    Ok(hash.to_string())
+}
+
+/// Check if a password matches an encoded Argon2 hash.
+pub fn verify_password(password: &str, hash: &str) -> bool {
+    use argon2::password_hash::{PasswordHash, PasswordVerifier};
+
+    let hash = match PasswordHash::new(hash) {
+        Ok(hash) => hash,
+        Err(_err) => return false,
+    };
+
+    argon2::Argon2::default()
+        .verify_password(password.as_bytes(), &hash)
+        .is_ok()
 }
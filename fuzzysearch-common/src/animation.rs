@@ -0,0 +1,237 @@
+use std::io::{BufRead, Seek};
+
+use image::{AnimationDecoder, DynamicImage, ImageFormat};
+
+use crate::types::{FrameHash, HashKind};
+
+/// The maximum number of frames to decode from a single animation.
+pub const MAX_FRAMES: usize = 2_000;
+
+/// The maximum number of keyframes to hash from a single animation.
+pub const MAX_KEYFRAMES: usize = 64;
+
+/// How many bits must differ from the previous keyframe's hash for a frame to
+/// be considered a new keyframe.
+pub const KEYFRAME_DISTANCE: u32 = 5;
+
+/// Decode an animated GIF, APNG, or WebP and hash each of its keyframes.
+///
+/// Frames that are nearly identical to the previous keyframe are skipped, so
+/// long animations with little movement only produce a few hashes. Still
+/// images in any other format are decoded normally and produce a single frame.
+pub fn hash_animation<R: BufRead + Seek>(
+    reader: R,
+    kind: HashKind,
+) -> anyhow::Result<Vec<FrameHash>> {
+    let reader = image::io::Reader::new(reader).with_guessed_format()?;
+
+    let frames: Box<dyn Iterator<Item = anyhow::Result<DynamicImage>>> = match reader.format() {
+        Some(ImageFormat::Gif) => Box::new(
+            image::codecs::gif::GifDecoder::new(reader.into_inner())?
+                .into_frames()
+                .map(|frame| Ok(DynamicImage::ImageRgba8(frame?.into_buffer()))),
+        ),
+        Some(ImageFormat::Png) => {
+            let decoder = image::codecs::png::PngDecoder::new(reader.into_inner())?;
+
+            if decoder.is_apng() {
+                Box::new(
+                    decoder
+                        .apng()
+                        .into_frames()
+                        .map(|frame| Ok(DynamicImage::ImageRgba8(frame?.into_buffer()))),
+                )
+            } else {
+                let im = DynamicImage::from_decoder(decoder)?;
+                return Ok(vec![FrameHash {
+                    frame: 0,
+                    hash: crate::hash_image_kind(kind, &im),
+                }]);
+            }
+        }
+        Some(ImageFormat::WebP) => {
+            let mut decoder =
+                image_webp::WebPDecoder::new(reader.into_inner()).map_err(webp_error)?;
+
+            if decoder.is_animated() {
+                Box::new(webp_frames(decoder))
+            } else {
+                let im = read_webp(&mut decoder)?;
+                return Ok(vec![FrameHash {
+                    frame: 0,
+                    hash: crate::hash_image_kind(kind, &im),
+                }]);
+            }
+        }
+        _ => {
+            let im = reader.decode()?;
+            return Ok(vec![FrameHash {
+                frame: 0,
+                hash: crate::hash_image_kind(kind, &im),
+            }]);
+        }
+    };
+
+    let mut keyframes: Vec<FrameHash> = Vec::new();
+
+    for (index, im) in frames.take(MAX_FRAMES).enumerate() {
+        let im = im?;
+        let hash = crate::hash_image_kind(kind, &im);
+
+        let is_keyframe = match keyframes.last() {
+            Some(previous) => (previous.hash ^ hash).count_ones() > KEYFRAME_DISTANCE,
+            None => true,
+        };
+
+        if !is_keyframe {
+            continue;
+        }
+
+        keyframes.push(FrameHash {
+            frame: index as u32,
+            hash,
+        });
+
+        if keyframes.len() >= MAX_KEYFRAMES {
+            break;
+        }
+    }
+
+    if keyframes.is_empty() {
+        anyhow::bail!("animation contained no frames");
+    }
+
+    Ok(keyframes)
+}
+
+/// Decode each frame of an animated WebP.
+fn webp_frames<R: BufRead + Seek>(
+    mut decoder: image_webp::WebPDecoder<R>,
+) -> impl Iterator<Item = anyhow::Result<DynamicImage>> {
+    (0..decoder.num_frames()).map(move |_| read_webp(&mut decoder))
+}
+
+/// Read the next frame of an animated WebP, or the image of a still WebP.
+///
+/// The decoder's pixel format is RGBA when the image has an alpha channel and
+/// RGB otherwise.
+fn read_webp<R: BufRead + Seek>(
+    decoder: &mut image_webp::WebPDecoder<R>,
+) -> anyhow::Result<DynamicImage> {
+    let (width, height) = decoder.dimensions();
+    let size = decoder
+        .output_buffer_size()
+        .ok_or_else(|| anyhow::anyhow!("webp image was too large"))?;
+
+    let mut buf = vec![0; size];
+    if decoder.is_animated() {
+        decoder.read_frame(&mut buf).map_err(webp_error)?;
+    } else {
+        decoder.read_image(&mut buf).map_err(webp_error)?;
+    }
+
+    let im = if decoder.has_alpha() {
+        image::RgbaImage::from_raw(width, height, buf).map(DynamicImage::ImageRgba8)
+    } else {
+        image::RgbImage::from_raw(width, height, buf).map(DynamicImage::ImageRgb8)
+    };
+
+    im.ok_or_else(|| anyhow::anyhow!("webp buffer did not match dimensions"))
+}
+
+/// Convert a WebP decoding error into the same error as other formats.
+fn webp_error(err: image_webp::DecodingError) -> image::ImageError {
+    match err {
+        image_webp::DecodingError::IoError(err) => image::ImageError::IoError(err),
+        err => image::ImageError::Decoding(image::error::DecodingError::new(
+            ImageFormat::WebP.into(),
+            err,
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    fn fixture(name: &str) -> Vec<u8> {
+        let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("../tests")
+            .join(name);
+
+        std::fs::read(path).unwrap()
+    }
+
+    fn assert_keyframes(keyframes: &[FrameHash]) {
+        assert!(!keyframes.is_empty());
+        assert!(keyframes.len() <= MAX_KEYFRAMES);
+        assert_eq!(keyframes[0].frame, 0);
+
+        for pair in keyframes.windows(2) {
+            assert!(pair[0].frame < pair[1].frame);
+            assert!((pair[0].hash ^ pair[1].hash).count_ones() > KEYFRAME_DISTANCE);
+        }
+    }
+
+    #[test]
+    fn hash_gif_keyframes() {
+        let data = fixture("fox.gif");
+
+        let frames = image::codecs::gif::GifDecoder::new(Cursor::new(&data))
+            .unwrap()
+            .into_frames()
+            .count();
+        let keyframes = hash_animation(Cursor::new(&data), HashKind::Gradient).unwrap();
+
+        assert_keyframes(&keyframes);
+        assert!(keyframes.len() > 1);
+        assert!(keyframes.len() < frames);
+        assert!((keyframes.last().unwrap().frame as usize) < frames);
+    }
+
+    #[test]
+    fn hash_apng_and_webp_keyframes() {
+        // Both animations contain the same four frames, where the second and
+        // fourth frames repeat the frame before them.
+        let apng =
+            hash_animation(Cursor::new(fixture("animation.png")), HashKind::Gradient).unwrap();
+        let webp =
+            hash_animation(Cursor::new(fixture("animation.webp")), HashKind::Gradient).unwrap();
+
+        assert_keyframes(&apng);
+        assert_eq!(
+            apng.iter().map(|frame| frame.frame).collect::<Vec<_>>(),
+            vec![0, 2]
+        );
+        assert_eq!(apng, webp);
+    }
+
+    #[test]
+    fn hash_still_image() {
+        let data = fixture("samples/1460136557.psychonautic_syfarore.png");
+        let im = image::load_from_memory(&data).unwrap();
+
+        for kind in HashKind::ALL {
+            let frames = hash_animation(Cursor::new(&data), kind).unwrap();
+
+            assert_eq!(
+                frames,
+                vec![FrameHash {
+                    frame: 0,
+                    hash: crate::hash_image_kind(kind, &im),
+                }]
+            );
+        }
+    }
+
+    #[test]
+    fn hash_invalid_animations() {
+        let webp = fixture("animation.webp");
+
+        assert!(hash_animation(Cursor::new(&webp[..webp.len() / 2]), HashKind::Gradient).is_err());
+        assert!(hash_animation(Cursor::new(b"not an image"), HashKind::Gradient).is_err());
+        assert!(hash_animation(Cursor::new(b""), HashKind::Gradient).is_err());
+    }
+}
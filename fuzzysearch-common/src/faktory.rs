@@ -59,6 +59,21 @@ impl FaktoryClient {
         job.reserve_for = Some(30);
         self.enqueue(job).await
     }
+
+    /// Create a new job to send a previously delivered payload to a webhook
+    /// again and enqueue it.
+    pub async fn redeliver_webhook(
+        &self,
+        payload: serde_json::Value,
+        webhook_id: i32,
+    ) -> anyhow::Result<()> {
+        let mut job = faktory::Job::new("send_webhook", vec![payload, webhook_id.into()])
+            .on_queue("fuzzysearch_webhook");
+        // The webhook worker records and retries failed attempts itself.
+        job.retry = Some(0);
+
+        self.enqueue(job).await
+    }
 }
 
 fn get_faktory_custom() -> HashMap<String, serde_json::Value> {
@@ -91,6 +106,8 @@ pub struct WebHookData {
     pub file_sha256: Option<Vec<u8>>,
     #[serde(with = "b64_u8")]
     pub hash: Option<[u8; 8]>,
+    #[serde(default)]
+    pub rating: Option<crate::types::Rating>,
 }
 
 mod b64_vec {
@@ -1,5 +1,7 @@
+pub mod animation;
 #[cfg(feature = "queue")]
 pub mod faktory;
+pub mod net;
 pub mod types;
 
 #[cfg(feature = "trace")]
@@ -8,15 +10,47 @@ pub mod trace;
 #[cfg(feature = "download")]
 pub mod download;
 
+#[cfg(feature = "webhook")]
+pub mod webhook;
+
 /// Create an instance of img_hash with project defaults.
 pub fn get_hasher() -> img_hash::Hasher<[u8; 8]> {
-    use img_hash::{HashAlg::Gradient, HasherConfig};
+    get_hasher_kind(types::HashKind::Gradient)
+}
+
+/// Create an instance of img_hash for a specific kind of hash.
+pub fn get_hasher_kind(kind: types::HashKind) -> img_hash::Hasher<[u8; 8]> {
+    use img_hash::{HashAlg, HasherConfig};
+
+    let config = HasherConfig::with_bytes_type::<[u8; 8]>().hash_size(8, 8);
+
+    match kind {
+        types::HashKind::Gradient => config.hash_alg(HashAlg::Gradient).preproc_dct(),
+        types::HashKind::BlockMean => config.hash_alg(HashAlg::Blockhash),
+        types::HashKind::PHash => config.hash_alg(HashAlg::Mean).preproc_dct(),
+        types::HashKind::DoubleGradient => config.hash_alg(HashAlg::DoubleGradient),
+    }
+    .to_hasher()
+}
+
+/// Hash an image with the given kind of hash, returning the hash as a number.
+pub fn hash_image_kind(kind: types::HashKind, im: &image::DynamicImage) -> i64 {
+    let hash = get_hasher_kind(kind).hash_image(im);
+
+    let mut bytes: [u8; 8] = [0; 8];
+    bytes.copy_from_slice(hash.as_bytes());
+
+    i64::from_be_bytes(bytes)
+}
 
-    HasherConfig::with_bytes_type::<[u8; 8]>()
-        .hash_alg(Gradient)
-        .hash_size(8, 8)
-        .preproc_dct()
-        .to_hasher()
+/// Calculate every hash kind other than the default gradient hash, which is
+/// stored alongside each submission.
+pub fn hash_image_extra_kinds(im: &image::DynamicImage) -> Vec<(types::HashKind, i64)> {
+    types::HashKind::ALL
+        .iter()
+        .filter(|kind| **kind != types::HashKind::Gradient)
+        .map(|kind| (*kind, hash_image_kind(*kind, im)))
+        .collect()
 }
 
 /// Initialize the logger. This should only be called by the running binary.
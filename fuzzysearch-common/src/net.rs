@@ -0,0 +1,95 @@
+//! Checking whether addresses are publicly routable, such as before connecting
+//! to hosts chosen by users so they can't be used to reach internal services.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// If an address is publicly routable.
+pub fn is_public(addr: IpAddr) -> bool {
+    match addr {
+        IpAddr::V4(addr) => is_public_v4(addr),
+        IpAddr::V6(addr) => is_public_v6(addr),
+    }
+}
+
+/// If an IPv4 address is publicly routable.
+pub fn is_public_v4(addr: Ipv4Addr) -> bool {
+    let [a, b, c, _] = addr.octets();
+
+    !(addr.is_unspecified()
+        || addr.is_loopback()
+        || addr.is_private()
+        || addr.is_link_local()
+        || addr.is_broadcast()
+        || addr.is_documentation()
+        || addr.is_multicast()
+        // "This network"
+        || a == 0
+        // Shared address space for carrier-grade NAT
+        || (a == 100 && (64..128).contains(&b))
+        // IETF protocol assignments
+        || (a == 192 && b == 0 && c == 0)
+        // Benchmarking
+        || (a == 198 && (b == 18 || b == 19))
+        // Reserved for future use
+        || a >= 240)
+}
+
+/// If an IPv6 address is publicly routable, checking addresses that embed an
+/// IPv4 address as that address.
+pub fn is_public_v6(addr: Ipv6Addr) -> bool {
+    let segments = addr.segments();
+
+    if let [0, 0, 0, 0, 0, 0xffff, ..] = segments {
+        let [_, _, _, _, _, _, high, low] = segments;
+        return is_public_v4(Ipv4Addr::from(u32::from(high) << 16 | u32::from(low)));
+    }
+
+    !(addr.is_unspecified()
+        || addr.is_loopback()
+        || addr.is_multicast()
+        // Unique local
+        || (segments[0] & 0xfe00) == 0xfc00
+        // Link-local
+        || (segments[0] & 0xffc0) == 0xfe80
+        // Site-local, deprecated but still not public
+        || (segments[0] & 0xffc0) == 0xfec0
+        // Documentation
+        || (segments[0] == 0x2001 && segments[1] == 0x0db8)
+        // IPv4-compatible, deprecated
+        || segments[..6].iter().all(|segment| *segment == 0)
+        // NAT64 and 6to4 could reach private IPv4 addresses
+        || (segments[0] == 0x0064 && segments[1] == 0xff9b)
+        || segments[0] == 0x2002)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::is_public;
+
+    #[test]
+    fn checks_addresses() {
+        for addr in ["1.1.1.1", "93.184.216.34", "2606:4700:4700::1111"] {
+            assert!(is_public(addr.parse().unwrap()), "{} is public", addr);
+        }
+
+        for addr in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "255.255.255.255",
+            "::1",
+            "::",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+            "64:ff9b::a00:1",
+            "2002:a00:1::",
+        ] {
+            assert!(!is_public(addr.parse().unwrap()), "{} is not public", addr);
+        }
+    }
+}
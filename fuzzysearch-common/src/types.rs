@@ -1,6 +1,8 @@
 use serde::{Deserialize, Serialize};
 
-#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+/// A content rating, ordered from least to most explicit.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
 #[serde(rename_all = "lowercase")]
 pub enum Rating {
     General,
@@ -24,8 +26,93 @@ impl std::str::FromStr for Rating {
     }
 }
 
+impl Rating {
+    /// A number for the rating that increases with how explicit it is, used
+    /// to store and compare ratings.
+    pub fn level(&self) -> i16 {
+        match self {
+            Self::General => 0,
+            Self::Mature => 1,
+            Self::Adult => 2,
+        }
+    }
+
+    /// Get the rating for a level, if it is valid.
+    pub fn from_level(level: i16) -> Option<Self> {
+        match level {
+            0 => Some(Self::General),
+            1 => Some(Self::Mature),
+            2 => Some(Self::Adult),
+            _ => None,
+        }
+    }
+}
+
+/// A perceptual hashing algorithm.
+///
+/// Gradient hashes are the original hash stored with each submission, other
+/// kinds are stored separately and may not exist for every submission.
+#[derive(Copy, Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum HashKind {
+    /// A DCT preprocessed gradient hash.
+    #[default]
+    Gradient,
+    /// A block mean value hash, more resilient to crops.
+    BlockMean,
+    /// A DCT preprocessed mean hash, more resilient to recolors.
+    #[serde(rename = "phash")]
+    PHash,
+    /// A gradient hash over both rows and columns.
+    DoubleGradient,
+}
+
+impl HashKind {
+    /// Every kind of hash that should be calculated for a submission.
+    pub const ALL: [HashKind; 4] = [
+        Self::Gradient,
+        Self::BlockMean,
+        Self::PHash,
+        Self::DoubleGradient,
+    ];
+
+    /// The name of the hash kind, as stored in the database.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Gradient => "gradient",
+            Self::BlockMean => "block_mean",
+            Self::PHash => "phash",
+            Self::DoubleGradient => "double_gradient",
+        }
+    }
+}
+
+impl std::str::FromStr for HashKind {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let kind = match s {
+            "gradient" => Self::Gradient,
+            "block_mean" => Self::BlockMean,
+            "phash" => Self::PHash,
+            "double_gradient" => Self::DoubleGradient,
+            _ => return Err("unknown hash kind"),
+        };
+
+        Ok(kind)
+    }
+}
+
+impl std::fmt::Display for HashKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
 /// A general type for every result in a search.
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
 pub struct SearchResult {
     pub site_id: i64,
     pub site_id_str: String,
@@ -38,8 +125,16 @@ pub struct SearchResult {
 
     pub sha256: Option<String>,
 
+    /// If the submission has since been deleted from its site.
+    #[serde(default)]
+    pub deleted: bool,
+
     #[serde(skip_serializing_if = "Option::is_none")]
     pub tags: Option<Vec<String>>,
+    /// Tags grouped by their category, for sites with categorized tags.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[cfg_attr(feature = "openapi", schema(value_type = Option<Object>))]
+    pub tag_categories: Option<std::collections::BTreeMap<String, Vec<String>>>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
     #[serde(flatten)]
@@ -52,9 +147,70 @@ pub struct SearchResult {
 
     #[serde(skip_serializing_if = "Option::is_none")]
     pub searched_hash: Option<i64>,
+
+    /// The index of the animation frame that matched, if the match was on a
+    /// keyframe instead of the submission's image.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frame: Option<u32>,
+}
+
+/// The hash of a single keyframe from an animation.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct FrameHash {
+    /// The index of the frame within the animation.
+    pub frame: u32,
+    pub hash: i64,
+}
+
+/// The version of the JSON responses from the hash input service.
+pub const HASH_INPUT_VERSION: u32 = 1;
+
+/// The media type used to request JSON responses from the hash input service.
+pub const HASH_INPUT_MEDIA_TYPE: &str = "application/vnd.fuzzysearch.hash-input.v1+json";
+
+/// Information about an image hashed by the hash input service.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ImageHashInfo {
+    pub version: u32,
+    /// The perceptual hash of the image.
+    pub hash: i64,
+    /// The hex encoded SHA-256 of the raw image bytes.
+    pub sha256: String,
+    /// The detected format of the image, as a file extension.
+    pub format: String,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// A machine-readable reason the hash input service could not hash an image.
+#[derive(Copy, Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ImageHashErrorCode {
+    /// The image was not in a format that could be decoded.
+    UnsupportedFormat,
+    /// The image data or dimensions were larger than allowed.
+    TooLarge,
+    /// The image data was invalid for its format.
+    DecodeFailed,
+    /// The image data ended before the image was complete.
+    Truncated,
+    /// The request did not include the expected image field.
+    MissingField,
+    /// Something went wrong unrelated to the provided image.
+    Internal,
+}
+
+/// An error from the hash input service.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ImageHashError {
+    pub version: u32,
+    pub code: ImageHashErrorCode,
+    /// A human-readable description of the error.
+    pub message: String,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
 #[serde(tag = "site", content = "site_info")]
 pub enum SiteInfo {
     FurAffinity {
@@ -68,7 +224,20 @@ pub enum SiteInfo {
     Weasyl,
 }
 
-#[derive(Copy, Clone, Deserialize, Serialize, Debug)]
+impl SiteInfo {
+    /// The site the information is from.
+    pub fn site(&self) -> Site {
+        match self {
+            Self::FurAffinity { .. } => Site::FurAffinity,
+            Self::E621 { .. } => Site::E621,
+            Self::Twitter => Site::Twitter,
+            Self::Weasyl => Site::Weasyl,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Deserialize, Serialize, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
 pub enum Site {
     FurAffinity,
     E621,
@@ -76,6 +245,22 @@ pub enum Site {
     Twitter,
 }
 
+impl std::str::FromStr for Site {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let site = match s.to_ascii_lowercase().as_str() {
+            "furaffinity" => Self::FurAffinity,
+            "e621" => Self::E621,
+            "weasyl" => Self::Weasyl,
+            "twitter" => Self::Twitter,
+            _ => return Err("unknown site"),
+        };
+
+        Ok(site)
+    }
+}
+
 impl std::fmt::Display for Site {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
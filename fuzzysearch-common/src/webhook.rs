@@ -0,0 +1,212 @@
+//! Signing webhook payloads, so receivers can verify they were sent by
+//! FuzzySearch and are not being replayed.
+//!
+//! Each webhook has a secret that is shown to its owner when the webhook is
+//! created. Requests include the time they were signed, a signature, and a
+//! delivery ID that stays the same if a delivery is retried.
+//!
+//! The signature is an HMAC-SHA256 of the timestamp, a period, and the raw
+//! request body, keyed with the secret and encoded as `sha256=<hex>`.
+//!
+//! ```
+//! use fuzzysearch_common::webhook;
+//!
+//! /// Check a request using the values of its timestamp and signature headers.
+//! fn is_valid(timestamp: &str, signature: &str, body: &[u8]) -> bool {
+//!     webhook::verify(
+//!         b"webhook secret",
+//!         timestamp,
+//!         signature,
+//!         body,
+//!         webhook::DEFAULT_TOLERANCE,
+//!     )
+//!     .is_ok()
+//! }
+//! ```
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+/// The header containing the signature.
+pub const SIGNATURE_HEADER: &str = "x-fuzzysearch-signature";
+
+/// The header containing the UNIX timestamp the request was signed at.
+pub const TIMESTAMP_HEADER: &str = "x-fuzzysearch-timestamp";
+
+/// The header containing an ID unique to each delivery, which can be used to
+/// ignore deliveries that were already received.
+pub const DELIVERY_HEADER: &str = "x-fuzzysearch-delivery";
+
+/// How many seconds apart the signing time and the current time may be
+/// before a request is rejected.
+pub const DEFAULT_TOLERANCE: i64 = 5 * 60;
+
+const SIGNATURE_PREFIX: &str = "sha256=";
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum VerifyError {
+    /// The timestamp was not a valid UNIX timestamp.
+    InvalidTimestamp,
+    /// The timestamp was outside of the tolerance.
+    Expired,
+    /// The signature was malformed or did not match the body.
+    InvalidSignature,
+}
+
+impl std::fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidTimestamp => write!(f, "invalid timestamp"),
+            Self::Expired => write!(f, "timestamp outside of tolerance"),
+            Self::InvalidSignature => write!(f, "invalid signature"),
+        }
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+fn mac(secret: &[u8], timestamp: i64, body: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("hmac accepts keys of any size");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body);
+
+    mac
+}
+
+/// Sign a request body at a UNIX timestamp, returning the value for the
+/// signature header.
+pub fn sign(secret: &[u8], timestamp: i64, body: &[u8]) -> String {
+    let signature = mac(secret, timestamp, body).finalize().into_bytes();
+
+    format!("{}{}", SIGNATURE_PREFIX, hex::encode(signature))
+}
+
+/// Verify a request body was signed with the secret within `tolerance`
+/// seconds of now, given the values of the timestamp and signature headers.
+pub fn verify(
+    secret: &[u8],
+    timestamp: &str,
+    signature: &str,
+    body: &[u8],
+    tolerance: i64,
+) -> Result<(), VerifyError> {
+    let timestamp: i64 = timestamp
+        .trim()
+        .parse()
+        .map_err(|_err| VerifyError::InvalidTimestamp)?;
+
+    // Timestamps are untrusted, so the difference must not overflow.
+    if chrono::Utc::now().timestamp().abs_diff(timestamp) > tolerance.max(0) as u64 {
+        return Err(VerifyError::Expired);
+    }
+
+    let signature = signature
+        .trim()
+        .strip_prefix(SIGNATURE_PREFIX)
+        .and_then(|signature| hex::decode(signature).ok())
+        .ok_or(VerifyError::InvalidSignature)?;
+
+    mac(secret, timestamp, body)
+        .verify_slice(&signature)
+        .map_err(|_err| VerifyError::InvalidSignature)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &[u8] = b"webhook secret";
+    const BODY: &[u8] = br#"{"site":"FurAffinity","site_id":1}"#;
+
+    fn signed_now() -> (String, String) {
+        let timestamp = chrono::Utc::now().timestamp();
+
+        (timestamp.to_string(), sign(SECRET, timestamp, BODY))
+    }
+
+    #[test]
+    fn verifies_signed_body() {
+        let (timestamp, signature) = signed_now();
+
+        assert_eq!(
+            verify(SECRET, &timestamp, &signature, BODY, DEFAULT_TOLERANCE),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn rejects_changed_requests() {
+        let (timestamp, signature) = signed_now();
+
+        assert_eq!(
+            verify(
+                b"other secret",
+                &timestamp,
+                &signature,
+                BODY,
+                DEFAULT_TOLERANCE
+            ),
+            Err(VerifyError::InvalidSignature)
+        );
+        assert_eq!(
+            verify(SECRET, &timestamp, &signature, b"{}", DEFAULT_TOLERANCE),
+            Err(VerifyError::InvalidSignature)
+        );
+
+        let earlier = (timestamp.parse::<i64>().unwrap() - 1).to_string();
+        assert_eq!(
+            verify(SECRET, &earlier, &signature, BODY, DEFAULT_TOLERANCE),
+            Err(VerifyError::InvalidSignature)
+        );
+
+        for signature in [
+            "",
+            "sha256=",
+            "sha256=zz",
+            &signature[SIGNATURE_PREFIX.len()..],
+        ] {
+            assert_eq!(
+                verify(SECRET, &timestamp, signature, BODY, DEFAULT_TOLERANCE),
+                Err(VerifyError::InvalidSignature)
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_expired_timestamps() {
+        let now = chrono::Utc::now().timestamp();
+
+        for timestamp in [
+            now - DEFAULT_TOLERANCE - 60,
+            now + DEFAULT_TOLERANCE + 60,
+            i64::MIN,
+            i64::MAX,
+        ] {
+            let signature = sign(SECRET, timestamp, BODY);
+
+            assert_eq!(
+                verify(
+                    SECRET,
+                    &timestamp.to_string(),
+                    &signature,
+                    BODY,
+                    DEFAULT_TOLERANCE
+                ),
+                Err(VerifyError::Expired)
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_timestamps() {
+        let (_timestamp, signature) = signed_now();
+
+        for timestamp in ["", "now", "1.5", "99999999999999999999"] {
+            assert_eq!(
+                verify(SECRET, timestamp, &signature, BODY, DEFAULT_TOLERANCE),
+                Err(VerifyError::InvalidTimestamp)
+            );
+        }
+    }
+}
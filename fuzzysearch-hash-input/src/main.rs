@@ -1,9 +1,15 @@
 use std::{
+    cell::Cell,
     convert::TryInto,
-    io::{BufReader, SeekFrom},
+    io::{BufRead, BufReader, ErrorKind, Read, Seek, SeekFrom},
+    rc::Rc,
 };
 
-use actix_web::{post, web::Data, App, HttpRequest, HttpResponse, HttpServer, Responder};
+use actix_web::{
+    post,
+    web::{Data, Query},
+    App, HttpRequest, HttpResponse, HttpServer, Responder,
+};
 use tempfile::tempfile;
 use tokio::{
     io::{AsyncSeekExt, AsyncWriteExt},
@@ -11,6 +17,11 @@ use tokio::{
 };
 use tokio_stream::StreamExt;
 
+use fuzzysearch_common::types::{
+    FrameHash, HashKind, ImageHashError, ImageHashErrorCode, ImageHashInfo, HASH_INPUT_MEDIA_TYPE,
+    HASH_INPUT_VERSION,
+};
+
 lazy_static::lazy_static! {
     static ref IMAGE_LOADING_DURATION: prometheus::Histogram =
         prometheus::register_histogram!("fuzzysearch_image_image_loading_seconds", "Duration to download and save image").unwrap();
@@ -18,33 +29,226 @@ lazy_static::lazy_static! {
         prometheus::register_histogram!("fuzzysearch_image_image_decoding_seconds", "Duration to decode image data").unwrap();
     static ref IMAGE_HASHING_DURATION: prometheus::Histogram =
         prometheus::register_histogram!("fuzzysearch_image_image_hashing_seconds", "Duration to hash image").unwrap();
+    static ref ANIMATION_HASHING_DURATION: prometheus::Histogram =
+        prometheus::register_histogram!("fuzzysearch_image_animation_hashing_seconds", "Duration to decode and hash animation keyframes").unwrap();
+}
+
+/// The largest file that will be accepted, in bytes.
+const MAX_FILE_SIZE: usize = 50 * 1024 * 1024;
+
+/// The uploaded file was larger than [MAX_FILE_SIZE].
+#[derive(Debug)]
+struct TooLarge;
+
+impl std::fmt::Display for TooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "file was larger than {} bytes", MAX_FILE_SIZE)
+    }
+}
+
+impl std::error::Error for TooLarge {}
+
+/// The request did not contain the expected multipart field.
+#[derive(Debug)]
+struct MissingField(&'static str);
+
+impl std::fmt::Display for MissingField {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "missing {} field", self.0)
+    }
+}
+
+impl std::error::Error for MissingField {}
+
+/// The file ended before it could be decoded.
+#[derive(Debug)]
+struct Truncated;
+
+impl std::fmt::Display for Truncated {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "image data was truncated")
+    }
+}
+
+impl std::error::Error for Truncated {}
+
+/// A reader that records if the end of its data was reached.
+///
+/// Decoders report running out of data in different ways, some return an
+/// unexpected EOF IO error while others only return a generic format error.
+/// Any decoding error after reaching the end of the data is instead treated as
+/// the data being truncated.
+struct EofReader<R> {
+    inner: R,
+    reached_eof: Rc<Cell<bool>>,
+}
+
+impl<R> EofReader<R> {
+    fn new(inner: R) -> (Self, Rc<Cell<bool>>) {
+        let reached_eof = Rc::new(Cell::new(false));
+
+        let reader = Self {
+            inner,
+            reached_eof: reached_eof.clone(),
+        };
+
+        (reader, reached_eof)
+    }
+}
+
+impl<R: Read> Read for EofReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        if len == 0 && !buf.is_empty() {
+            self.reached_eof.set(true);
+        }
+
+        Ok(len)
+    }
+}
+
+impl<R: BufRead> BufRead for EofReader<R> {
+    fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+        let buf = self.inner.fill_buf()?;
+        if buf.is_empty() {
+            self.reached_eof.set(true);
+        }
+
+        Ok(buf)
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.inner.consume(amt)
+    }
+}
+
+impl<R: Seek> Seek for EofReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+/// Decode a file, marking decoding errors as truncation when the decoder
+/// reached the end of the file.
+fn decode_file<R, T, F>(reader: R, decode: F) -> anyhow::Result<T>
+where
+    R: BufRead + Seek,
+    F: FnOnce(EofReader<R>) -> anyhow::Result<T>,
+{
+    let (reader, reached_eof) = EofReader::new(reader);
+
+    decode(reader).map_err(|err| {
+        let is_decoding_err = matches!(
+            err.downcast_ref::<image::ImageError>(),
+            Some(image::ImageError::Decoding(_))
+        );
+
+        if is_decoding_err && reached_eof.get() {
+            err.context(Truncated)
+        } else {
+            err
+        }
+    })
+}
+
+/// Determine the machine-readable code for an error while processing a file.
+fn error_code(err: &anyhow::Error) -> ImageHashErrorCode {
+    if err.is::<TooLarge>() {
+        return ImageHashErrorCode::TooLarge;
+    }
+
+    if err.is::<MissingField>() {
+        return ImageHashErrorCode::MissingField;
+    }
+
+    if err.is::<Truncated>() {
+        return ImageHashErrorCode::Truncated;
+    }
+
+    let image_err = match err.downcast_ref::<image::ImageError>() {
+        Some(image_err) => image_err,
+        None => return ImageHashErrorCode::Internal,
+    };
+
+    match image_err {
+        image::ImageError::IoError(io_err) if io_err.kind() == ErrorKind::UnexpectedEof => {
+            ImageHashErrorCode::Truncated
+        }
+        image::ImageError::Unsupported(_) => ImageHashErrorCode::UnsupportedFormat,
+        image::ImageError::Limits(_) => ImageHashErrorCode::TooLarge,
+        _ => ImageHashErrorCode::DecodeFailed,
+    }
 }
 
 enum ImageResponse {
-    Hash(i64),
+    Hash(ImageHashInfo),
+    Frames(Vec<FrameHash>),
     Error(anyhow::Error),
 }
 
+impl ImageResponse {
+    /// If the request asked for a JSON response instead of the original plain
+    /// text response.
+    fn wants_json(req: &HttpRequest) -> bool {
+        req.headers()
+            .get(actix_web::http::header::ACCEPT)
+            .and_then(|accept| accept.to_str().ok())
+            .map(|accept| {
+                accept.contains(HASH_INPUT_MEDIA_TYPE) || accept.contains("application/json")
+            })
+            .unwrap_or(false)
+    }
+}
+
 impl Responder for ImageResponse {
     type Body = actix_web::body::BoxBody;
 
-    fn respond_to(self, _req: &HttpRequest) -> HttpResponse<Self::Body> {
+    fn respond_to(self, req: &HttpRequest) -> HttpResponse<Self::Body> {
+        let wants_json = Self::wants_json(req);
+
         match self {
-            ImageResponse::Hash(hash) => HttpResponse::Ok()
-                .content_type("text/plain")
-                .body(hash.to_string()),
-            ImageResponse::Error(error) => HttpResponse::BadRequest()
+            ImageResponse::Hash(info) if wants_json => HttpResponse::Ok()
+                .content_type(HASH_INPUT_MEDIA_TYPE)
+                .json(info),
+            ImageResponse::Hash(info) => HttpResponse::Ok()
                 .content_type("text/plain")
-                .body(error.to_string()),
+                .body(info.hash.to_string()),
+            ImageResponse::Frames(frames) => HttpResponse::Ok().json(frames),
+            ImageResponse::Error(error) => {
+                let code = error_code(&error);
+
+                let mut resp = match code {
+                    ImageHashErrorCode::TooLarge => HttpResponse::PayloadTooLarge(),
+                    ImageHashErrorCode::Internal => HttpResponse::InternalServerError(),
+                    _ => HttpResponse::BadRequest(),
+                };
+
+                if wants_json {
+                    resp.content_type(HASH_INPUT_MEDIA_TYPE)
+                        .json(ImageHashError {
+                            version: HASH_INPUT_VERSION,
+                            code,
+                            message: error.to_string(),
+                        })
+                } else {
+                    resp.content_type("text/plain").body(error.to_string())
+                }
+            }
         }
     }
 }
 
-#[tracing::instrument(err, skip(field, semaphore))]
-async fn process_image(
-    mut field: actix_multipart::Field,
-    semaphore: Data<Semaphore>,
-) -> anyhow::Result<i64> {
+#[derive(Debug, serde::Deserialize)]
+struct ImageOpts {
+    kind: Option<HashKind>,
+}
+
+/// Write the contents of a multipart field into a temporary file, returning
+/// the file at its beginning and the SHA-256 of its contents.
+#[tracing::instrument(err, skip(field))]
+async fn save_field(mut field: actix_multipart::Field) -> anyhow::Result<(std::fs::File, Vec<u8>)> {
+    use sha2::{Digest, Sha256};
+
     tracing::debug!("creating temp file");
 
     let loading_duration = IMAGE_LOADING_DURATION.start_timer();
@@ -56,10 +260,16 @@ async fn process_image(
         .await??;
 
     tracing::debug!("writing contents to temp file");
+    let mut hasher = Sha256::new();
     let mut size = 0;
     while let Ok(Some(chunk)) = field.try_next().await {
-        file.write_all(&chunk).await?;
         size += chunk.len();
+        if size > MAX_FILE_SIZE {
+            return Err(TooLarge.into());
+        }
+
+        hasher.update(&chunk);
+        file.write_all(&chunk).await?;
     }
     tracing::debug!("file was {} bytes", size);
 
@@ -68,36 +278,102 @@ async fn process_image(
     let file = file.into_std().await;
     loading_duration.stop_and_record();
 
+    Ok((file, hasher.finalize().to_vec()))
+}
+
+/// Decode and hash an image.
+fn hash_image<R: BufRead + Seek>(
+    reader: R,
+    kind: HashKind,
+    sha256: Vec<u8>,
+) -> anyhow::Result<ImageHashInfo> {
+    use image::GenericImageView;
+
+    let decoding_duration = IMAGE_DECODING_DURATION.start_timer();
+    let (format, im) = decode_file(reader, |reader| {
+        let reader = image::io::Reader::new(reader).with_guessed_format()?;
+        let format = reader
+            .format()
+            .and_then(|format| format.extensions_str().first().copied())
+            .unwrap_or("unknown");
+
+        Ok((format, reader.decode()?))
+    })?;
+    decoding_duration.stop_and_record();
+
+    let hashing_duration = IMAGE_HASHING_DURATION.start_timer();
+    let image_hash = fuzzysearch_common::get_hasher_kind(kind).hash_image(&im);
+    let hash: [u8; 8] = image_hash.as_bytes().try_into()?;
+    let hash = i64::from_be_bytes(hash);
+    hashing_duration.stop_and_record();
+
+    let (width, height) = im.dimensions();
+
+    Ok(ImageHashInfo {
+        version: HASH_INPUT_VERSION,
+        hash,
+        sha256: hex::encode(sha256),
+        format: format.to_string(),
+        width,
+        height,
+    })
+}
+
+/// Decode and hash each keyframe of an animation.
+fn hash_frames<R: BufRead + Seek>(reader: R, kind: HashKind) -> anyhow::Result<Vec<FrameHash>> {
+    let _timer = ANIMATION_HASHING_DURATION.start_timer();
+
+    decode_file(reader, |reader| {
+        fuzzysearch_common::animation::hash_animation(reader, kind)
+    })
+}
+
+#[tracing::instrument(err, skip(field, semaphore))]
+async fn process_image(
+    field: actix_multipart::Field,
+    semaphore: Data<Semaphore>,
+    kind: HashKind,
+) -> anyhow::Result<ImageHashInfo> {
+    let (file, sha256) = save_field(field).await?;
+
     tracing::debug!("getting semaphore permit");
     let _permit = semaphore.acquire().await?;
 
     tracing::debug!("decoding and hashing image");
-    let hash = tokio::task::spawn_blocking(move || -> anyhow::Result<i64, anyhow::Error> {
-        let decoding_duration = IMAGE_DECODING_DURATION.start_timer();
-        let reader = BufReader::new(file);
-        let reader = image::io::Reader::new(reader).with_guessed_format()?;
-        let im = reader.decode()?;
-        decoding_duration.stop_and_record();
+    let info = tokio::task::spawn_blocking(move || hash_image(BufReader::new(file), kind, sha256))
+        .await??;
 
-        let hashing_duration = IMAGE_HASHING_DURATION.start_timer();
-        let image_hash = fuzzysearch_common::get_hasher().hash_image(&im);
-        let hash: [u8; 8] = image_hash.as_bytes().try_into()?;
-        let hash = i64::from_be_bytes(hash);
-        hashing_duration.stop_and_record();
+    tracing::debug!("calculated image hash: {}", info.hash);
+    Ok(info)
+}
 
-        Ok(hash)
-    })
-    .await??;
+#[tracing::instrument(err, skip(field, semaphore))]
+async fn process_animation(
+    field: actix_multipart::Field,
+    semaphore: Data<Semaphore>,
+    kind: HashKind,
+) -> anyhow::Result<Vec<FrameHash>> {
+    let (file, _sha256) = save_field(field).await?;
+
+    tracing::debug!("getting semaphore permit");
+    let _permit = semaphore.acquire().await?;
+
+    tracing::debug!("decoding and hashing animation frames");
+    let frames =
+        tokio::task::spawn_blocking(move || hash_frames(BufReader::new(file), kind)).await??;
 
-    tracing::debug!("calculated image hash: {}", hash);
-    Ok(hash)
+    tracing::debug!("calculated {} keyframe hashes", frames.len());
+    Ok(frames)
 }
 
 #[post("/image")]
 async fn post_image(
     mut form: actix_multipart::Multipart,
+    opts: Query<ImageOpts>,
     semaphore: Data<Semaphore>,
 ) -> impl Responder {
+    let kind = opts.kind.unwrap_or_default();
+
     while let Ok(Some(field)) = form.try_next().await {
         tracing::debug!("got multipart field: {:?}", field);
 
@@ -105,13 +381,37 @@ async fn post_image(
             continue;
         }
 
-        match process_image(field, semaphore).await {
-            Ok(hash) => return ImageResponse::Hash(hash),
+        match process_image(field, semaphore, kind).await {
+            Ok(info) => return ImageResponse::Hash(info),
+            Err(err) => return ImageResponse::Error(err),
+        }
+    }
+
+    ImageResponse::Error(MissingField("image").into())
+}
+
+#[post("/video")]
+async fn post_video(
+    mut form: actix_multipart::Multipart,
+    opts: Query<ImageOpts>,
+    semaphore: Data<Semaphore>,
+) -> impl Responder {
+    let kind = opts.kind.unwrap_or_default();
+
+    while let Ok(Some(field)) = form.try_next().await {
+        tracing::debug!("got multipart field: {:?}", field);
+
+        if !matches!(field.content_disposition().get_name(), Some("video")) {
+            continue;
+        }
+
+        match process_animation(field, semaphore, kind).await {
+            Ok(frames) => return ImageResponse::Frames(frames),
             Err(err) => return ImageResponse::Error(err),
         }
     }
 
-    ImageResponse::Error(anyhow::anyhow!("missing image field"))
+    ImageResponse::Error(MissingField("video").into())
 }
 
 #[actix_web::main]
@@ -126,6 +426,7 @@ async fn main() {
             .wrap(tracing_actix_web::TracingLogger::default())
             .app_data(semaphore.clone())
             .service(post_image)
+            .service(post_video)
     })
     .workers(2)
     .bind("0.0.0.0:8090")
@@ -134,3 +435,70 @@ async fn main() {
     .await
     .unwrap();
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    fn fixture(name: &str) -> Vec<u8> {
+        let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("../tests")
+            .join(name);
+
+        std::fs::read(path).unwrap()
+    }
+
+    #[test]
+    fn truncated_animation() {
+        for name in ["fox.gif", "animation.png", "animation.webp"] {
+            let data = fixture(name);
+
+            let err =
+                hash_frames(Cursor::new(&data[..data.len() / 2]), HashKind::Gradient).unwrap_err();
+            assert_eq!(error_code(&err), ImageHashErrorCode::Truncated, "{}", name);
+        }
+    }
+
+    #[test]
+    fn truncated_image() {
+        for name in [
+            "samples/1460136557.psychonautic_syfarore.png",
+            "samples/273210894ab3d9f02f02742acead73a2.jpg",
+        ] {
+            let data = fixture(name);
+
+            let err = hash_image(
+                Cursor::new(&data[..data.len() / 2]),
+                HashKind::Gradient,
+                Vec::new(),
+            )
+            .unwrap_err();
+            assert_eq!(error_code(&err), ImageHashErrorCode::Truncated, "{}", name);
+        }
+    }
+
+    #[test]
+    fn invalid_image() {
+        let err =
+            hash_image(Cursor::new(b"not an image"), HashKind::Gradient, Vec::new()).unwrap_err();
+        assert_eq!(error_code(&err), ImageHashErrorCode::UnsupportedFormat);
+
+        // A complete GIF with corrupted frame data is not truncated.
+        let mut data = fixture("fox.gif");
+        let len = data.len();
+        data[len / 2..len - 1]
+            .iter_mut()
+            .for_each(|byte| *byte = 0xff);
+
+        let err = hash_frames(Cursor::new(&data), HashKind::Gradient).unwrap_err();
+        assert_eq!(error_code(&err), ImageHashErrorCode::DecodeFailed);
+    }
+
+    #[test]
+    fn complete_animation() {
+        let frames = hash_frames(Cursor::new(fixture("fox.gif")), HashKind::Gradient).unwrap();
+        assert!(frames.len() > 1);
+    }
+}
@@ -6,7 +6,10 @@ use prometheus::{
 use sqlx::Connection;
 use tracing_unwrap::ResultExt;
 
-use fuzzysearch_common::faktory::FaktoryClient;
+use fuzzysearch_common::{
+    faktory::FaktoryClient,
+    types::{FrameHash, HashKind},
+};
 
 static USER_AGENT: &str = "e621-watcher / FuzzySearch Ingester / Syfaro <syfaro@huefox.com>";
 
@@ -228,6 +231,8 @@ struct ImageData {
     hash_error: Option<String>,
     sha256: Option<Vec<u8>>,
     bytes: Option<Vec<u8>>,
+    extra_hashes: Vec<(HashKind, i64)>,
+    frames: Vec<FrameHash>,
 }
 
 #[tracing::instrument(err, skip(conn, faktory, client, post, download_folder), fields(id))]
@@ -253,6 +258,8 @@ async fn insert_submission(
         hash,
         hash_error,
         sha256,
+        extra_hashes,
+        frames,
         ..
     } = if let Some((url, ext)) = get_post_url_ext(post) {
         let ImageData {
@@ -260,7 +267,11 @@ async fn insert_submission(
             hash_error,
             sha256,
             bytes,
-        } = if url != "/images/deleted-preview.png" && (ext == "jpg" || ext == "png") {
+            extra_hashes,
+            frames,
+        } = if url != "/images/deleted-preview.png"
+            && (ext == "jpg" || ext == "png" || ext == "gif")
+        {
             load_image(client, url).await?
         } else {
             tracing::debug!("Ignoring post as it is deleted or not a supported image format");
@@ -270,6 +281,8 @@ async fn insert_submission(
                 hash_error: None,
                 sha256: None,
                 bytes: None,
+                extra_hashes: Vec::new(),
+                frames: Vec::new(),
             }
         };
 
@@ -302,6 +315,10 @@ async fn insert_submission(
                 file_url: url.to_owned(),
                 file_sha256: sha256.clone(),
                 hash: hash.map(|hash| hash.to_be_bytes()),
+                rating: post
+                    .get("rating")
+                    .and_then(|rating| rating.as_str())
+                    .and_then(|rating| rating.parse().ok()),
             })
             .await?;
 
@@ -310,6 +327,8 @@ async fn insert_submission(
             hash_error,
             sha256,
             bytes,
+            extra_hashes,
+            frames,
         }
     } else {
         tracing::warn!("Post had missing URL or extension");
@@ -319,6 +338,8 @@ async fn insert_submission(
             hash_error: None,
             sha256: None,
             bytes: None,
+            extra_hashes: Vec::new(),
+            frames: Vec::new(),
         }
     };
 
@@ -337,9 +358,33 @@ async fn insert_submission(
         hash_error,
         sha256
     )
-    .execute(conn)
+    .execute(&mut *conn)
     .await?;
 
+    for (kind, hash) in extra_hashes {
+        sqlx::query!(
+            "INSERT INTO submission_hash (site, site_id, kind, hash) VALUES ('e621', $1, $2, $3)
+                ON CONFLICT (site, site_id, kind) DO UPDATE SET hash = EXCLUDED.hash",
+            id as i64,
+            kind.as_str(),
+            hash
+        )
+        .execute(&mut *conn)
+        .await?;
+    }
+
+    for frame in frames {
+        sqlx::query!(
+            "INSERT INTO submission_frame (site, site_id, frame, hash) VALUES ('e621', $1, $2, $3)
+                ON CONFLICT (site, site_id, frame) DO UPDATE SET hash = EXCLUDED.hash",
+            id as i64,
+            frame.frame as i32,
+            frame.hash
+        )
+        .execute(&mut *conn)
+        .await?;
+    }
+
     tracing::info!("Completed submission");
 
     Ok(())
@@ -379,6 +424,8 @@ async fn load_image(client: &reqwest::Client, url: &str) -> anyhow::Result<Image
                 hash_error: Some(err.to_string()),
                 sha256: Some(result),
                 bytes: Some(bytes),
+                extra_hashes: Vec::new(),
+                frames: Vec::new(),
             });
         }
     };
@@ -391,10 +438,37 @@ async fn load_image(client: &reqwest::Client, url: &str) -> anyhow::Result<Image
 
     tracing::trace!(?hash, "Calculated image hash");
 
+    let extra_hashes = fuzzysearch_common::hash_image_extra_kinds(&img);
+
+    // Animations also have each of their keyframes hashed, a still image
+    // only has a single frame which is already stored as the image's hash.
+    let frames = match image::guess_format(&bytes) {
+        Ok(image::ImageFormat::Gif)
+        | Ok(image::ImageFormat::Png)
+        | Ok(image::ImageFormat::WebP) => {
+            match fuzzysearch_common::animation::hash_animation(
+                std::io::Cursor::new(&bytes),
+                HashKind::Gradient,
+            ) {
+                Ok(frames) if frames.len() > 1 => frames,
+                Ok(_frames) => Vec::new(),
+                Err(err) => {
+                    tracing::warn!(?err, "Unable to hash animation frames");
+                    Vec::new()
+                }
+            }
+        }
+        _ => Vec::new(),
+    };
+
+    tracing::trace!(frames = frames.len(), "Calculated animation frame hashes");
+
     Ok(ImageData {
         hash: Some(hash),
         hash_error: None,
         sha256: Some(result),
         bytes: Some(bytes),
+        extra_hashes,
+        frames,
     })
 }
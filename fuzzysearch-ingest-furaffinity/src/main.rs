@@ -6,7 +6,7 @@ use prometheus::{
 use tokio_postgres::Client;
 use tracing_unwrap::{OptionExt, ResultExt};
 
-use fuzzysearch_common::faktory::FaktoryClient;
+use fuzzysearch_common::{faktory::FaktoryClient, types::HashKind};
 
 lazy_static! {
     static ref INDEX_DURATION: Histogram = register_histogram!(HistogramOpts::new(
@@ -102,6 +102,7 @@ async fn ids_to_check(client: &Client, max: i32) -> Vec<i32> {
 async fn insert_submission(
     client: &Client,
     sub: &furaffinity_rs::Submission,
+    extra_hashes: &[(HashKind, i64)],
 ) -> Result<(), tokio_postgres::Error> {
     let artist_id = lookup_artist(client, &sub.artist).await;
     let mut tag_ids = Vec::with_capacity(sub.tags.len());
@@ -109,12 +110,12 @@ async fn insert_submission(
         tag_ids.push(lookup_tag(client, tag).await);
     }
 
-    let hash = sub.hash.clone();
+    let hash = sub.hash_num.map(|hash| hash.to_be_bytes().to_vec());
     let url = sub.content.url();
 
     let size = sub.file_size.map(|size| size as i32);
 
-    client.execute("INSERT INTO submission (id, artist_id, url, filename, hash, rating, posted_at, description, hash_int, file_id, file_size, file_sha256) VALUES ($1, $2, $3, $4, decode($5, 'base64'), $6, $7, $8, $9, CASE WHEN isnumeric(split_part($4, '.', 1)) THEN split_part($4, '.', 1)::int ELSE null END, $10, $11)", &[
+    client.execute("INSERT INTO submission (id, artist_id, url, filename, hash, rating, posted_at, description, hash_int, file_id, file_size, file_sha256) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, CASE WHEN isnumeric(split_part($4, '.', 1)) THEN split_part($4, '.', 1)::int ELSE null END, $10, $11)", &[
         &sub.id, &artist_id, &url, &sub.filename, &hash, &sub.rating.serialize(), &sub.posted_at, &sub.description, &sub.hash_num, &size, &sub.file_sha256,
     ]).await?;
 
@@ -126,6 +127,18 @@ async fn insert_submission(
         client.execute(&stmt, &[&tag_id, &sub.id]).await?;
     }
 
+    if !extra_hashes.is_empty() {
+        let stmt = client
+            .prepare("INSERT INTO submission_hash (site, site_id, kind, hash) VALUES ('FurAffinity', $1, $2, $3) ON CONFLICT (site, site_id, kind) DO UPDATE SET hash = EXCLUDED.hash")
+            .await?;
+
+        for (kind, hash) in extra_hashes {
+            client
+                .execute(&stmt, &[&(sub.id as i64), &kind.as_str(), hash])
+                .await?;
+        }
+    }
+
     Ok(())
 }
 
@@ -170,9 +183,81 @@ impl futures_retry::ErrorHandler<furaffinity_rs::Error> for RetryHandler {
     }
 }
 
-#[tracing::instrument(skip(client, fa, faktory, download_folder))]
+impl futures_retry::ErrorHandler<reqwest::Error> for RetryHandler {
+    type OutError = reqwest::Error;
+
+    #[tracing::instrument(skip(self), fields(max_attempts = self.max_attempts))]
+    fn handle(
+        &mut self,
+        attempt: usize,
+        err: reqwest::Error,
+    ) -> futures_retry::RetryPolicy<Self::OutError> {
+        tracing::warn!("Attempt failed");
+
+        if attempt >= self.max_attempts {
+            tracing::error!("All attempts have been used");
+            return futures_retry::RetryPolicy::ForwardError(err);
+        }
+
+        if !err.is_timeout() && !err.is_connect() {
+            tracing::error!("Error was not a timeout or connection error");
+            return futures_retry::RetryPolicy::ForwardError(err);
+        }
+
+        futures_retry::RetryPolicy::WaitRetry(std::time::Duration::from_secs(1 + attempt as u64))
+    }
+}
+
+#[tracing::instrument(skip(client))]
+async fn download_image(client: &reqwest::Client, url: &str) -> Result<Vec<u8>, reqwest::Error> {
+    let bytes = client
+        .get(url)
+        .send()
+        .await?
+        .error_for_status()?
+        .bytes()
+        .await?;
+
+    Ok(bytes.to_vec())
+}
+
+/// Calculate the SHA256 and every kind of hash for a submission's image,
+/// decoding the image only once.
+///
+/// Returns the submission with its file and hashes set, along with each kind
+/// of hash other than the gradient hash.
+fn hash_submission_image(
+    mut sub: furaffinity_rs::Submission,
+    bytes: Vec<u8>,
+) -> (furaffinity_rs::Submission, Vec<(HashKind, i64)>) {
+    use sha2::{Digest, Sha256};
+
+    let image = match image::load_from_memory(&bytes) {
+        Ok(image) => Some(image),
+        Err(err) => {
+            tracing::error!("Unable to decode submission image: {:?}", err);
+            None
+        }
+    };
+
+    sub.hash_num = image
+        .as_ref()
+        .map(|image| fuzzysearch_common::hash_image_kind(HashKind::Gradient, image));
+    sub.file_size = Some(bytes.len());
+    sub.file_sha256 = Some(Sha256::digest(&bytes).to_vec());
+    sub.file = Some(bytes);
+
+    let extra_hashes = image
+        .map(|image| fuzzysearch_common::hash_image_extra_kinds(&image))
+        .unwrap_or_default();
+
+    (sub, extra_hashes)
+}
+
+#[tracing::instrument(skip(client, http, fa, faktory, download_folder))]
 async fn process_submission(
     client: &Client,
+    http: &reqwest::Client,
     fa: &furaffinity_rs::FurAffinity,
     faktory: &FaktoryClient,
     id: i32,
@@ -213,17 +298,18 @@ async fn process_submission(
         }
     };
 
+    let url = sub.content.url();
     let image =
-        futures_retry::FutureRetry::new(|| fa.calc_image_hash(sub.clone()), RetryHandler::new(3))
+        futures_retry::FutureRetry::new(|| download_image(http, &url), RetryHandler::new(3))
             .await
-            .map(|(sub, _attempt)| sub)
+            .map(|(bytes, _attempt)| bytes)
             .map_err(|(err, _attempt)| err);
 
-    let sub = match image {
-        Ok(sub) => sub,
+    let (sub, extra_hashes) = match image {
+        Ok(bytes) => hash_submission_image(sub, bytes),
         Err(err) => {
-            tracing::error!("Unable to hash submission image: {:?}", err);
-            sub
+            tracing::error!("Unable to download submission image: {:?}", err);
+            (sub, Vec::new())
         }
     };
 
@@ -245,13 +331,19 @@ async fn process_submission(
             file_url: sub.content.url().clone(),
             file_sha256: sub.file_sha256.clone(),
             hash: sub.hash_num.map(|hash| hash.to_be_bytes()),
+            rating: sub
+                .rating
+                .serialize()
+                .and_then(|rating| rating.parse().ok()),
         })
         .await
     {
         tracing::error!("Unable to queue webhook: {:?}", err);
     }
 
-    insert_submission(client, &sub).await.unwrap_or_log();
+    insert_submission(client, &sub, &extra_hashes)
+        .await
+        .unwrap_or_log();
 }
 
 #[tokio::main]
@@ -267,12 +359,13 @@ async fn main() {
     let download_folder = std::env::var("DOWNLOAD_FOLDER").ok();
 
     let user_agent = std::env::var("USER_AGENT").expect_or_log("Missing USER_AGENT");
-    let client = reqwest::Client::builder()
+    let http = reqwest::Client::builder()
         .timeout(std::time::Duration::from_secs(10))
+        .user_agent(&user_agent)
         .build()
         .unwrap_or_log();
 
-    let fa = furaffinity_rs::FurAffinity::new(cookie_a, cookie_b, user_agent, Some(client));
+    let fa = furaffinity_rs::FurAffinity::new(cookie_a, cookie_b, user_agent, Some(http.clone()));
 
     let dsn = std::env::var("POSTGRES_DSN").expect_or_log("Missing POSTGRES_DSN");
 
@@ -315,7 +408,7 @@ async fn main() {
             .set(online.other as i64);
 
         for id in ids_to_check(&client, latest_id).await {
-            process_submission(&client, &fa, &faktory, id, &download_folder).await;
+            process_submission(&client, &http, &fa, &faktory, id, &download_folder).await;
         }
 
         tracing::info!("Completed fetch, waiting a minute before loading more");
@@ -165,17 +165,20 @@ async fn process_submission(
         .await?
         .to_vec();
 
-    let num = if let Ok(image) = image::load_from_memory(&data) {
+    let (num, extra_hashes) = if let Ok(image) = image::load_from_memory(&data) {
         let hasher = fuzzysearch_common::get_hasher();
         let hash = hasher.hash_image(&image);
         let mut bytes: [u8; 8] = [0; 8];
         bytes.copy_from_slice(hash.as_bytes());
         let num = i64::from_be_bytes(bytes);
-        Some(num)
+        (
+            Some(num),
+            fuzzysearch_common::hash_image_extra_kinds(&image),
+        )
     } else {
         tracing::warn!("Unable to decode image");
 
-        None
+        (None, Vec::new())
     };
 
     let mut hasher = Sha256::new();
@@ -199,6 +202,18 @@ async fn process_submission(
     .execute(pool)
     .await?;
 
+    for (kind, hash) in extra_hashes {
+        sqlx::query!(
+            "INSERT INTO submission_hash (site, site_id, kind, hash) VALUES ('Weasyl', $1, $2, $3)
+                ON CONFLICT (site, site_id, kind) DO UPDATE SET hash = EXCLUDED.hash",
+            sub.id as i64,
+            kind.as_str(),
+            hash
+        )
+        .execute(pool)
+        .await?;
+    }
+
     tracing::info!("Completed submission");
 
     faktory
@@ -209,6 +224,10 @@ async fn process_submission(
             file_url: sub.media.submission.first().unwrap_or_log().url.clone(),
             file_sha256: Some(result.to_vec()),
             hash: num.map(|hash| hash.to_be_bytes()),
+            rating: body
+                .get("rating")
+                .and_then(|rating| rating.as_str())
+                .and_then(|rating| rating.parse().ok()),
         })
         .await?;
 
@@ -1,3 +1,6 @@
+use std::net::SocketAddr;
+
+use fuzzysearch_common::{types::Rating, webhook};
 use r2d2_postgres::{postgres::NoTls, PostgresConnectionManager};
 use thiserror::Error;
 use tracing_unwrap::ResultExt;
@@ -22,10 +25,228 @@ pub enum WebhookError {
     Database(#[from] r2d2::Error),
     #[error("network error")]
     Network(#[from] reqwest::Error),
+    #[error("invalid endpoint")]
+    InvalidEndpoint,
+    #[error("could not resolve endpoint")]
+    Resolve(#[from] std::io::Error),
+    #[error("endpoint resolved to a forbidden address")]
+    ForbiddenEndpoint,
     #[error("faktory error")]
     Faktory,
 }
 
+/// How long to wait for an endpoint to respond.
+const REQUEST_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// How long to wait before retrying a delivery the first time, doubling
+/// after each attempt up to the maximum.
+const BASE_RETRY_DELAY: i64 = 30;
+const MAX_RETRY_DELAY: i64 = 60 * 60;
+
+/// How failed deliveries are handled.
+#[derive(Clone, Copy, Debug)]
+struct RetryConfig {
+    /// The most times each delivery is attempted.
+    max_attempts: i32,
+    /// How many attempts in a row may fail before a webhook is disabled.
+    max_failures: i32,
+}
+
+impl RetryConfig {
+    fn from_env() -> Self {
+        let var = |name: &str, default: i32| {
+            std::env::var(name)
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(default)
+        };
+
+        Self {
+            max_attempts: var("WEBHOOK_MAX_ATTEMPTS", 5),
+            max_failures: var("WEBHOOK_MAX_FAILURES", 20),
+        }
+    }
+}
+
+/// How long to wait before the next attempt after an attempt failed.
+fn retry_delay(attempt: i32) -> chrono::Duration {
+    let delay = BASE_RETRY_DELAY.saturating_mul(1 << (attempt - 1).clamp(0, 16));
+
+    chrono::Duration::seconds(delay.min(MAX_RETRY_DELAY))
+}
+
+/// The outcome of attempting to send a webhook.
+struct Attempt {
+    status_code: Option<u16>,
+    latency: std::time::Duration,
+    error: Option<String>,
+}
+
+/// Create a job to send a payload to a webhook.
+///
+/// Failed attempts are recorded and retried by enqueueing a new job, so
+/// Faktory must not also retry jobs that return an error before an attempt
+/// is recorded.
+fn send_webhook_job(args: Vec<serde_json::Value>) -> faktory::Job {
+    let mut job = faktory::Job::new("send_webhook", args).on_queue("fuzzysearch_webhook");
+    job.retry = Some(0);
+
+    job
+}
+
+/// Build a client that only connects to an allowed address of the endpoint's
+/// host, and does not follow redirects.
+///
+/// Endpoints are checked when they are set, but the host may resolve to a
+/// different address by the time a webhook is sent. Requests are made to the
+/// address that was checked.
+fn guarded_client<F>(endpoint: &str, allowed: F) -> Result<reqwest::blocking::Client, WebhookError>
+where
+    F: Fn(SocketAddr) -> bool,
+{
+    use std::net::ToSocketAddrs;
+
+    let url = reqwest::Url::parse(endpoint).map_err(|_err| WebhookError::InvalidEndpoint)?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(WebhookError::InvalidEndpoint);
+    }
+
+    // IPv6 addresses are bracketed in URLs, but can't be resolved that way.
+    let host = url
+        .host_str()
+        .ok_or(WebhookError::InvalidEndpoint)?
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+    let port = url
+        .port_or_known_default()
+        .ok_or(WebhookError::InvalidEndpoint)?;
+
+    let addrs: Vec<SocketAddr> = (host, port).to_socket_addrs()?.collect();
+    if addrs.is_empty() || !addrs.iter().all(|addr| allowed(*addr)) {
+        return Err(WebhookError::ForbiddenEndpoint);
+    }
+
+    let builder = reqwest::blocking::ClientBuilder::default()
+        .user_agent(APP_USER_AGENT)
+        .timeout(REQUEST_TIMEOUT)
+        .redirect(reqwest::redirect::Policy::none())
+        .no_proxy();
+
+    let builder = match url.domain() {
+        Some(domain) => builder.resolve(domain, addrs[0]),
+        None => builder,
+    };
+
+    Ok(builder.build()?)
+}
+
+/// If an address may be connected to, only allowing publicly routable
+/// addresses.
+fn is_allowed(addr: SocketAddr) -> bool {
+    fuzzysearch_common::net::is_public(addr.ip())
+}
+
+/// Send a signed payload to an endpoint.
+fn attempt_delivery(endpoint: &str, secret: &str, delivery_id: &str, body: Vec<u8>) -> Attempt {
+    deliver_to(endpoint, is_allowed, secret, delivery_id, body)
+}
+
+/// Send a signed payload to an endpoint, only connecting to addresses that
+/// are `allowed`.
+fn deliver_to<F>(
+    endpoint: &str,
+    allowed: F,
+    secret: &str,
+    delivery_id: &str,
+    body: Vec<u8>,
+) -> Attempt
+where
+    F: Fn(SocketAddr) -> bool,
+{
+    let client = match guarded_client(endpoint, allowed) {
+        Ok(client) => client,
+        Err(err) => {
+            return Attempt {
+                status_code: None,
+                latency: std::time::Duration::default(),
+                error: Some(err.to_string()),
+            }
+        }
+    };
+
+    let timestamp = chrono::Utc::now().timestamp();
+
+    let req = client
+        .post(endpoint)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .header(webhook::DELIVERY_HEADER, delivery_id)
+        .header(webhook::TIMESTAMP_HEADER, timestamp)
+        .header(
+            webhook::SIGNATURE_HEADER,
+            webhook::sign(secret.as_bytes(), timestamp, &body),
+        );
+
+    let start = std::time::Instant::now();
+    let result = req.body(body).send();
+    let latency = start.elapsed();
+
+    match result {
+        // Redirects are not followed, so they are failures like any other
+        // unsuccessful status.
+        Ok(resp) => Attempt {
+            status_code: Some(resp.status().as_u16()),
+            latency,
+            error: if resp.status().is_success() {
+                None
+            } else {
+                Some(format!("unsuccessful status: {}", resp.status()))
+            },
+        },
+        Err(err) => Attempt {
+            status_code: None,
+            latency,
+            error: Some(err.to_string()),
+        },
+    }
+}
+
+/// Find the webhooks that want a submission.
+///
+/// Each webhook may only want some sites, artists, or ratings. Submissions
+/// without a rating are only sent to webhooks that accept every rating.
+/// Accounts that watch hashes also only want submissions similar to one of
+/// them.
+fn matching_webhooks<C: r2d2_postgres::postgres::GenericClient>(
+    conn: &mut C,
+    hash: Option<i64>,
+    site: &str,
+    artists: &[String],
+    rating: Option<i16>,
+) -> Result<Vec<i32>, r2d2_postgres::postgres::Error> {
+    let rows = conn.query(
+        "SELECT webhook.id
+        FROM webhook
+        WHERE
+            webhook.disabled_at IS NULL AND
+            (webhook.sites IS NULL OR $2 = ANY(webhook.sites)) AND
+            (webhook.artists IS NULL OR webhook.artists && $3) AND
+            (webhook.max_rating IS NULL OR ($4::smallint IS NOT NULL AND $4 <= webhook.max_rating)) AND
+            (NOT webhook.require_hash OR $1::bigint IS NOT NULL) AND
+            (NOT EXISTS (
+                SELECT 1 FROM hash_watch WHERE hash_watch.account_id = webhook.account_id
+            ) OR EXISTS (
+                SELECT 1 FROM hash_watch
+                WHERE
+                    hash_watch.account_id = webhook.account_id AND
+                    length(replace((hash_watch.hash # $1)::bit(64)::text, '0', '')) <= hash_watch.distance
+            ))
+        ORDER BY webhook.id",
+        &[&hash, &site, &artists, &rating],
+    )?;
+
+    Ok(rows.into_iter().map(|row| row.get(0)).collect())
+}
+
 fn main() {
     fuzzysearch_common::init_logger();
 
@@ -35,18 +256,19 @@ fn main() {
     let manager = PostgresConnectionManager::new(dsn.parse().unwrap_or_log(), NoTls);
     let pool = r2d2::Pool::new(manager).unwrap_or_log();
 
-    let client = reqwest::blocking::ClientBuilder::default()
-        .user_agent(APP_USER_AGENT)
-        .timeout(std::time::Duration::from_secs(3))
-        .build()
-        .unwrap_or_log();
-
     let mut faktory = faktory::ConsumerBuilder::default();
     faktory.labels(vec!["fuzzysearch-webhook".to_string()]);
     faktory.workers(2);
 
-    let producer = std::sync::Mutex::new(faktory::Producer::connect(None).unwrap());
+    let retry_config = RetryConfig::from_env();
+    tracing::debug!(?retry_config, "Loaded retry config");
+
+    let producer = std::sync::Arc::new(std::sync::Mutex::new(
+        faktory::Producer::connect(None).unwrap(),
+    ));
 
+    let submission_pool = pool.clone();
+    let submission_producer = producer.clone();
     faktory.register("new_submission", move |job| -> Result<(), WebhookError> {
         let _span = tracing::info_span!("new_submission", job_id = job.id()).entered();
 
@@ -57,20 +279,27 @@ fn main() {
             .ok_or(WebhookError::MissingData)?
             .to_owned();
 
-        let mut conn = pool.get()?;
+        let value: fuzzysearch_common::faktory::WebHookData =
+            serde_json::value::from_value(data.clone())?;
+        let hash = value.hash.map(i64::from_be_bytes);
+        let site = value.site.to_string();
+        let artists: Vec<String> = value
+            .artist
+            .split(", ")
+            .map(|artist| artist.to_lowercase())
+            .collect();
+        let rating = value.rating.as_ref().map(Rating::level);
 
-        for row in conn.query("SELECT endpoint FROM webhook", &[])? {
-            let endpoint: &str = row.get(0);
+        let mut conn = submission_pool.get()?;
 
-            tracing::debug!(endpoint, "Queueing webhook");
+        let webhook_ids = matching_webhooks(&mut *conn, hash, &site, &artists, rating)?;
 
-            let job = faktory::Job::new(
-                "send_webhook",
-                vec![data.clone(), serde_json::to_value(endpoint)?],
-            )
-            .on_queue("fuzzysearch_webhook");
+        for webhook_id in webhook_ids {
+            tracing::debug!(webhook_id, "Queueing webhook");
 
-            let mut producer = producer.lock().unwrap();
+            let job = send_webhook_job(vec![data.clone(), serde_json::to_value(webhook_id)?]);
+
+            let mut producer = submission_producer.lock().unwrap();
             producer.enqueue(job).map_err(|_| WebhookError::Faktory)?;
         }
 
@@ -85,21 +314,132 @@ fn main() {
         let mut args = job.args().iter();
 
         let data = args.next().ok_or(WebhookError::MissingData)?.to_owned();
-        let value: fuzzysearch_common::faktory::WebHookData = serde_json::value::from_value(data)?;
+        let value: fuzzysearch_common::faktory::WebHookData = serde_json::value::from_value(data.clone())?;
+
+        let webhook = args.next().ok_or(WebhookError::MissingData)?;
+
+        // Jobs queued before webhooks were referenced by ID contain the
+        // endpoint instead, and are sent once without being recorded.
+        let webhook_id = match webhook.as_i64() {
+            Some(webhook_id) => webhook_id as i32,
+            None => {
+                let endpoint = webhook.as_str().ok_or(WebhookError::MissingData)?;
+                tracing::trace!(endpoint, "Sending legacy webhook");
+
+                guarded_client(endpoint, is_allowed)?
+                    .post(endpoint)
+                    .header(webhook::DELIVERY_HEADER, job.id())
+                    .json(&value)
+                    .send()?
+                    .error_for_status()?;
+
+                return Ok(());
+            }
+        };
 
-        let endpoint = args
+        // Retries keep the ID of the first attempt so receivers can tell it
+        // is the same delivery.
+        let delivery_id = args
             .next()
-            .ok_or(WebhookError::MissingData)?
-            .as_str()
-            .ok_or(WebhookError::MissingData)?;
+            .and_then(|delivery_id| delivery_id.as_str())
+            .unwrap_or_else(|| job.id())
+            .to_string();
+        let attempt = args
+            .next()
+            .and_then(|attempt| attempt.as_i64())
+            .unwrap_or(1) as i32;
+
+        let mut conn = pool.get()?;
 
-        tracing::trace!(endpoint, site = %value.site, site_id = value.site_id, "Sending webhook");
+        let row = match conn.query_opt(
+            "SELECT endpoint, secret, disabled_at IS NOT NULL FROM webhook WHERE id = $1",
+            &[&webhook_id],
+        )? {
+            Some(row) => row,
+            None => {
+                tracing::info!(webhook_id, "Webhook no longer exists");
+                return Ok(());
+            }
+        };
+
+        let endpoint: String = row.get(0);
+        let secret: String = row.get(1);
+        let disabled: bool = row.get(2);
+
+        if disabled {
+            tracing::info!(webhook_id, "Webhook is disabled");
+            return Ok(());
+        }
 
-        client
-            .post(endpoint)
-            .json(&value)
-            .send()?
-            .error_for_status()?;
+        tracing::trace!(%endpoint, site = %value.site, site_id = value.site_id, attempt, "Sending webhook");
+
+        let body = serde_json::to_vec(&value)?;
+        let result = attempt_delivery(&endpoint, &secret, &delivery_id, body);
+
+        conn.execute(
+            "INSERT INTO webhook_delivery
+                (webhook_id, delivery_id, attempt, payload, status_code, latency_ms, error)
+            VALUES ($1, $2, $3, $4::text::jsonb, $5, $6, $7)",
+            &[
+                &webhook_id,
+                &delivery_id,
+                &attempt,
+                &data.to_string(),
+                &result.status_code.map(|status_code| status_code as i16),
+                &(result.latency.as_millis() as i32),
+                &result.error,
+            ],
+        )?;
+
+        let error = match result.error {
+            Some(error) => error,
+            None => {
+                conn.execute(
+                    "UPDATE webhook SET consecutive_failures = 0 WHERE id = $1",
+                    &[&webhook_id],
+                )?;
+
+                return Ok(());
+            }
+        };
+
+        let disabled: bool = conn
+            .query_one(
+                "UPDATE webhook SET
+                    consecutive_failures = consecutive_failures + 1,
+                    disabled_at = CASE
+                        WHEN consecutive_failures + 1 >= $2 THEN current_timestamp
+                        ELSE disabled_at
+                    END
+                WHERE id = $1
+                RETURNING disabled_at IS NOT NULL",
+                &[&webhook_id, &retry_config.max_failures],
+            )?
+            .get(0);
+
+        if disabled {
+            tracing::warn!(webhook_id, %error, "Disabled webhook after too many failures");
+            return Ok(());
+        }
+
+        if attempt >= retry_config.max_attempts {
+            tracing::warn!(webhook_id, %error, attempt, "Giving up on webhook delivery");
+            return Ok(());
+        }
+
+        let delay = retry_delay(attempt);
+        tracing::info!(webhook_id, %error, attempt, delay = delay.num_seconds(), "Retrying webhook delivery");
+
+        let mut job = send_webhook_job(vec![
+            data,
+            serde_json::to_value(webhook_id)?,
+            serde_json::to_value(&delivery_id)?,
+            serde_json::to_value(attempt + 1)?,
+        ]);
+        job.at = Some(chrono::Utc::now() + delay);
+
+        let mut producer = producer.lock().unwrap();
+        producer.enqueue(job).map_err(|_| WebhookError::Faktory)?;
 
         Ok(())
     });
@@ -107,3 +447,250 @@ fn main() {
     let faktory = faktory.connect(None).unwrap_or_log();
     faktory.run_to_completion(&["fuzzysearch_webhook"]);
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::{SocketAddr, TcpListener};
+
+    use fuzzysearch_common::webhook::{self, VerifyError};
+
+    use super::{attempt_delivery, deliver_to, matching_webhooks};
+
+    const SECRET: &str = "webhook secret";
+
+    /// A request received by the test server.
+    struct Received {
+        headers: Vec<(String, String)>,
+        body: Vec<u8>,
+    }
+
+    impl Received {
+        fn header(&self, name: &str) -> &str {
+            self.headers
+                .iter()
+                .find(|(header, _value)| header == name)
+                .map(|(_header, value)| value.as_str())
+                .unwrap_or_else(|| panic!("missing {} header", name))
+        }
+    }
+
+    /// Start a server on a loopback address that receives one request and
+    /// responds with the status line.
+    fn receive_one(status: &'static str) -> (SocketAddr, std::thread::JoinHandle<Received>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let handle = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+
+            let mut headers = Vec::new();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+
+                let line = line.trim_end();
+                if line.is_empty() {
+                    break;
+                }
+
+                if let Some((name, value)) = line.split_once(':') {
+                    headers.push((name.to_lowercase(), value.trim().to_string()));
+                }
+            }
+
+            let length: usize = headers
+                .iter()
+                .find(|(name, _value)| name == "content-length")
+                .map(|(_name, value)| value.parse().unwrap())
+                .unwrap_or(0);
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+
+            write!(
+                reader.get_mut(),
+                "HTTP/1.1 {}\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+                status
+            )
+            .unwrap();
+
+            Received { headers, body }
+        });
+
+        (addr, handle)
+    }
+
+    #[test]
+    fn sends_signed_payloads() {
+        let (addr, handle) = receive_one("200 OK");
+        let body = br#"{"site":"FurAffinity","site_id":1}"#.to_vec();
+
+        let attempt = deliver_to(
+            &format!("http://{}/webhook", addr),
+            |allowed| allowed == addr,
+            SECRET,
+            "delivery-id",
+            body.clone(),
+        );
+        assert_eq!(attempt.status_code, Some(200));
+        assert_eq!(attempt.error, None);
+
+        let received = handle.join().unwrap();
+        assert_eq!(received.body, body);
+        assert_eq!(received.header("content-type"), "application/json");
+        assert_eq!(received.header(webhook::DELIVERY_HEADER), "delivery-id");
+
+        let timestamp = received.header(webhook::TIMESTAMP_HEADER);
+        let signature = received.header(webhook::SIGNATURE_HEADER);
+
+        let verify = |secret: &str, timestamp: &str, body: &[u8]| {
+            webhook::verify(
+                secret.as_bytes(),
+                timestamp,
+                signature,
+                body,
+                webhook::DEFAULT_TOLERANCE,
+            )
+        };
+
+        assert_eq!(verify(SECRET, timestamp, &received.body), Ok(()));
+        assert_eq!(
+            verify("other secret", timestamp, &received.body),
+            Err(VerifyError::InvalidSignature)
+        );
+        assert_eq!(
+            verify(SECRET, timestamp, b"{}"),
+            Err(VerifyError::InvalidSignature)
+        );
+
+        let expired = timestamp.parse::<i64>().unwrap() - webhook::DEFAULT_TOLERANCE - 60;
+        assert_eq!(
+            verify(SECRET, &expired.to_string(), &received.body),
+            Err(VerifyError::Expired)
+        );
+    }
+
+    #[test]
+    fn records_failed_deliveries() {
+        for status in ["500 Internal Server Error", "302 Found"] {
+            let (addr, handle) = receive_one(status);
+
+            let attempt = deliver_to(
+                &format!("http://{}/webhook", addr),
+                |allowed| allowed == addr,
+                SECRET,
+                "delivery-id",
+                b"{}".to_vec(),
+            );
+            handle.join().unwrap();
+
+            assert_eq!(
+                attempt.status_code,
+                status.split(' ').next().unwrap().parse().ok()
+            );
+            assert!(attempt.error.is_some(), "{} was not a failure", status);
+        }
+    }
+
+    #[test]
+    fn rejects_private_endpoints() {
+        for endpoint in [
+            "http://127.0.0.1:8080/webhook",
+            "http://localhost/webhook",
+            "http://[::1]/webhook",
+            "http://169.254.169.254/latest/meta-data",
+            "ftp://example.com/webhook",
+        ] {
+            let attempt = attempt_delivery(endpoint, SECRET, "delivery-id", b"{}".to_vec());
+
+            assert_eq!(attempt.status_code, None);
+            assert!(attempt.error.is_some(), "{} was not rejected", endpoint);
+        }
+    }
+
+    #[test]
+    #[ignore = "requires a migrated database at DATABASE_URL"]
+    fn matches_webhook_filters() {
+        use r2d2_postgres::postgres::{Client, NoTls};
+
+        let mut client = Client::connect(
+            &std::env::var("DATABASE_URL").expect("Missing DATABASE_URL"),
+            NoTls,
+        )
+        .unwrap();
+        let mut tx = client.transaction().unwrap();
+
+        let account_id: i32 = tx
+            .query_one(
+                "INSERT INTO account (email, password) VALUES ('fixture-webhook@example.com', '')
+                RETURNING id",
+                &[],
+            )
+            .unwrap()
+            .get(0);
+
+        let mut insert = |sites: Option<&[&str]>,
+                          artists: Option<&[&str]>,
+                          max_rating: Option<i16>,
+                          require_hash: bool|
+         -> i32 {
+            tx.query_one(
+                "INSERT INTO webhook (account_id, endpoint, secret, sites, artists, max_rating, require_hash)
+                VALUES ($1, 'https://example.com', 'secret', $2, $3, $4, $5)
+                RETURNING id",
+                &[&account_id, &sites, &artists, &max_rating, &require_hash],
+            )
+            .unwrap()
+            .get(0)
+        };
+
+        let every = insert(None, None, None, false);
+        let general = insert(None, None, Some(0), false);
+        let adult = insert(None, None, Some(2), false);
+        let e621 = insert(Some(&["e621"]), None, None, false);
+        let artist = insert(None, Some(&["artist"]), None, false);
+        let hashed = insert(None, None, None, true);
+
+        let artists = vec!["artist".to_string()];
+        let others = vec!["other".to_string()];
+
+        for (hash, site, artists, rating, expected) in [
+            (
+                Some(1),
+                "FurAffinity",
+                &artists,
+                Some(0),
+                vec![every, general, adult, artist, hashed],
+            ),
+            (
+                Some(1),
+                "FurAffinity",
+                &others,
+                Some(1),
+                vec![every, adult, hashed],
+            ),
+            (
+                Some(1),
+                "e621",
+                &others,
+                Some(2),
+                vec![every, adult, e621, hashed],
+            ),
+            // Unrated submissions are only sent to webhooks accepting every
+            // rating.
+            (Some(1), "FurAffinity", &others, None, vec![every, hashed]),
+            (None, "FurAffinity", &artists, None, vec![every, artist]),
+        ] {
+            let mut webhook_ids = matching_webhooks(&mut tx, hash, site, artists, rating).unwrap();
+            webhook_ids.retain(|webhook_id| *webhook_id >= every);
+            assert_eq!(
+                webhook_ids, expected,
+                "{:?} {} {:?} {:?}",
+                hash, site, artists, rating
+            );
+        }
+
+        tx.rollback().unwrap();
+    }
+}
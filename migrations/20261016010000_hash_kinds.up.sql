@@ -0,0 +1,11 @@
+CREATE TABLE submission_hash (
+    site TEXT NOT NULL,
+    site_id BIGINT NOT NULL,
+    kind TEXT NOT NULL,
+    hash BIGINT NOT NULL,
+
+    PRIMARY KEY (site, site_id, kind)
+);
+
+CREATE INDEX submission_hash_kind_hash_idx ON submission_hash (kind, hash);
+CREATE INDEX bk_submission_hash ON submission_hash USING spgist (hash bktree_ops);
@@ -0,0 +1,13 @@
+CREATE TABLE submission_frame (
+    site TEXT NOT NULL,
+    site_id BIGINT NOT NULL,
+    frame INTEGER NOT NULL,
+    hash BIGINT NOT NULL,
+
+    PRIMARY KEY (site, site_id, frame)
+);
+
+CREATE INDEX submission_frame_hash_idx ON submission_frame (hash);
+
+CREATE TRIGGER update_notify_frame AFTER INSERT OR UPDATE ON submission_frame
+    FOR EACH ROW EXECUTE PROCEDURE update_notify_others();
@@ -0,0 +1,3 @@
+ALTER TABLE api_key ADD COLUMN sha256_limit SMALLINT NOT NULL DEFAULT 60;
+
+UPDATE api_key SET sha256_limit = name_limit;
@@ -0,0 +1,3 @@
+DROP INDEX e621_md5_idx;
+DROP INDEX weasyl_media_url_idx;
+DROP INDEX tweet_media_url_idx;
@@ -0,0 +1,3 @@
+CREATE INDEX e621_md5_idx ON e621 ((data->'file'->>'md5'));
+CREATE INDEX weasyl_media_url_idx ON weasyl ((data->'media'->'submission'->0->>'url'));
+CREATE INDEX tweet_media_url_idx ON tweet_media (url);
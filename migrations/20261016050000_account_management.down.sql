@@ -0,0 +1,14 @@
+DROP TABLE account_attempt;
+
+DROP INDEX account_email_lower_idx;
+
+DROP INDEX api_key_user_id_idx;
+
+ALTER TABLE api_key ALTER COLUMN hash_limit DROP DEFAULT;
+ALTER TABLE api_key ALTER COLUMN image_limit DROP DEFAULT;
+ALTER TABLE api_key ALTER COLUMN name_limit DROP DEFAULT;
+
+ALTER TABLE api_key DROP COLUMN revoked_at;
+ALTER TABLE api_key DROP COLUMN created_at;
+
+ALTER TABLE account DROP COLUMN is_admin;
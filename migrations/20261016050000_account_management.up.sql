@@ -0,0 +1,22 @@
+ALTER TABLE account ADD COLUMN is_admin BOOLEAN NOT NULL DEFAULT false;
+
+ALTER TABLE api_key ADD COLUMN created_at TIMESTAMPTZ NOT NULL DEFAULT current_timestamp;
+ALTER TABLE api_key ADD COLUMN revoked_at TIMESTAMPTZ;
+
+ALTER TABLE api_key ALTER COLUMN name_limit SET DEFAULT 60;
+ALTER TABLE api_key ALTER COLUMN image_limit SET DEFAULT 60;
+ALTER TABLE api_key ALTER COLUMN hash_limit SET DEFAULT 15;
+
+CREATE INDEX api_key_user_id_idx ON api_key (user_id);
+
+CREATE UNIQUE INDEX account_email_lower_idx ON account (lower(email));
+
+CREATE TABLE account_attempt (
+    key TEXT NOT NULL,
+    time_window BIGINT NOT NULL,
+    count SMALLINT NOT NULL DEFAULT 0,
+
+    PRIMARY KEY (key, time_window)
+);
+
+CREATE INDEX account_attempt_time_window_idx ON account_attempt (time_window);
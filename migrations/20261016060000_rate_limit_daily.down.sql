@@ -0,0 +1,3 @@
+DROP INDEX rate_limit_time_window_idx;
+
+DROP TABLE rate_limit_daily;
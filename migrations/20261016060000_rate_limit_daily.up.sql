@@ -0,0 +1,10 @@
+CREATE TABLE rate_limit_daily (
+    api_key_id INTEGER NOT NULL REFERENCES api_key (id),
+    day DATE NOT NULL,
+    group_name TEXT NOT NULL,
+    count INTEGER NOT NULL,
+
+    PRIMARY KEY (api_key_id, day, group_name)
+);
+
+CREATE INDEX rate_limit_time_window_idx ON rate_limit (time_window);
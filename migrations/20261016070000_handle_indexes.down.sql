@@ -0,0 +1,4 @@
+DROP INDEX artist_name_lower_idx;
+DROP INDEX e621_artist_tags_idx;
+DROP INDEX weasyl_owner_login_idx;
+DROP INDEX tweet_twitter_user_id_idx;
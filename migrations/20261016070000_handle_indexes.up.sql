@@ -0,0 +1,4 @@
+CREATE INDEX artist_name_lower_idx ON artist (lower(name));
+CREATE INDEX e621_artist_tags_idx ON e621 USING gin ((data->'tags'->'artist'));
+CREATE INDEX weasyl_owner_login_idx ON weasyl (lower(data->>'owner_login'));
+CREATE INDEX tweet_twitter_user_id_idx ON tweet (twitter_user_id);
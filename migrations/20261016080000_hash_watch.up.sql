@@ -0,0 +1,9 @@
+CREATE TABLE hash_watch (
+    id SERIAL PRIMARY KEY,
+    account_id INTEGER NOT NULL REFERENCES account (id),
+    hash BIGINT NOT NULL,
+    distance SMALLINT NOT NULL DEFAULT 3,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT current_timestamp,
+
+    UNIQUE (account_id, hash)
+);
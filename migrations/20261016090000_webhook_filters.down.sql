@@ -0,0 +1,7 @@
+DROP INDEX webhook_account_id_idx;
+
+ALTER TABLE webhook DROP COLUMN created_at;
+ALTER TABLE webhook DROP COLUMN require_hash;
+ALTER TABLE webhook DROP COLUMN max_rating;
+ALTER TABLE webhook DROP COLUMN artists;
+ALTER TABLE webhook DROP COLUMN sites;
@@ -0,0 +1,7 @@
+ALTER TABLE webhook ADD COLUMN sites TEXT[];
+ALTER TABLE webhook ADD COLUMN artists TEXT[];
+ALTER TABLE webhook ADD COLUMN max_rating SMALLINT;
+ALTER TABLE webhook ADD COLUMN require_hash BOOLEAN NOT NULL DEFAULT false;
+ALTER TABLE webhook ADD COLUMN created_at TIMESTAMPTZ NOT NULL DEFAULT current_timestamp;
+
+CREATE INDEX webhook_account_id_idx ON webhook (account_id);
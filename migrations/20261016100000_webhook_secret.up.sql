@@ -0,0 +1,10 @@
+-- gen_random_uuid is only built in from PostgreSQL 13.
+CREATE EXTENSION IF NOT EXISTS pgcrypto;
+
+ALTER TABLE webhook ADD COLUMN secret TEXT;
+
+UPDATE webhook
+SET secret = replace(gen_random_uuid()::text || gen_random_uuid()::text, '-', '')
+WHERE secret IS NULL;
+
+ALTER TABLE webhook ALTER COLUMN secret SET NOT NULL;
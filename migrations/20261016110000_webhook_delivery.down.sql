@@ -0,0 +1,4 @@
+DROP TABLE webhook_delivery;
+
+ALTER TABLE webhook DROP COLUMN disabled_at;
+ALTER TABLE webhook DROP COLUMN consecutive_failures;
@@ -0,0 +1,17 @@
+ALTER TABLE webhook ADD COLUMN consecutive_failures INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE webhook ADD COLUMN disabled_at TIMESTAMPTZ;
+
+CREATE TABLE webhook_delivery (
+    id BIGSERIAL PRIMARY KEY,
+    webhook_id INTEGER NOT NULL REFERENCES webhook (id) ON DELETE CASCADE,
+    delivery_id TEXT NOT NULL,
+    attempt INTEGER NOT NULL,
+    payload JSONB NOT NULL,
+    status_code SMALLINT,
+    latency_ms INTEGER NOT NULL,
+    error TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT current_timestamp
+);
+
+CREATE INDEX webhook_delivery_webhook_id_idx ON webhook_delivery (webhook_id, id);
+CREATE INDEX webhook_delivery_created_at_idx ON webhook_delivery (created_at);